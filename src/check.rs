@@ -0,0 +1,903 @@
+use {
+    crate::{
+        function::Function,
+        interpreter::{Context, ReadOnly},
+        module::{self, Loader},
+        stdlib,
+        token::{Ident, Lexer, Operator, Span, Token},
+        tree::{Expr, Parser, Pattern, Stmt},
+        types::infer_function,
+        value::Value,
+    },
+    std::{
+        collections::HashMap,
+        fmt::{self, Display, Formatter},
+        path::{Path, PathBuf},
+        rc::Rc,
+    },
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.span, self.severity, self.message)
+    }
+}
+
+struct Declaration {
+    span: Span,
+    used: bool,
+}
+
+/// The span from the start of `first` to the end of `last`
+fn join(first: Span, last: Span) -> Span {
+    Span {
+        end: last.end,
+        ..first
+    }
+}
+
+/// Validates a whole program without running it. Every statement is checked against the
+/// declarations that precede it, so the first mistake doesn't hide the rest.
+pub struct Checker {
+    scratch: Context,
+    variables: HashMap<Ident, Declaration>,
+    /// Functions whose clauses don't accept every argument yet, by where the last one is declared
+    partial: HashMap<Ident, Span>,
+    diagnostics: Vec<Diagnostic>,
+    loader: Rc<Loader>,
+    /// The directory imports are relative to
+    dir: Option<PathBuf>,
+}
+impl Checker {
+    pub fn new() -> Self {
+        Self::with_loader(Rc::new(Loader::checking()))
+    }
+
+    /// A checker sharing the files already read by `loader`, which should be `Loader::checking`
+    pub fn with_loader(loader: Rc<Loader>) -> Self {
+        Checker {
+            scratch: Context::new(),
+            variables: HashMap::new(),
+            partial: HashMap::new(),
+            diagnostics: Vec::new(),
+            loader,
+            dir: None,
+        }
+    }
+
+    /// Checks an imported file, whose declarations go in a namespace and can't hide builtins
+    pub fn in_module(mut self) -> Self {
+        self.scratch.module = true;
+        self
+    }
+
+    /// Resolves imports relative to `dir`, the directory of the file being checked
+    pub fn relative_to(mut self, dir: Option<&Path>) -> Self {
+        self.dir = dir.map(Path::to_owned);
+        self
+    }
+
+    pub fn check(mut self, source: &str) -> Vec<Diagnostic> {
+        self.check_lines(source);
+
+        let mut unused = self
+            .variables
+            .iter()
+            .filter(|(_, decl)| !decl.used)
+            .map(|(ident, decl)| (decl.span, ident.clone()))
+            .collect::<Vec<(Span, Ident)>>();
+        unused.sort_by_key(|(span, _)| span.start);
+        for (span, ident) in unused {
+            self.warn(span, format!("Variable {} is never used", ident));
+        }
+        let partial = self.partial.drain().collect::<Vec<(Ident, Span)>>();
+        for (ident, span) in partial {
+            let message = format!("The clauses of {} don't cover every argument", ident);
+            self.warn(span, message);
+        }
+
+        self.diagnostics.sort_by_key(|diag| diag.span.start);
+        self.diagnostics
+    }
+
+    /// Everything an imported file declares, as if it had been run, or its first error after its position.
+    /// Unused declarations aren't a problem, they're there to be imported.
+    pub fn declarations(mut self, source: &str) -> Result<Context, String> {
+        self.check_lines(source);
+        match self
+            .diagnostics
+            .iter()
+            .filter(|diag| diag.severity == Severity::Error)
+            .min_by_key(|diag| diag.span.start)
+        {
+            Some(diag) => Err(format!("{}: {}", diag.span, diag.message)),
+            None => Ok(self.scratch),
+        }
+    }
+
+    fn check_lines(&mut self, source: &str) {
+        let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+        for res in Lexer::new(source) {
+            match res {
+                Ok((tok, span)) => {
+                    tokens.push(tok);
+                    spans.push(span);
+                }
+                Err(e) => self.error(e.span, e.message),
+            }
+        }
+
+        let mut parser = Parser::new(tokens.iter().cloned().zip(spans.iter().cloned()).collect());
+        while let Some((parsed, range)) = parser.next_statement() {
+            self.check_line(parsed, &tokens[range.clone()], &spans[range]);
+        }
+    }
+
+    /// Checks a top level statement, parsed from `tokens`
+    fn check_line(&mut self, parsed: Result<Stmt, String>, tokens: &[Token], spans: &[Span]) {
+        let whole = join(spans[0], spans[spans.len() - 1]);
+        match parsed {
+            Err(e) => self.error(whole, e),
+            Ok(Stmt::Function(func)) => self.check_function(func, tokens, spans, false),
+            Ok(Stmt::Var { ident, expr, .. }) => self.check_variable(ident, &expr, spans, false),
+            Ok(Stmt::Const { ident, expr, .. }) => self.check_constant(ident, &expr, spans, false),
+            Ok(Stmt::Destructure { pattern, expr }) => {
+                self.check_destructure(&pattern, &expr, tokens, spans, false)
+            }
+            // Everything after `override`
+            Ok(Stmt::Override(stmt)) => match *stmt {
+                Stmt::Function(func) => self.check_function(func, &tokens[1..], &spans[1..], true),
+                Stmt::Var { ident, expr, .. } => {
+                    self.check_variable(ident, &expr, &spans[1..], true)
+                }
+                Stmt::Const { ident, expr, .. } => {
+                    self.check_constant(ident, &expr, &spans[1..], true)
+                }
+                Stmt::Destructure { pattern, expr } => {
+                    self.check_destructure(&pattern, &expr, &tokens[1..], &spans[1..], true)
+                }
+                _ => unreachable!(),
+            },
+            Ok(Stmt::Use { ns, span }) => {
+                if let Err(e) = self.scratch.use_namespace(ns) {
+                    self.error(span, e);
+                }
+            }
+            // Imported files are checked, not run, to find out what they declare
+            Ok(Stmt::Import { path, alias, span }) => {
+                let res = module::import(
+                    &mut self.scratch,
+                    &self.loader,
+                    &path,
+                    alias.as_ref(),
+                    self.dir.as_deref(),
+                );
+                if let Err(e) = res {
+                    self.error(span, e);
+                }
+            }
+            Ok(stmt) => self.check_statement(&stmt, &mut Vec::new(), whole),
+        }
+    }
+
+    /// Checks a statement that isn't a top level declaration. Variables declared in blocks are added to `locals`
+    /// until the block ends, problems that can't be pinned down are reported at `whole`.
+    fn check_statement(&mut self, stmt: &Stmt, locals: &mut Vec<Ident>, whole: Span) {
+        match stmt {
+            Stmt::Function(_)
+            | Stmt::Import { .. }
+            | Stmt::Use { .. }
+            | Stmt::Override(_)
+            | Stmt::Const { .. }
+            | Stmt::Break
+            | Stmt::Continue => (),
+            Stmt::Var { ident, expr, .. } => {
+                self.check_expression(expr, locals);
+                locals.push(ident.clone());
+            }
+            Stmt::Destructure { pattern, expr } => {
+                self.check_expression(expr, locals);
+                locals.extend(pattern.idents().into_iter().cloned());
+            }
+            Stmt::Assign {
+                ident,
+                op,
+                expr,
+                span,
+            } => {
+                self.check_expression(expr, locals);
+                if locals.contains(ident) {
+                } else if let Some(readonly) =
+                    self.scratch.readonly.get(&self.scratch.resolve(ident))
+                {
+                    let message = format!("{} is {} and can't be assigned to", ident, readonly);
+                    self.error(*span, message);
+                } else if let Some(decl) = self.variables.get_mut(ident) {
+                    // `x += 1` reads x, `x = 1` doesn't
+                    decl.used |= op.is_some();
+                } else if stdlib::is_builtin(&self.scratch.resolve(ident)) {
+                    self.error(
+                        *span,
+                        format!("{} is a builtin and can't be assigned to", ident),
+                    );
+                } else if self
+                    .scratch
+                    .functions
+                    .contains_key(&self.scratch.resolve(ident))
+                {
+                    self.error(
+                        *span,
+                        format!("{} is a function and can't be assigned to", ident),
+                    );
+                } else if !self
+                    .scratch
+                    .variables
+                    .borrow()
+                    .contains_key(&self.scratch.resolve(ident))
+                {
+                    self.error(
+                        *span,
+                        format!("Unknown variable {}, declare it with var first", ident),
+                    );
+                }
+            }
+            Stmt::While { cond, body } => {
+                self.check_expression(cond, locals);
+                self.check_block(body, locals, whole);
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.check_expression(cond, locals);
+                self.check_block(then, locals, whole);
+                self.check_block(otherwise, locals, whole);
+            }
+            // Returning a function that still needs arguments is fine
+            Stmt::Return(expr) => self.check_expression(expr, locals),
+            Stmt::For {
+                ident,
+                from,
+                to,
+                body,
+            } => {
+                self.check_expression(from, locals);
+                self.check_expression(to, locals);
+                locals.push(ident.clone());
+                self.check_block(body, locals, whole);
+                locals.pop();
+            }
+            Stmt::Expr(expr) => {
+                self.check_expression(expr, locals);
+                self.check_called(expr, locals, whole);
+            }
+        }
+    }
+
+    fn check_block(&mut self, body: &[Stmt], locals: &mut Vec<Ident>, whole: Span) {
+        let depth = locals.len();
+        for stmt in body {
+            self.check_statement(stmt, locals, whole);
+        }
+        locals.truncate(depth);
+    }
+
+    /// With `over`, the function is declared with `override` and may replace a builtin
+    fn check_function(&mut self, func: Function, tokens: &[Token], spans: &[Span], over: bool) {
+        if let (false, Err(e)) = (over, self.scratch.protect(&func.ident)) {
+            self.error(spans[1], e);
+        }
+        if let Err(e) = self.scratch.writable(&func.ident) {
+            self.error(spans[1], e);
+        }
+
+        let mut used = Vec::new();
+        names(&func.body, &mut used);
+        if let Some(guard) = &func.guard {
+            names(guard, &mut used);
+        }
+        for default in func.defaults.iter().flatten() {
+            names(default, &mut used);
+        }
+        // The first `=` or `{` outside of the parentheses around an argument, `(b = 2)` has its own
+        let mut depth = 0;
+        let body = tokens
+            .iter()
+            .position(|tok| {
+                match tok {
+                    Token::Operator(Operator::LParen) => depth += 1,
+                    Token::Operator(Operator::RParen) => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+                    && matches!(
+                        tok,
+                        Token::Operator(Operator::Assign) | Token::Operator(Operator::LBrace)
+                    )
+            })
+            .unwrap();
+        let names = func.names();
+        for arg in &names {
+            let read =
+                |name: &&Ident| *name == arg || name.fields().iter().any(|(base, _)| base == arg);
+            if !used.iter().any(read) {
+                // After `function` and the name, which an argument can share
+                let idx = 2 + tokens[2..body]
+                    .iter()
+                    .position(|tok| *tok == Token::Identifier(arg.clone()))
+                    .unwrap();
+                self.warn(
+                    spans[idx],
+                    format!("Argument {} of function {} is never used", arg, func.ident),
+                );
+            }
+        }
+
+        for (idx, default) in func.defaults.iter().enumerate() {
+            if let Some(default) = default {
+                self.check_expression(default, &func.args[..idx]);
+            }
+        }
+
+        // Declare before checking the body so recursive references resolve
+        match self.scratch.merge_clause(func.clone()) {
+            Ok(merged) => {
+                if merged.last_clause().is_partial() {
+                    self.partial.insert(func.ident.clone(), spans[1]);
+                } else {
+                    self.partial.remove(&func.ident);
+                }
+                self.scratch.functions.insert(func.ident.clone(), merged);
+            }
+            Err(e) => self.error(spans[1], e),
+        }
+        if let Some(guard) = &func.guard {
+            self.check_expression(guard, &names);
+        }
+        match &*func.body {
+            Expr::Block(body) => {
+                let whole = join(spans[0], spans[spans.len() - 1]);
+                self.check_block(body, &mut names.clone(), whole);
+            }
+            body => self.check_expression(body, &names),
+        }
+    }
+
+    fn check_variable(&mut self, ident: Ident, expr: &Expr, spans: &[Span], over: bool) {
+        self.check_expression(expr, &[]);
+        self.check_binding(ident, spans[1], over);
+    }
+
+    /// `var (a, b) = expr`, where each name is declared like a variable
+    fn check_destructure(
+        &mut self,
+        pattern: &Pattern,
+        expr: &Expr,
+        tokens: &[Token],
+        spans: &[Span],
+        over: bool,
+    ) {
+        self.check_expression(expr, &[]);
+        for ident in pattern.idents() {
+            let idx = tokens
+                .iter()
+                .position(|tok| *tok == Token::Identifier(ident.clone()))
+                .unwrap();
+            self.check_binding(ident.clone(), spans[idx], over);
+        }
+    }
+
+    /// Declares the global variable `ident`, written at `span`
+    fn check_binding(&mut self, ident: Ident, span: Span, over: bool) {
+        if let (false, Err(e)) = (over, self.scratch.protect(&ident)) {
+            self.error(span, e);
+        }
+        if let Err(e) = self
+            .scratch
+            .writable(&ident)
+            .and_then(|_| self.scratch.unshadowed(&ident))
+        {
+            return self.error(span, e);
+        }
+        if let Some(prev) = self.variables.get(&ident) {
+            if !prev.used {
+                self.warn(
+                    prev.span,
+                    format!("Variable {} is never used before being redeclared", ident),
+                );
+            }
+        }
+
+        self.scratch
+            .variables
+            .borrow_mut()
+            .insert(ident.clone(), Value::Num(0.0));
+        self.variables
+            .insert(ident, Declaration { span, used: false });
+    }
+
+    fn check_constant(&mut self, ident: Ident, expr: &Expr, spans: &[Span], over: bool) {
+        self.check_variable(ident.clone(), expr, spans, over);
+        self.scratch.readonly.insert(ident, ReadOnly::Const);
+    }
+
+    /// Whether `ident` is declared, marking variables as used
+    fn known(&mut self, ident: &Ident, locals: &[Ident]) -> bool {
+        if locals.contains(ident) {
+            return true;
+        }
+        if let Some(decl) = self.variables.get_mut(ident) {
+            decl.used = true;
+            return true;
+        }
+        let resolved = self.scratch.resolve(ident);
+        self.scratch.functions.contains_key(&resolved)
+            || self.scratch.variables.borrow().contains_key(&resolved)
+    }
+
+    /// Reports unknown identifiers, `locals` are the parameters in scope
+    fn check_expression(&mut self, expr: &Expr, locals: &[Ident]) {
+        match expr {
+            Expr::Num(_) | Expr::Str(_) => (),
+            Expr::Var(ident, span) => {
+                // `loan.rate` can be a field of `loan`
+                let known = self.known(ident, locals)
+                    || ident
+                        .fields()
+                        .iter()
+                        .any(|(base, _)| self.known(base, locals));
+                if !known {
+                    self.error(*span, format!("Unknown identifier {}", ident));
+                }
+            }
+            Expr::Unary { operand, .. } => self.check_operand(operand, locals),
+            Expr::Binary { lhs, rhs, .. } => {
+                self.check_operand(lhs, locals);
+                self.check_operand(rhs, locals);
+            }
+            Expr::App { callee, args, span } => {
+                if let Expr::Num(v) = **callee {
+                    self.error(
+                        *span,
+                        format!("{} is not a function, did you forget an operator?", v),
+                    );
+                }
+                self.check_expression(callee, locals);
+                for arg in args {
+                    self.check_expression(arg, locals);
+                }
+                self.check_extra(callee, args.len(), locals, *span);
+            }
+            Expr::Call {
+                callee,
+                args,
+                named,
+                span,
+            } => {
+                self.check_expression(callee, locals);
+                for arg in args.iter().chain(named.iter().map(|(_, arg)| arg)) {
+                    self.check_expression(arg, locals);
+                }
+                self.check_extra(callee, args.len(), locals, *span);
+                let func = match &**callee {
+                    Expr::Var(ident, _) if !locals.contains(ident) => self
+                        .scratch
+                        .functions
+                        .get(&self.scratch.resolve(ident))
+                        .cloned(),
+                    _ => None,
+                };
+                if let Some(func) = func {
+                    for (ident, _) in named {
+                        if !func.args.contains(ident) {
+                            let message = format!("{} has no argument named {}", callee, ident);
+                            self.error(*span, message);
+                        }
+                    }
+                    let missing = func.args[..func.required()]
+                        .iter()
+                        .skip(args.len())
+                        .find(|arg| !named.iter().any(|(ident, _)| ident == *arg));
+                    if let Some(arg) = missing {
+                        self.error(*span, format!("{} is missing argument {}", callee, arg));
+                    }
+                }
+            }
+            Expr::Lambda { params, body } => {
+                let mut locals = locals.to_vec();
+                locals.extend(params.iter().cloned());
+                self.check_expression(body, &locals);
+            }
+            // Only function bodies are blocks, see `check_function`
+            Expr::Block(_) => (),
+            Expr::Record(fields) => {
+                for (_, expr) in fields {
+                    self.check_expression(expr, locals);
+                }
+            }
+            Expr::With { record, fields } => {
+                self.check_expression(record, locals);
+                for (_, expr) in fields {
+                    self.check_expression(expr, locals);
+                }
+            }
+            Expr::Tuple(items) => {
+                for item in items {
+                    self.check_expression(item, locals);
+                }
+            }
+            Expr::Try {
+                body,
+                ident,
+                fallback,
+            } => {
+                self.check_expression(body, locals);
+                let mut locals = locals.to_vec();
+                locals.push(ident.clone());
+                self.check_expression(fallback, &locals);
+            }
+            Expr::Match { value, arms, span } => {
+                self.check_expression(value, locals);
+                let mut total = false;
+                for arm in arms {
+                    if total {
+                        self.warn(
+                            *span,
+                            format!("The arm {} of the match is never reached", arm.pattern),
+                        );
+                    }
+                    total |= arm.is_total();
+                    let mut locals = locals.to_vec();
+                    locals.extend(arm.pattern.idents().into_iter().cloned());
+                    if let Some(guard) = &arm.guard {
+                        self.check_expression(guard, &locals);
+                    }
+                    self.check_expression(&arm.body, &locals);
+                }
+                if !total {
+                    let message = "The match doesn't cover every value, add a _ => arm".to_owned();
+                    self.warn(*span, message);
+                }
+            }
+        }
+    }
+
+    fn check_operand(&mut self, expr: &Expr, locals: &[Ident]) {
+        self.check_expression(expr, locals);
+        let span = match expr {
+            Expr::Var(_, span) | Expr::App { span, .. } => *span,
+            _ => return,
+        };
+        self.check_called(expr, locals, span);
+    }
+
+    /// Operands and whole statements should be numbers, so a named function missing arguments there is a mistake
+    fn check_called(&mut self, expr: &Expr, locals: &[Ident], at: Span) {
+        let (ident, given) = match expr {
+            Expr::Var(ident, _) => (ident, 0),
+            Expr::App { callee, args, .. } => match &**callee {
+                Expr::Var(ident, _) => (ident, args.len()),
+                _ => return,
+            },
+            _ => return,
+        };
+        if locals.contains(ident) {
+            return;
+        }
+        if let Some(func) = self.scratch.functions.get(&self.scratch.resolve(ident)) {
+            if given < func.required() {
+                let message = format!(
+                    "{} expects {} argument(s), but only {} are available",
+                    ident,
+                    func.required(),
+                    given
+                );
+                self.error(at, message);
+            }
+        }
+    }
+
+    /// More arguments than a named function takes are only fine when it returns a function to pass the rest to
+    fn check_extra(&mut self, callee: &Expr, given: usize, locals: &[Ident], at: Span) {
+        let ident = match callee {
+            Expr::Var(ident, _) if !locals.contains(ident) => ident,
+            _ => return,
+        };
+        let mut func = match self.scratch.functions.get(&self.scratch.resolve(ident)) {
+            Some(func) => func.clone(),
+            None => return,
+        };
+        if func.sig.is_none() {
+            func.sig = infer_function(&func, &self.scratch).ok();
+        }
+        // Named as it's written, `sin` rather than `math.sin`
+        func.ident = ident.clone();
+        if let Err(e) = func.check_argc(given, &[]) {
+            self.error(at, e);
+        }
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            span,
+            message,
+        });
+    }
+
+    fn warn(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            span,
+            message,
+        });
+    }
+}
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every identifier used in `expr`
+fn names<'a>(expr: &'a Expr, out: &mut Vec<&'a Ident>) {
+    match expr {
+        Expr::Num(_) | Expr::Str(_) => (),
+        Expr::Var(ident, _) => out.push(ident),
+        Expr::Unary { operand, .. } => names(operand, out),
+        Expr::Binary { lhs, rhs, .. } => {
+            names(lhs, out);
+            names(rhs, out);
+        }
+        Expr::App { callee, args, .. } => {
+            names(callee, out);
+            args.iter().for_each(|arg| names(arg, out));
+        }
+        Expr::Call {
+            callee,
+            args,
+            named,
+            ..
+        } => {
+            names(callee, out);
+            args.iter().for_each(|arg| names(arg, out));
+            named.iter().for_each(|(_, arg)| names(arg, out));
+        }
+        Expr::Lambda { body, .. } => names(body, out),
+        Expr::Record(fields) => fields.iter().for_each(|(_, expr)| names(expr, out)),
+        Expr::With { record, fields } => {
+            names(record, out);
+            fields.iter().for_each(|(_, expr)| names(expr, out));
+        }
+        Expr::Tuple(items) => items.iter().for_each(|item| names(item, out)),
+        Expr::Try { body, fallback, .. } => {
+            names(body, out);
+            names(fallback, out);
+        }
+        Expr::Match { value, arms, .. } => {
+            names(value, out);
+            for arm in arms {
+                arm.guard.iter().for_each(|guard| names(guard, out));
+                names(&arm.body, out);
+            }
+        }
+        Expr::Block(body) => body.iter().for_each(|stmt| statement_names(stmt, out)),
+    }
+}
+
+fn statement_names<'a>(stmt: &'a Stmt, out: &mut Vec<&'a Ident>) {
+    match stmt {
+        Stmt::Function(_)
+        | Stmt::Import { .. }
+        | Stmt::Use { .. }
+        | Stmt::Override(_)
+        | Stmt::Break
+        | Stmt::Continue => (),
+        Stmt::Var { expr, .. }
+        | Stmt::Destructure { expr, .. }
+        | Stmt::Const { expr, .. }
+        | Stmt::Return(expr)
+        | Stmt::Expr(expr) => names(expr, out),
+        Stmt::Assign { ident, expr, .. } => {
+            out.push(ident);
+            names(expr, out);
+        }
+        Stmt::While { cond, body } => {
+            names(cond, out);
+            body.iter().for_each(|stmt| statement_names(stmt, out));
+        }
+        Stmt::For { from, to, body, .. } => {
+            names(from, out);
+            names(to, out);
+            body.iter().for_each(|stmt| statement_names(stmt, out));
+        }
+        Stmt::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            names(cond, out);
+            then.iter()
+                .chain(otherwise)
+                .for_each(|stmt| statement_names(stmt, out));
+        }
+    }
+}
+
+pub fn check(source: &str) -> Vec<Diagnostic> {
+    Checker::new().check(source)
+}
+
+#[test]
+fn test_check() {
+    let messages = |source: &str| {
+        check(source)
+            .iter()
+            .map(|diag| format!("{}", diag))
+            .collect::<Vec<String>>()
+    };
+    let diags = check("var a = 10 # ten\nfunction add x y = x + y\nadd a 2");
+    assert!(diags.is_empty(), "{:?}", diags);
+
+    let diags = check("foo + 1\nvar b = bar * baz\nb");
+    let errors = diags
+        .iter()
+        .filter(|diag| diag.severity == Severity::Error)
+        .map(|diag| format!("{}", diag))
+        .collect::<Vec<String>>();
+    assert_eq!(
+        errors,
+        vec![
+            "1:1: error: Unknown identifier foo".to_owned(),
+            "2:9: error: Unknown identifier bar".to_owned(),
+            "2:15: error: Unknown identifier baz".to_owned(),
+        ]
+    );
+
+    let diags = check("function add x y = x + y\nadd 1");
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].severity, Severity::Error);
+    assert_eq!(
+        (diags[0].span.line, diags[0].span.col, diags[0].span.end),
+        (2, 1, 30)
+    );
+
+    assert_eq!(
+        messages("var unused = 1\nfunction sin x = x\n1 + $ 2\n1 +"),
+        vec![
+            "1:5: warning: Variable unused is never used".to_owned(),
+            "2:10: error: sin is a builtin, use override to replace it".to_owned(),
+            "3:5: error: Unexpected character '$'".to_owned(),
+            "4:1: error: Operator + is missing an operand".to_owned(),
+        ]
+    );
+
+    // Loop variables and variables declared in a block are only in scope inside it
+    assert_eq!(
+        messages(
+        "var total = 0\nfor i in 1..10 do\n  var sq = i ^ 2\n  total += sq\n  count = i\nend\nsq + i\nwhile 1 do total",
+    ),
+        vec![
+            "5:3: error: Unknown variable count, declare it with var first".to_owned(),
+            "7:1: error: Unknown identifier sq".to_owned(),
+            "7:6: error: Unknown identifier i".to_owned(),
+            "8:1: error: Expected 'end' to close the block".to_owned(),
+        ]
+    );
+
+    // Extra arguments are only passed on when the function returns one
+    assert_eq!(
+        messages(
+            "function add a b = a + b\nfunction adder n = \\x -> n + x\nadd 1 2 3\nadd(1, 2, 3)\nvar s = sin 1 2\nadder 1 s"
+        ),
+        vec![
+            "3:1: error: add expects 2 argument(s), but 3 are given".to_owned(),
+            "4:1: error: add expects 2 argument(s), but 3 are given".to_owned(),
+            "5:9: error: sin expects 1 argument(s), but 2 are given".to_owned(),
+        ]
+    );
+
+    assert_eq!(
+        messages("function f x y {\n  var z = x * 2\n  return z + w\n}\nz"),
+        vec![
+            "1:14: warning: Argument y of function f is never used".to_owned(),
+            "3:14: error: Unknown identifier w".to_owned(),
+            "5:1: error: Unknown identifier z".to_owned(),
+        ]
+    );
+
+    assert_eq!(
+        messages(
+            "function f a (b = a) (c = 1) = a + b
+f(b: 2)
+f(1, d: 2)
+min 1 2 3",
+        ),
+        vec![
+            "1:23: warning: Argument c of function f is never used".to_owned(),
+            "2:1: error: f is missing argument a".to_owned(),
+            "3:1: error: f has no argument named d".to_owned(),
+        ]
+    );
+
+    assert_eq!(
+        messages(
+            "const n = 3
+n += 1
+var n = 2
+override function cos x = n",
+        ),
+        vec![
+            "2:1: error: n is a constant and can't be assigned to".to_owned(),
+            "3:5: error: n is a constant and can't be redeclared".to_owned(),
+            "4:23: warning: Argument x of function cos is never used".to_owned(),
+        ]
+    );
+
+    assert_eq!(
+        messages(
+            "var (q, r) = divmod(7, 2)
+function f (x, y) = x + z
+q + (r, 1)",
+        ),
+        vec![
+            "2:16: warning: Argument y of function f is never used".to_owned(),
+            "2:25: error: Unknown identifier z".to_owned(),
+        ]
+    );
+
+    assert_eq!(
+        messages(
+            "function sign n | n < 0 = -1
+function sign 0 = 0
+function pick x = match x { 0 => a, _ => 1, 2 => 3 }
+function parity x = match x % 2 { 0 => 1 }",
+        ),
+        vec![
+            "2:10: warning: The clauses of sign don't cover every argument".to_owned(),
+            "3:19: warning: The arm 2 of the match is never reached".to_owned(),
+            "3:34: error: Unknown identifier a".to_owned(),
+            "4:21: warning: The match doesn't cover every value, add a _ => arm".to_owned(),
+        ]
+    );
+
+    assert_eq!(
+        messages("try error(\"a\") catch e => e.message ++ b"),
+        vec!["1:40: error: Unknown identifier b".to_owned()]
+    );
+
+    // The argument, not the function with the same name
+    assert_eq!(
+        messages("function x x = 1\noverride function sin sin = 1"),
+        vec![
+            "1:12: warning: Argument x of function x is never used".to_owned(),
+            "2:23: warning: Argument sin of function sin is never used".to_owned(),
+        ]
+    );
+    assert_eq!(
+        messages("function f x = x\nvar f = 1\nf 2"),
+        vec!["2:5: error: f is a function and can't be a variable".to_owned()]
+    );
+}
@@ -0,0 +1,294 @@
+use {
+    crate::{
+        function::Function,
+        token::{Ident, Operator, Span},
+        value::Value,
+    },
+    std::{
+        collections::HashSet,
+        fmt::{self, Display, Formatter},
+        io::{stdin, stdout, Write},
+        path::Path,
+        rc::Rc,
+    },
+};
+
+/// The arguments a user function was called with, pushed for the duration of the call
+#[derive(PartialEq, Debug, Clone)]
+pub struct Frame {
+    pub ident: Ident,
+    pub bindings: Vec<(Ident, Value)>,
+    /// Where it was called from, the default span if that isn't known
+    pub span: Span,
+    /// The file `span` is in, `None` for code given to the REPL or `Engine::run`
+    pub source: Option<Rc<Path>>,
+}
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}({})",
+            self.ident,
+            self.bindings
+                .iter()
+                .map(|(ident, val)| format!("{} = {}", ident, val))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )?;
+        if self.span.line > 0 {
+            match &self.source {
+                Some(file) => write!(f, " at {}:{}", file.display(), self.span)?,
+                None => write!(f, " at <repl>:{}", self.span)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Something the evaluator is about to do, or has just done
+pub enum Event<'a> {
+    Call {
+        func: &'a Function,
+        args: &'a [Value],
+    },
+    Return {
+        func: &'a Function,
+        val: &'a Value,
+    },
+    Operator {
+        op: Operator,
+        operands: &'a [Value],
+    },
+}
+impl Display for Event<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Call { func, args } => write!(
+                f,
+                "call {}({})",
+                func.ident,
+                func.args
+                    .iter()
+                    .zip(args.iter())
+                    .map(|(arg, val)| format!("{} = {}", arg, val))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Return { func, val } => write!(f, "{} returned {}", func.ident, val),
+            Self::Operator { op, operands } => match operands {
+                [a] if *op == Operator::USub => write!(f, "-{}", a),
+                [a] if op.is_postfix() => write!(f, "{}{}", a, op),
+                [a] => write!(f, "{}{}", op, a),
+                [a, b] => write!(f, "{} {} {}", a, op, b),
+                _ => write!(f, "{} {:?}", op, operands),
+            },
+        }
+    }
+}
+
+/// What the debugger shows when evaluation is paused
+#[derive(Clone)]
+pub struct Pause {
+    pub event: String,
+    pub breakpoint: bool,
+    /// Values on the stack of the expression currently being evaluated
+    pub stack: Vec<Value>,
+    /// Innermost call last
+    pub frames: Vec<Frame>,
+}
+
+pub enum Action {
+    Step,
+    Continue,
+    Abort,
+}
+
+pub trait Frontend {
+    fn pause(&mut self, pause: &Pause) -> Action;
+}
+
+/// Prompts on stdin while paused, used by the REPL
+pub struct StdinFrontend;
+impl Frontend for StdinFrontend {
+    fn pause(&mut self, pause: &Pause) -> Action {
+        if pause.breakpoint {
+            println!("Breakpoint: {}", pause.event);
+        } else {
+            println!("Step: {}", pause.event);
+        }
+
+        loop {
+            print!("(debug) ");
+            stdout().flush().unwrap();
+
+            let mut userin = String::new();
+            if stdin().read_line(&mut userin).unwrap() == 0 {
+                return Action::Continue;
+            }
+
+            match userin.trim() {
+                "" | ":step" | ":s" => return Action::Step,
+                ":continue" | ":c" => return Action::Continue,
+                ":abort" | ":q" => return Action::Abort,
+                ":stack" => println!(
+                    "[{}]",
+                    pause
+                        .stack
+                        .iter()
+                        .map(|v| format!("{}", v))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                ":locals" => match pause.frames.last() {
+                    Some(frame) => {
+                        for (ident, val) in &frame.bindings {
+                            println!("{} = {}", ident, val);
+                        }
+                    }
+                    None => println!("Not inside a function"),
+                },
+                ":where" => {
+                    for frame in pause.frames.iter().rev() {
+                        println!("in {}", frame);
+                    }
+                }
+                other => println!(
+                    "Unknown debugger command {}, expected one of :step :continue :stack :locals :where :abort",
+                    other
+                ),
+            }
+        }
+    }
+}
+
+pub struct Debugger {
+    pub breakpoints: HashSet<Ident>,
+    /// Pause before every event, not just on breakpoints
+    pub stepping: bool,
+    frontend: Box<dyn Frontend>,
+}
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: HashSet::new(),
+            stepping: false,
+            frontend: Box::new(StdinFrontend),
+        }
+    }
+
+    pub fn set_frontend(&mut self, frontend: Box<dyn Frontend>) {
+        self.frontend = frontend;
+    }
+
+    /// Called by the evaluator for every event, pauses if a breakpoint is hit or we're stepping
+    fn breakpoint(&self, event: &Event) -> bool {
+        match event {
+            Event::Call { func, .. } => self.breakpoints.contains(&func.ident),
+            _ => false,
+        }
+    }
+
+    /// Whether `hook` would pause on `event`
+    pub fn pauses(&self, event: &Event) -> bool {
+        self.stepping || self.breakpoint(event)
+    }
+
+    pub fn hook(&mut self, event: Event, stack: &[Value], frames: &[Frame]) -> Result<(), String> {
+        let breakpoint = self.breakpoint(&event);
+        if !breakpoint && !self.stepping {
+            return Ok(());
+        }
+
+        let pause = Pause {
+            event: format!("{}", event),
+            breakpoint,
+            stack: stack.to_vec(),
+            frames: frames.to_vec(),
+        };
+        match self.frontend.pause(&pause) {
+            Action::Step => {
+                self.stepping = true;
+                Ok(())
+            }
+            Action::Continue => {
+                self.stepping = false;
+                Ok(())
+            }
+            Action::Abort => {
+                self.stepping = false;
+                Err("Evaluation aborted by the debugger".to_owned())
+            }
+        }
+    }
+}
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_debugger() {
+    use {
+        crate::{interpreter::Context, token::tokenize},
+        std::{cell::RefCell, rc::Rc},
+    };
+
+    struct Scripted {
+        actions: Vec<Action>,
+        seen: Rc<RefCell<Vec<Pause>>>,
+    }
+    impl Frontend for Scripted {
+        fn pause(&mut self, pause: &Pause) -> Action {
+            self.seen.borrow_mut().push(pause.clone());
+            self.actions.remove(0)
+        }
+    }
+
+    let mut ctx = Context::new();
+    ctx.new_func(&tokenize("function f a b = a * b + 1".to_owned()).unwrap())
+        .unwrap();
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    ctx.debugger.borrow_mut().set_frontend(Box::new(Scripted {
+        actions: vec![Action::Step, Action::Step, Action::Continue],
+        seen: seen.clone(),
+    }));
+    ctx.debugger
+        .borrow_mut()
+        .breakpoints
+        .insert(Ident::new("f".to_owned()).unwrap());
+
+    let tokens = tokenize("10 + f 2 3".to_owned()).unwrap();
+    assert_eq!(
+        crate::interpreter::evaluate(&tokens, &ctx),
+        Ok(Value::Num(17.0))
+    );
+
+    let seen = seen.borrow();
+    let events = seen
+        .iter()
+        .map(|pause| pause.event.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(events, vec!["call f(a = 2, b = 3)", "2 * 3", "6 + 1"]);
+    assert_eq!(
+        seen[0].stack,
+        vec![Value::Num(10.0), Value::Num(2.0), Value::Num(3.0)]
+    );
+    assert_eq!(seen[1].stack, vec![Value::Num(2.0), Value::Num(3.0)]);
+    assert_eq!(
+        seen[1].frames,
+        vec![Frame {
+            ident: Ident::new("f".to_owned()).unwrap(),
+            bindings: vec![
+                (Ident::new("a".to_owned()).unwrap(), Value::Num(2.0)),
+                (Ident::new("b".to_owned()).unwrap(), Value::Num(3.0)),
+            ],
+            span: Span::default(),
+            source: None,
+        }]
+    );
+    // Paused on entering f, the arguments are already bound
+    assert_eq!(seen[0].frames, seen[1].frames);
+    assert!(!ctx.debugger.borrow().stepping);
+}
@@ -0,0 +1,566 @@
+use {
+    crate::{
+        debugger::Frame,
+        eval::eval,
+        exec::Executor,
+        function::Function,
+        interpreter::{Context, ReadOnly},
+        module::{self, Loader},
+        output::Output,
+        token::{Ident, Lexer, Span, Token},
+        trace::Trace,
+        tree::{nesting, Expr, Parser, Stmt},
+        value::Value,
+    },
+    std::{
+        cell::RefCell,
+        fs,
+        path::{Path, PathBuf},
+        rc::Rc,
+    },
+};
+
+/// The result of running a statement
+#[derive(PartialEq, Debug, Clone)]
+pub enum Outcome {
+    Function(Function),
+    Variable(Ident, Value),
+    /// The variables declared by taking apart a tuple, `var (q, r) = divmod(7, 2)`
+    Variables(Vec<(Ident, Value)>),
+    /// The namespace a file was imported into
+    Module(Ident),
+    Value(Value),
+    Empty,
+}
+
+/// Entry point for embedding minilang in another program, the REPL is built on top of it
+pub struct Engine {
+    pub context: Context,
+    loader: Rc<Loader>,
+    /// The directory of the file being run, which imports are relative to
+    dir: Option<PathBuf>,
+    /// The file being run, which the functions it declares are from
+    file: Option<Rc<Path>>,
+}
+impl Engine {
+    pub fn new() -> Self {
+        Self::with_loader(Rc::new(Loader::new()))
+    }
+
+    /// An engine sharing the files already imported by `loader`
+    pub fn with_loader(loader: Rc<Loader>) -> Self {
+        Engine {
+            context: Context::new(),
+            loader,
+            dir: None,
+            file: None,
+        }
+    }
+
+    /// While set, every evaluation records a `Trace`, available from `last_trace`
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.context.tracing.set(tracing);
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.context.tracing.get()
+    }
+
+    /// The trace of the last expression evaluated with tracing enabled
+    pub fn last_trace(&self) -> Option<Trace> {
+        self.context.last_trace.borrow().clone()
+    }
+
+    /// The user functions the last failed statement or evaluation was inside of when it failed, outermost
+    /// first, with the arguments they were called with and where they were called from, including the file
+    pub fn traceback(&self) -> Vec<Frame> {
+        self.context
+            .traceback
+            .borrow()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// In calculator mode a `%` that isn't followed by an operand means percent, so `50%` is 0.5 and
+    /// `200 + 10%` is 220. Otherwise `%` is always modulo.
+    pub fn set_calculator(&mut self, calculator: bool) {
+        self.context.calculator = calculator;
+    }
+
+    pub fn calculator(&self) -> bool {
+        self.context.calculator
+    }
+
+    /// Stops any evaluation running more than `limit` operators, calls and statements, e.g. an endless loop
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.context.limit = limit;
+    }
+
+    pub fn instruction_limit(&self) -> Option<u64> {
+        self.context.limit
+    }
+
+    /// Fails any call of a user function nesting more than `depth` calls deep, which `try` can catch
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.context.max_depth = depth;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.context.max_depth
+    }
+
+    /// Fails any call of a user function once nested calls take more than `bytes` of the stack, instead of
+    /// overflowing it. The default of `DEFAULT_STACK_LIMIT` fits any thread, a program running minilang on a
+    /// bigger stack can allow more.
+    pub fn set_stack_limit(&mut self, bytes: usize) {
+        self.context.stack_limit = bytes;
+    }
+
+    pub fn stack_limit(&self) -> usize {
+        self.context.stack_limit
+    }
+
+    /// Sends what `print` and `println` write to `output` instead of stdout, e.g. a `Buffer` to collect it
+    pub fn set_output(&mut self, output: Box<dyn Output>) {
+        self.context.output = RefCell::new(output);
+    }
+
+    /// Whether `src` opens more blocks than it closes, so a REPL should read another line
+    pub fn incomplete(&self, src: &str) -> bool {
+        let (mut depth, mut prev) = (0, None);
+        for res in Lexer::new(src).calculator(self.context.calculator) {
+            match res {
+                Ok((tok, _)) => {
+                    depth += nesting(prev.as_ref(), &tok);
+                    prev = Some(tok);
+                }
+                Err(_) => return false,
+            }
+        }
+        depth > 0
+    }
+
+    /// Lexes according to the current mode
+    fn lex(&self, src: &str) -> Result<Vec<(Token, Span)>, String> {
+        Lexer::new(src)
+            .calculator(self.context.calculator)
+            .map(|res| res.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    pub fn parse_expression(&self, src: &str) -> Result<Expr, String> {
+        Parser::new(self.lex(src)?).expression()
+    }
+
+    /// Runs every statement in `src`, stopping at the first error. Nothing runs if any of them fails to parse.
+    /// Returns the outcome of the last statement.
+    pub fn run(&mut self, src: &str) -> Result<Outcome, String> {
+        let stmts = Parser::new(self.lex(src)?).program()?;
+        let mut outcome = Outcome::Empty;
+        for stmt in stmts {
+            outcome = self.statement(stmt)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Runs the file at `path`, with imports relative to it
+    pub fn run_file(&mut self, path: &Path) -> Result<Outcome, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        self.run_source(&source, path)
+    }
+
+    /// Runs `src` as the contents of the file at `path`, with imports relative to it
+    pub fn run_source(&mut self, src: &str, path: &Path) -> Result<Outcome, String> {
+        let prev = self.file.replace(Rc::from(path));
+        let outcome = self.run_in(src, path.parent());
+        self.file = prev;
+        outcome
+    }
+
+    /// Runs `src` as if it were a file in `dir`
+    pub fn run_in(&mut self, src: &str, dir: Option<&Path>) -> Result<Outcome, String> {
+        let prev = std::mem::replace(&mut self.dir, dir.map(Path::to_owned));
+        let outcome = self.run(src);
+        self.dir = prev;
+        outcome
+    }
+
+    fn statement(&mut self, stmt: Stmt) -> Result<Outcome, String> {
+        self.start();
+        match stmt {
+            Stmt::Override(stmt) => self.declaration(*stmt),
+            Stmt::Function(Function { ref ident, .. })
+            | Stmt::Var { ref ident, .. }
+            | Stmt::Const { ref ident, .. } => {
+                self.context.protect(ident)?;
+                self.declaration(stmt)
+            }
+            Stmt::Destructure { ref pattern, .. } => {
+                for ident in pattern.idents() {
+                    self.context.protect(ident)?;
+                }
+                self.declaration(stmt)
+            }
+            Stmt::Assign {
+                ident, op, expr, ..
+            } => Executor::new(&self.context)
+                .assign(&ident, op, &expr)
+                .map(|val| Outcome::Variable(ident, val)),
+            Stmt::Import { path, alias, .. } => module::import(
+                &mut self.context,
+                &self.loader,
+                &path,
+                alias.as_ref(),
+                self.dir.as_deref(),
+            )
+            .map(Outcome::Module),
+            Stmt::Use { ns, .. } => self.context.use_namespace(ns).map(|_| Outcome::Empty),
+            Stmt::Expr(expr) => eval(&expr, &self.context, Vec::new()).map(Outcome::Value),
+            stmt => Executor::new(&self.context)
+                .statement(&stmt)
+                .map(|_| Outcome::Empty),
+        }
+    }
+
+    /// Resets what's left from the last evaluation before starting another
+    fn start(&self) {
+        self.context.instructions.set(0);
+        self.context.traceback.borrow_mut().clear();
+        self.context.source.replace(self.file.clone());
+    }
+
+    /// Declares a function or variable, which replaces anything with the same name
+    fn declaration(&mut self, stmt: Stmt) -> Result<Outcome, String> {
+        match stmt {
+            Stmt::Function(func) => self
+                .context
+                .declare(Function {
+                    file: self.file.clone(),
+                    ..func
+                })
+                .map(Outcome::Function),
+            Stmt::Var { ident, ann, expr } => self
+                .context
+                .bind(ident, ann, &expr)
+                .map(|(ident, val)| Outcome::Variable(ident, val)),
+            Stmt::Const { ident, ann, expr } => self
+                .context
+                .constant(ident, ann, &expr)
+                .map(|(ident, val)| Outcome::Variable(ident, val)),
+            Stmt::Destructure { pattern, expr } => self
+                .context
+                .destructure(&pattern, &expr)
+                .map(Outcome::Variables),
+            stmt => Err(format!("{} isn't a declaration", stmt)),
+        }
+    }
+
+    /// Provides a value scripts can read as `name` but never change, e.g. a market rate. Calling it again
+    /// replaces the value.
+    pub fn set_input(&mut self, name: &str, val: Value) -> Result<(), String> {
+        let ident = Ident::new(name.to_owned())
+            .filter(|ident| !ident.is_qualified())
+            .ok_or_else(|| format!("{} isn't a valid input name", name))?;
+        self.context.protect(&ident)?;
+        if self.context.readonly.get(&ident) == Some(&ReadOnly::Const) {
+            return Err(format!("{} is a constant and can't be redeclared", ident));
+        }
+        if self.context.functions.contains_key(&ident) {
+            return Err(format!("{} is a function and can't be an input", ident));
+        }
+        self.context.annotations.remove(&ident);
+        self.context
+            .variables
+            .borrow_mut()
+            .insert(ident.clone(), val);
+        self.context.readonly.insert(ident, ReadOnly::Input);
+        Ok(())
+    }
+
+    pub fn eval(&self, expr: &str) -> Result<Value, String> {
+        self.start();
+        eval(&self.parse_expression(expr)?, &self.context, Vec::new())
+    }
+
+    /// Evaluates `expr` with tracing enabled, regardless of `tracing`
+    pub fn explain(&self, expr: &str) -> Result<Trace, String> {
+        let expr = self.parse_expression(expr)?;
+
+        self.start();
+        let tracing = self.context.tracing.replace(true);
+        let result = eval(&expr, &self.context, Vec::new());
+        self.context.tracing.set(tracing);
+
+        result?;
+        self.context
+            .last_trace
+            .borrow()
+            .clone()
+            .ok_or_else(|| "Nothing was evaluated".to_owned())
+    }
+}
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_engine_trace() {
+    let mut engine = Engine::new();
+    assert!(matches!(
+        engine.run("function f a b = a * b + 1"),
+        Ok(Outcome::Function(_))
+    ));
+    assert_eq!(
+        engine.run("var x = 2"),
+        Ok(Outcome::Variable(
+            Ident::new("x".to_owned()).unwrap(),
+            Value::Num(2.0)
+        ))
+    );
+    assert_eq!(engine.run(""), Ok(Outcome::Empty));
+
+    assert_eq!(engine.eval("f x 3"), Ok(Value::Num(7.0)));
+    assert_eq!(engine.last_trace(), None);
+
+    engine.set_tracing(true);
+    assert_eq!(
+        engine.run("10 - f x 3"),
+        Ok(Outcome::Value(Value::Num(3.0)))
+    );
+    let trace = engine.last_trace().unwrap();
+    assert_eq!(trace.value(), Value::Num(3.0));
+    assert_eq!(
+        format!("{}", trace),
+        "3 = 10 - 7\n  7 = f(a = 2, b = 3)\n    7 = 6 + 1\n      6 = 2 * 3\n"
+    );
+
+    engine.set_tracing(false);
+    let trace = engine.explain("-(1 + 2)").unwrap();
+    assert_eq!(format!("{}", trace), "-3 = -3\n  3 = 1 + 2\n");
+
+    engine
+        .run("function g x {\n    var y = x * 2\n    if y > 100 then\n        return 0\n    end\n    return y + 1\n}")
+        .unwrap();
+    let trace = engine.explain("g 3 + 1").unwrap();
+    assert_eq!(
+        format!("{}", trace),
+        "8 = 7 + 1\n  7 = g(x = 3)\n    6 = 3 * 2\n    0 = 6 > 100\n    7 = 6 + 1\n"
+    );
+    assert!(!engine.tracing());
+}
+
+#[test]
+fn test_postfix_operators() {
+    let mut engine = Engine::new();
+    let eval = |engine: &Engine, s: &str| engine.eval(s).and_then(|val| val.num()).unwrap();
+
+    assert_eq!(eval(&engine, "5!"), 120.0);
+    assert_eq!(eval(&engine, "0!"), 1.0);
+    assert_eq!(eval(&engine, "-3!"), -6.0);
+    assert_eq!(eval(&engine, "2 ^ 3!"), 64.0);
+    assert_eq!(eval(&engine, "(1 + 2)! * 2"), 12.0);
+    assert_eq!(eval(&engine, "3!!"), 720.0);
+    assert!((eval(&engine, "0.5!") - std::f64::consts::PI.sqrt() / 2.0).abs() < 1e-12);
+    assert!((eval(&engine, "4.5!") - 52.342_777_784_553_52).abs() < 1e-9);
+    assert!(engine.eval("(-2)!").is_err());
+    assert!(engine.eval("!3").is_err());
+
+    // Modulo unless calculator mode is on
+    assert_eq!(eval(&engine, "10 % 3"), 1.0);
+    assert!(engine.eval("50%").is_err());
+
+    engine.set_calculator(true);
+    assert_eq!(eval(&engine, "10 % 3"), 1.0);
+    assert_eq!(eval(&engine, "50%"), 0.5);
+    assert_eq!(eval(&engine, "200 + 10%"), 220.0);
+    assert_eq!(eval(&engine, "200 - 25%"), 150.0);
+    assert_eq!(eval(&engine, "80 * 25%"), 20.0);
+    assert_eq!(eval(&engine, "(200 + 10%) * 2"), 440.0);
+    assert_eq!(eval(&engine, "200 + 10% * 2"), 200.2);
+
+    let trace = engine.explain("200 + 10%").unwrap();
+    assert_eq!(format!("{}", trace), "220 = 200 + 10%\n  0.1 = 10%\n");
+}
+
+#[test]
+fn test_implicit_multiplication() {
+    let mut engine = Engine::new();
+    engine.run("var a = 2").unwrap();
+    engine.run("var b = 5").unwrap();
+
+    // Outside calculator mode the number is called
+    assert!(engine.eval("3(a + b)").is_err());
+    assert_eq!(engine.eval("max 2 (3)"), Ok(Value::Num(3.0)));
+
+    engine.set_calculator(true);
+    let eval = |s: &str| engine.eval(s).and_then(|val| val.num()).unwrap();
+    assert_eq!(eval("2pi"), 2.0 * std::f64::consts::PI);
+    assert_eq!(eval("2e"), 2.0 * std::f64::consts::E);
+    assert_eq!(eval("3(a + b)"), 21.0);
+    assert_eq!(eval("(a)(b)"), 10.0);
+    assert_eq!(eval("(a + 1)b"), 15.0);
+    assert_eq!(eval("3!a"), 12.0);
+
+    // Tighter than `*` and `/`, looser than `^` and negation
+    assert_eq!(eval("1 / 2a"), 0.25);
+    assert_eq!(eval("2a^2"), 8.0);
+    assert_eq!(eval("2^2a"), 8.0);
+    assert_eq!(eval("-2a"), -4.0);
+
+    // Only without whitespace, so function arguments are unaffected
+    assert_eq!(eval("max 2 (3)"), 3.0);
+    assert_eq!(eval("max 2 a"), 2.0);
+    assert_eq!(
+        engine.eval("2 a"),
+        Err("2 is not a function, did you forget an operator?".to_owned())
+    );
+}
+
+#[test]
+fn test_readonly() {
+    let mut engine = Engine::new();
+    engine.set_input("rate", Value::Num(0.25)).unwrap();
+    engine.run("const years: int = 30").unwrap();
+    assert_eq!(engine.eval("rate * years"), Ok(Value::Num(7.5)));
+
+    for (src, err) in [
+        ("rate = 0.1", "rate is an input and can't be assigned to"),
+        ("years += 1", "years is a constant and can't be assigned to"),
+        (
+            "var years = 1",
+            "years is a constant and can't be redeclared",
+        ),
+        (
+            "const years = 1",
+            "years is a constant and can't be redeclared",
+        ),
+        (
+            "function years x = x",
+            "years is a constant and can't be redeclared",
+        ),
+        (
+            "override var rate = 1",
+            "rate is an input and can't be redeclared",
+        ),
+        (
+            "const pi = 3",
+            "pi is a builtin, use override to replace it",
+        ),
+        (
+            "if 1 then const x = 1 end",
+            "Constants can only be declared at the top level",
+        ),
+    ] {
+        assert_eq!(engine.run(src), Err(err.to_owned()), "{}", src);
+    }
+    assert_eq!(engine.eval("rate * years"), Ok(Value::Num(7.5)));
+
+    // The host can still update its inputs, but not replace constants
+    engine.set_input("rate", Value::Num(0.5)).unwrap();
+    assert_eq!(engine.eval("rate * years"), Ok(Value::Num(15.0)));
+    assert!(engine.set_input("years", Value::Num(1.0)).is_err());
+    assert!(engine.set_input("sqrt", Value::Num(1.0)).is_err());
+    assert!(engine.set_input("a.b", Value::Num(1.0)).is_err());
+}
+
+#[test]
+fn test_traceback() {
+    let mut engine = Engine::new();
+    engine
+        .run("function inner x = x + num \"x\"\nfunction outer n = 1 + inner (n - 1)")
+        .unwrap();
+    assert_eq!(
+        engine.run("2 * outer 3"),
+        Err("Can't read \"x\" as a number".to_owned())
+    );
+    let frames = engine
+        .traceback()
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(
+        frames,
+        vec!["outer(n = 3) at <repl>:1:5", "inner(x = 2) at <repl>:2:24"]
+    );
+
+    // Errors caught by try don't leave frames behind
+    assert_eq!(engine.eval("try outer 3 catch e => 0"), Ok(Value::Num(0.0)));
+    assert_eq!(engine.traceback(), Vec::new());
+    assert!(engine.eval("(try outer 3 catch e => 0) + inner 1").is_err());
+    assert_eq!(engine.traceback().len(), 1);
+    assert_eq!(
+        engine.traceback()[0].to_string(),
+        "inner(x = 1) at <repl>:1:30"
+    );
+    assert_eq!(
+        engine.run("outer 3 + 1"),
+        Err("Can't read \"x\" as a number".to_owned())
+    );
+    assert_eq!(engine.run("1"), Ok(Outcome::Value(Value::Num(1.0))));
+    assert_eq!(engine.traceback(), Vec::new());
+}
+
+#[test]
+fn test_depth() {
+    use crate::interpreter::STACK_SIZE;
+    let declare = |engine: &mut Engine| {
+        engine.run("function f x = f (x + 1)").unwrap();
+        engine.run("function down 0 = 0").unwrap();
+        engine.run("function down n = 1 + down (n - 1)").unwrap();
+        // Clauses recursing through their guard never get to push a frame
+        engine.run("function g x | g x = 1").unwrap();
+        engine.run("function g x = 2").unwrap();
+        engine
+            .run("function h x {\n  var y = match x { 0 => 1, _ => x }\n  return h (y + 1)\n}")
+            .unwrap();
+    };
+
+    // On the stack of a test thread, with the defaults, recursion runs out of stack before it overflows it
+    let mut engine = Engine::new();
+    declare(&mut engine);
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+    for expr in ["f 0", "g 0", "h 0"] {
+        let message = eval(expr).unwrap_err();
+        assert!(message.ends_with("calls deep"), "{}", message);
+    }
+    assert_eq!(
+        eval("try f 0 catch e => e.kind"),
+        Ok("\"overflow\"".to_owned())
+    );
+    assert_eq!(eval("down 20"), Ok("20".to_owned()));
+    assert_eq!(engine.context.depth.get(), 0);
+    engine.set_max_depth(10);
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+    assert_eq!(eval("down 9"), Ok("9".to_owned()));
+    assert_eq!(
+        eval("down 10"),
+        Err("Calling down would nest more than 10 calls deep".to_owned())
+    );
+
+    // Given the stack the REPL runs on, the depth limit comes first
+    let run = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut engine = Engine::new();
+            engine.set_stack_limit(STACK_SIZE / 2);
+            declare(&mut engine);
+            let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+            assert_eq!(
+                eval("f 0"),
+                Err("Calling f would nest more than 1000 calls deep".to_owned())
+            );
+            assert_eq!(engine.traceback().len(), 1000);
+            assert_eq!(
+                eval("g 0"),
+                Err("Calling g would nest more than 1000 calls deep".to_owned())
+            );
+            assert_eq!(eval("down 900"), Ok("900".to_owned()));
+        })
+        .unwrap();
+    run.join().unwrap();
+}
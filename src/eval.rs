@@ -0,0 +1,809 @@
+use {
+    crate::{
+        debugger::Event,
+        exec::Executor,
+        function::Function,
+        interpreter::{Context, ErrorKind},
+        token::{Ident, Operator, Span},
+        trace::Trace,
+        tree::Expr,
+        value::{Closure, Value},
+    },
+    std::rc::Rc,
+};
+
+/// Walks an expression tree. Operands that have been evaluated but not yet consumed are kept on a stack,
+/// so the debugger sees the same values at every step as it would evaluating the rpn form.
+struct Evaluator<'a> {
+    ctx: &'a Context,
+    locals: Vec<(Ident, Value)>,
+    stack: Vec<Value>,
+    /// Mirrors `stack` while tracing, recording how each value was computed
+    traces: Vec<Trace>,
+    tracing: bool,
+}
+impl Evaluator<'_> {
+    fn push(&mut self, val: Value, trace: impl FnOnce() -> Trace) {
+        if self.tracing {
+            self.traces.push(trace());
+        }
+        self.stack.push(val);
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::Num(v) => self.push(Value::Num(*v), || Trace::Value(Value::Num(*v))),
+            Expr::Str(text) => {
+                let val = Value::Str(text.clone());
+                self.push(val.clone(), || Trace::Value(val));
+            }
+            Expr::Var(ident, span) => self.var(ident, *span)?,
+            Expr::Unary { op, operand } => {
+                self.eval(operand)?;
+                self.operator(*op, 1, false)?;
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                self.eval(lhs)?;
+                self.eval(rhs)?;
+                let percent = matches!(op, Operator::Add | Operator::Sub)
+                    && matches!(
+                        **rhs,
+                        Expr::Unary {
+                            op: Operator::Percent,
+                            ..
+                        }
+                    );
+                self.operator(*op, 2, percent)?;
+            }
+            Expr::App { callee, args, span } => {
+                // Like a function in rpn, the callee isn't on the stack while its arguments are evaluated
+                let callee = self.value(callee)?;
+                check_argc(&callee, args.len())?;
+                for arg in args {
+                    self.eval(arg)?;
+                }
+                self.call(callee, args.len(), None, *span)?;
+            }
+            Expr::Call {
+                callee,
+                args,
+                named,
+                span,
+            } => {
+                let callee = self.value(callee)?;
+                check_argc(&callee, args.len())?;
+                for arg in args {
+                    self.eval(arg)?;
+                }
+                let named = named
+                    .iter()
+                    .map(|(ident, arg)| Ok((ident.clone(), self.value(arg)?)))
+                    .collect::<Result<Vec<(Ident, Value)>, String>>()?;
+                self.call(callee, args.len(), Some(named), *span)?;
+            }
+            Expr::Lambda { params, body } => {
+                let func = Function {
+                    file: self.ctx.source.borrow().clone(),
+                    ..Function::lambda(params.clone(), body.clone())
+                };
+                let val = Value::Func(Closure {
+                    func: Rc::new(func),
+                    captured: self.locals.clone(),
+                    applied: Vec::new(),
+                });
+                self.push(val.clone(), || Trace::Value(val));
+            }
+            Expr::Block(body) => {
+                self.ctx.last_trace.borrow_mut().take();
+                let val = Executor::with_locals(self.ctx, self.locals.clone()).body(body)?;
+                // Left behind by `Executor::body` while tracing
+                let trace = self.ctx.last_trace.borrow_mut().take();
+                self.push(val.clone(), || trace.unwrap_or(Trace::Value(val)));
+            }
+            Expr::Record(fields) => {
+                let val = Value::Record(
+                    fields
+                        .iter()
+                        .map(|(ident, expr)| Ok((ident.clone(), self.value(expr)?)))
+                        .collect::<Result<Vec<(Ident, Value)>, String>>()?,
+                );
+                self.push(val.clone(), || Trace::Value(val));
+            }
+            Expr::With { record, fields } => {
+                let mut val = self.value(record)?;
+                for (ident, expr) in fields {
+                    let field = self.value(expr)?;
+                    val = val.with(ident, field)?;
+                }
+                self.push(val.clone(), || Trace::Value(val));
+            }
+            Expr::Tuple(items) => {
+                let val =
+                    Value::Tuple(items.iter().map(|item| self.value(item)).collect::<Result<
+                        Vec<Value>,
+                        String,
+                    >>(
+                    )?);
+                self.push(val.clone(), || Trace::Value(val));
+            }
+            Expr::Match { value, arms, .. } => {
+                let val = self.value(value)?;
+                let len = self.locals.len();
+                for arm in arms {
+                    if !arm.pattern.matches(&val, &mut self.locals) {
+                        continue;
+                    }
+                    let taken = match &arm.guard {
+                        Some(guard) => self.value(guard).and_then(|cond| cond.num()),
+                        None => Ok(1.0),
+                    };
+                    // The names the arm bound go out of scope with it
+                    let res = match taken {
+                        Ok(cond) if cond != 0.0 => self.eval(&arm.body).map(|_| true),
+                        taken => taken.map(|_| false),
+                    };
+                    self.locals.truncate(len);
+                    if res? {
+                        return Ok(());
+                    }
+                }
+                return Err(format!("{} isn't matched by any arm of the match", val));
+            }
+            Expr::Try {
+                body,
+                ident,
+                fallback,
+            } => {
+                let (depth, traced, locals) =
+                    (self.stack.len(), self.traces.len(), self.locals.len());
+                let unwound = self.ctx.traceback.borrow().len();
+                self.ctx.raised.set(None);
+                if let Err(message) = self.eval(body) {
+                    let kind = self.ctx.raised.take().unwrap_or(ErrorKind::Runtime);
+                    if kind == ErrorKind::Fatal {
+                        return Err(self.ctx.raise(kind, message));
+                    }
+                    // Whatever the body left behind when it failed
+                    self.stack.truncate(depth);
+                    self.traces.truncate(traced);
+                    self.locals.truncate(locals);
+                    self.ctx.traceback.borrow_mut().truncate(unwound);
+                    let field =
+                        |name: &str, text| (Ident::new(name.to_owned()).unwrap(), Value::Str(text));
+                    let err = Value::Record(vec![
+                        field("kind", kind.to_string()),
+                        field("message", message),
+                    ]);
+                    self.locals.push((ident.clone(), err));
+                    let res = self.eval(fallback);
+                    self.locals.truncate(locals);
+                    res?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates `expr` without leaving it on the stack
+    fn value(&mut self, expr: &Expr) -> Result<Value, String> {
+        self.eval(expr)?;
+        if self.tracing {
+            self.traces.pop();
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// Locals shadow functions, which shadow global variables
+    fn var(&mut self, ident: &Ident, span: Span) -> Result<(), String> {
+        let val = if let Some((_, val)) = self.locals.iter().rev().find(|(local, _)| local == ident)
+        {
+            val.clone()
+        } else if let Some(func) = self.ctx.functions.get(&self.ctx.resolve(ident)) {
+            let closure = Value::Func(Closure::new(func.clone()));
+            // Functions without arguments are called by naming them
+            if func.argc() == 0 {
+                return self.call(closure, 0, None, span);
+            }
+            closure
+        } else if let Some(val) = self.ctx.variables.borrow().get(&self.ctx.resolve(ident)) {
+            val.clone()
+        } else if let Some(val) = self.field(ident) {
+            val?
+        } else {
+            return Err(format!("Unknown identifier {}", ident));
+        };
+        self.push(val.clone(), || Trace::Value(val));
+        Ok(())
+    }
+
+    /// `loan.rate` read as the field `rate` of a variable `loan`, `None` if no part of the name is a variable
+    fn field(&self, ident: &Ident) -> Option<Result<Value, String>> {
+        ident.fields().into_iter().find_map(|(base, fields)| {
+            let val = match self.locals.iter().rev().find(|(local, _)| *local == base) {
+                Some((_, val)) => val.clone(),
+                None => self
+                    .ctx
+                    .variables
+                    .borrow()
+                    .get(&self.ctx.resolve(&base))?
+                    .clone(),
+            };
+            Some(
+                fields
+                    .iter()
+                    .try_fold(val, |val, field| val.field(field).cloned()),
+            )
+        })
+    }
+
+    /// Applies `op` to the top `argc` values. With `percent`, `a + b%` and `a - b%` add or take away b percent of a.
+    fn operator(&mut self, op: Operator, argc: usize, percent: bool) -> Result<(), String> {
+        self.ctx.tick()?;
+        let operands = self.stack[self.stack.len() - argc..].to_vec();
+        self.ctx.debug(
+            Event::Operator {
+                op,
+                operands: &operands,
+            },
+            &self.stack,
+        )?;
+        self.stack.truncate(self.stack.len() - argc);
+
+        let result = match operands.as_slice() {
+            // Anything can be compared for equality, not just numbers
+            [lhs, rhs]
+                if matches!(op, Operator::Eq | Operator::Ne)
+                    && !matches!((lhs, rhs), (Value::Num(_), Value::Num(_))) =>
+            {
+                let equal = lhs.equals(rhs)? == (op == Operator::Eq);
+                Value::Num(if equal { 1.0 } else { 0.0 })
+            }
+            [lhs, rhs] if op == Operator::Concat => match (lhs, rhs) {
+                (Value::Str(lhs), Value::Str(rhs)) => Value::Str(format!("{}{}", lhs, rhs)),
+                (Value::Str(_), val) | (val, _) => {
+                    return Err(format!("Operator {} expects strings, got {}", op, val))
+                }
+            },
+            _ => {
+                let nums = operands
+                    .iter()
+                    .map(|val| {
+                        val.num()
+                            .map_err(|_| format!("Operator {} expects numbers, got {}", op, val))
+                    })
+                    .collect::<Result<Vec<f64>, String>>()?;
+                Value::Num(match nums.as_slice() {
+                    [v] => op.operate_unary(*v)?,
+                    [lhs, rhs] if percent => op.operate(*lhs, lhs * rhs)?,
+                    [lhs, rhs] => op.operate(*lhs, *rhs)?,
+                    _ => unreachable!(),
+                })
+            }
+        };
+
+        let operands = if self.tracing {
+            self.traces.split_off(self.traces.len() - argc)
+        } else {
+            Vec::new()
+        };
+        self.push(result.clone(), || Trace::Operator {
+            op,
+            operands,
+            result,
+        });
+        Ok(())
+    }
+
+    /// Calls `callee` with the top `argc` values. Too few arguments partially apply it,
+    /// extra arguments are passed on to whatever the call returns. `named` is `Some` for `f(...)`,
+    /// which calls it right away whatever is missing. `span` is where it's called from.
+    fn call(
+        &mut self,
+        callee: Value,
+        argc: usize,
+        named: Option<Vec<(Ident, Value)>>,
+        span: Span,
+    ) -> Result<(), String> {
+        self.ctx.tick()?;
+        let closure = match callee {
+            Value::Func(closure) => closure,
+            val => {
+                return Err(format!(
+                    "{} is not a function, did you forget an operator?",
+                    val
+                ))
+            }
+        };
+        let mut args = closure.applied.clone();
+        args.extend(self.stack[self.stack.len() - argc..].iter().cloned());
+        let mut traces = Vec::new();
+        if self.tracing {
+            traces.extend(closure.applied.iter().cloned().map(Trace::Value));
+            traces.extend(self.traces.split_off(self.traces.len() - argc));
+        }
+
+        let func = closure.func.clone();
+        if named.is_none() && args.len() < func.required() {
+            self.stack.truncate(self.stack.len() - argc);
+            let val = Value::Func(Closure {
+                applied: args,
+                ..closure
+            });
+            self.push(val.clone(), || Trace::Value(val));
+            return Ok(());
+        }
+        // Before anything runs, so a call like `println(1, 2)` doesn't print first
+        func.check_argc(args.len(), &args)?;
+        let (args, rest) = func.bind(args, named.unwrap_or_default(), self.ctx)?;
+        let rest_traces = traces.split_off(traces.len().saturating_sub(rest.len()));
+        if self.tracing {
+            // Defaults, named arguments and the list of a variadic argument show as their values
+            traces.truncate(func.argc() - func.variadic as usize);
+            traces.extend(args[traces.len()..].iter().cloned().map(Trace::Value));
+        }
+
+        self.ctx.call_site.set(span);
+        self.ctx.debug(
+            Event::Call {
+                func: &func,
+                args: &args,
+            },
+            &self.stack,
+        )?;
+        self.stack.truncate(self.stack.len() - argc);
+
+        self.ctx.last_trace.borrow_mut().take();
+        let val = self.ctx.call(&closure, &args)?;
+        // Left behind by the evaluation of a user function's body
+        let body = self.ctx.last_trace.borrow_mut().take().map(Box::new);
+        self.push(val.clone(), || Trace::Call {
+            ident: func.ident.clone(),
+            args: func.args.iter().cloned().zip(traces).collect(),
+            body,
+            result: val.clone(),
+        });
+        self.ctx.debug(
+            Event::Return {
+                func: &func,
+                val: &val,
+            },
+            &self.stack,
+        )?;
+
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let val = self.value_on_top();
+        let argc = rest.len();
+        self.stack.extend(rest);
+        self.traces.extend(rest_traces);
+        self.call(val, argc, None, span)
+    }
+
+    fn value_on_top(&mut self) -> Value {
+        if self.tracing {
+            self.traces.pop();
+        }
+        self.stack.pop().unwrap()
+    }
+}
+
+/// Checks `argc` more arguments for `callee` before they're evaluated, knowing only those it already has
+fn check_argc(callee: &Value, argc: usize) -> Result<(), String> {
+    match callee {
+        Value::Func(closure) => closure
+            .func
+            .check_argc(closure.applied.len() + argc, &closure.applied),
+        _ => Ok(()),
+    }
+}
+
+/// Evaluates `expr` with `locals` in scope. While tracing, the trace is left in `Context::last_trace`
+pub fn eval(expr: &Expr, ctx: &Context, locals: Vec<(Ident, Value)>) -> Result<Value, String> {
+    let mut evaluator = Evaluator {
+        ctx,
+        locals,
+        stack: Vec::new(),
+        traces: Vec::new(),
+        tracing: ctx.tracing.get(),
+    };
+    evaluator.eval(expr)?;
+
+    if evaluator.tracing {
+        *ctx.last_trace.borrow_mut() = evaluator.traces.pop();
+    }
+    Ok(evaluator.value_on_top())
+}
+
+#[test]
+fn test_rpn_eval() {
+    use crate::{
+        interpreter::evaluate,
+        token::{tokenize, Token},
+    };
+    let mut ctx = Context::new();
+    // 10 + 2 * 3 = 16
+    let tokens = tokenize("10 + 2 * 3".to_owned()).unwrap();
+    assert_eq!(evaluate(&tokens, &ctx), Ok(Value::Num(16.0)));
+
+    // A function added without inferring its signature is called all the same
+    let mul = Function::new(&tokenize("function mul a b = a * b".to_owned()).unwrap()).unwrap();
+    assert_eq!(mul.sig, None);
+    ctx.functions
+        .insert(Ident::new("mul".to_owned()).unwrap(), mul.clone());
+    let tokens = tokenize("10 + mul 2 3".to_owned()).unwrap();
+    assert_eq!(evaluate(&tokens, &ctx), Ok(Value::Num(16.0)));
+    assert_eq!(
+        evaluate(&[Token::new("mul".to_owned()).unwrap()], &ctx).map(|val| val.to_string()),
+        Ok(Value::Func(Closure::new(mul)).to_string())
+    );
+}
+
+#[test]
+fn test_operators() {
+    use crate::{interpreter::evaluate, token::tokenize};
+    let ctx = Context::new();
+    let eval = |s: &str| evaluate(&tokenize(s.to_owned()).unwrap(), &ctx);
+
+    // Operand order and associativity
+    for (expr, expected) in [
+        ("10 - 3", 7.0),
+        ("10 - 3 - 2", 5.0),
+        ("10 + 3 - 2", 11.0),
+        ("12 / 3", 4.0),
+        ("12 / 3 / 2", 2.0),
+        ("2 * 3 / 4", 1.5),
+        ("2 ^ 3", 8.0),
+        ("2 ^ 3 ^ 2", 512.0),
+        ("7 % 3", 1.0),
+        ("-7 % 3", 2.0),
+        ("7 % -3", -2.0),
+        ("5.5 % 2", 1.5),
+        ("10 % 4 % 3", 2.0),
+        ("7 // 2", 3.0),
+        ("-7 // 2", -4.0),
+        ("20 // 3 // 2", 3.0),
+        ("6 & 3", 2.0),
+        ("6 | 3", 7.0),
+        ("6 xor 3", 5.0),
+        ("1 << 4", 16.0),
+        ("256 >> 2", 64.0),
+        ("-16 >> 2", -4.0),
+        ("1 << 2 << 3", 32.0),
+        ("256 >> 2 >> 1", 32.0),
+        ("~5", -6.0),
+        ("~~5", 5.0),
+        ("--5", 5.0),
+        ("-~5", 6.0),
+    ] {
+        assert_eq!(eval(expr), Ok(Value::Num(expected)), "{}", expr);
+    }
+
+    // Precedence
+    for (expr, expected) in [
+        ("2 + 3 * 4", 14.0),
+        ("2 * 3 ^ 2", 18.0),
+        ("-2 ^ 2", 4.0),
+        ("2 ^ -1", 0.5),
+        ("1 + 7 % 4", 4.0),
+        ("2 * 7 // 4", 3.0),
+        ("1 + 2 << 1", 6.0),
+        ("1 << 2 & 6", 4.0),
+        ("6 & 3 xor 1", 3.0),
+        ("1 | 6 xor 3", 5.0),
+        ("1 xor 3 | 4", 6.0),
+        ("~1 & 3", 2.0),
+    ] {
+        assert_eq!(eval(expr), Ok(Value::Num(expected)), "{}", expr);
+    }
+
+    assert!(eval("7 % 0").unwrap().num().unwrap().is_nan());
+    assert_eq!(
+        eval("2.5 & 1"),
+        Err("Operator & expects integers, got 2.5".to_owned())
+    );
+    assert_eq!(
+        eval("~0.5"),
+        Err("Operator ~ expects integers, got 0.5".to_owned())
+    );
+    assert_eq!(
+        eval("1 << 64"),
+        Err("Cannot shift by 64, expected 0 to 63".to_owned())
+    );
+}
+
+#[test]
+fn test_lambdas() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    for line in [
+        "function add a b = a + b",
+        "function twice f x = f (f x)",
+        "function compose f g = \\x -> f (g x)",
+        "var sq = \\x -> x ^ 2",
+        "var inc = add 1",
+        "var k = 3",
+    ] {
+        engine.run(line).unwrap();
+    }
+    let eval = |s: &str| engine.eval(s).map(|val| format!("{}", val));
+
+    assert_eq!(eval("sq 4"), Ok("16".to_owned()));
+    assert_eq!(eval("(fn(x, y) => x * y) 3 4"), Ok("12".to_owned()));
+    assert_eq!(eval("twice sq 3"), Ok("81".to_owned()));
+    assert_eq!(eval("twice (\\x -> x + k) 1"), Ok("7".to_owned()));
+    assert_eq!(eval("inc 41"), Ok("42".to_owned()));
+    assert_eq!(eval("twice (add 10) 1"), Ok("21".to_owned()));
+    assert_eq!(eval("compose sq inc 2"), Ok("9".to_owned()));
+    assert_eq!(eval("(\\a b c -> a * b - c) 2 3 4"), Ok("2".to_owned()));
+    assert_eq!(eval("(\\a -> \\b -> a - b) 10 4"), Ok("6".to_owned()));
+
+    // Function values display as the expression that made them
+    assert_eq!(eval("inc"), Ok("add 1".to_owned()));
+    assert_eq!(eval("sq"), Ok("\\x -> x ^ 2".to_owned()));
+    assert_eq!(
+        eval("(\\x y -> x - y) 5"),
+        Ok("(\\x y -> x - y) 5".to_owned())
+    );
+
+    // Lambdas capture the locals they can see
+    assert_eq!(eval("(compose inc inc) 0"), Ok("2".to_owned()));
+
+    assert_eq!(
+        eval("k 2"),
+        Err("3 is not a function, did you forget an operator?".to_owned())
+    );
+    assert_eq!(
+        eval("sq + 1"),
+        Err("Operator + expects numbers, got \\x -> x ^ 2".to_owned())
+    );
+
+    // A function value keeps the function it was made from, which no variable can hide
+    engine.run("var g = add").unwrap();
+    engine.run("var h = add 100").unwrap();
+    engine.run("function add a b = a * b").unwrap();
+    let eval = |s: &str| engine.eval(s).map(|val| format!("{}", val));
+    assert_eq!(eval("g 2 3 + h 1 + add 2 3"), Ok("112".to_owned()));
+    assert_eq!(
+        engine.run("var add = 5"),
+        Err("add is a function and can't be a variable".to_owned())
+    );
+    assert_eq!(
+        engine.run("var (add, z) = (1, 2)"),
+        Err("add is a function and can't be a variable".to_owned())
+    );
+
+    // Only values made by a lambda display as one, whatever a declared function is called
+    engine.run("function λ x = x * 2").unwrap();
+    assert_eq!(
+        engine.eval("λ").map(|val| val.to_string()),
+        Ok("λ".to_owned())
+    );
+}
+
+#[test]
+fn test_records() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    engine
+        .run("var loan = { principal: 1000, rate: 0.05, term: 30 }")
+        .unwrap();
+    engine
+        .run("function interest l = l.principal * l.rate")
+        .unwrap();
+    engine.run("var nested = {\n  inner: { v: 2 },\n}").unwrap();
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+
+    assert_eq!(eval("loan.rate"), Ok("0.05".to_owned()));
+    assert_eq!(eval("interest loan"), Ok("50".to_owned()));
+    assert_eq!(
+        eval("interest { loan with rate: 0.1 }"),
+        Ok("100".to_owned())
+    );
+    assert_eq!(
+        eval("{ loan with rate: 0.04, term: 15 }"),
+        Ok("{ principal: 1000, rate: 0.04, term: 15 }".to_owned())
+    );
+    assert_eq!(eval("nested.inner.v + 1"), Ok("3".to_owned()));
+    assert_eq!(eval("(\\r -> r.inner.v) nested"), Ok("2".to_owned()));
+
+    // Records are equal when their fields are, in any order
+    assert_eq!(
+        eval("{ term: 30, rate: 0.05, principal: 1000 } == loan"),
+        Ok("1".to_owned())
+    );
+    assert_eq!(eval("{ loan with term: 15 } != loan"), Ok("1".to_owned()));
+    assert_eq!(eval("{ a: 1 } == { a: 1, b: 2 }"), Ok("0".to_owned()));
+
+    assert_eq!(
+        eval("loan.nope"),
+        Err("{ principal: 1000, rate: 0.05, term: 30 } has no field nope".to_owned())
+    );
+    assert_eq!(
+        eval("{ nested with v: 1 }"),
+        Err("{ inner: { v: 2 } } has no field v".to_owned())
+    );
+    assert_eq!(
+        eval("{ a: 1, a: 2 }"),
+        Err("Field a is given twice".to_owned())
+    );
+    assert_eq!(
+        eval("loan + 1"),
+        Err("Operator + expects numbers, got { principal: 1000, rate: 0.05, term: 30 }".to_owned())
+    );
+    assert_eq!(
+        engine.run("function f x = loan.years"),
+        Err(
+            "In function f: { principal: num, rate: num, term: num } has no field years".to_owned()
+        )
+    );
+}
+
+#[test]
+fn test_tuples() {
+    use crate::engine::{Engine, Outcome};
+    let mut engine = Engine::new();
+    let num = |ident: &str, v: f64| (Ident::new(ident.to_owned()).unwrap(), Value::Num(v));
+    assert_eq!(
+        engine.run("var (q, r) = divmod(17, 5)"),
+        Ok(Outcome::Variables(vec![num("q", 3.0), num("r", 2.0)]))
+    );
+    assert_eq!(
+        engine.run("var (a, b) = (1, 2, 3)"),
+        Err("Expected a tuple of 2 values to take apart into (a, b), got (1, 2, 3)".to_owned())
+    );
+    assert!(engine.run("var (a, a) = (1, 2)").is_err());
+    assert_eq!(
+        engine.run("var (pi, x) = (1, 2)"),
+        Err("pi is a builtin, use override to replace it".to_owned())
+    );
+    engine.run("function swap (a, b) = (b, a)").unwrap();
+    engine
+        .run("function dist (x1, y1) (x2, y2) = sqrt((x2 - x1)^2 + (y2 - y1)^2)")
+        .unwrap();
+    engine
+        .run("function spread xs { var ((lo, hi), n) = (xs, 2); return (hi - lo) / n }")
+        .unwrap();
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+
+    assert_eq!(eval("q * 5 + r"), Ok("17".to_owned()));
+    assert_eq!(eval("divmod(-7, 2)"), Ok("(-4, 1)".to_owned()));
+    assert_eq!(eval("swap (1, (2, 3))"), Ok("((2, 3), 1)".to_owned()));
+    assert_eq!(eval("dist (0, 0) (3, 4)"), Ok("5".to_owned()));
+    assert_eq!(eval("minmax 4 1 9"), Ok("(1, 9)".to_owned()));
+    assert_eq!(eval("spread (minmax 4 1 9)"), Ok("4".to_owned()));
+    assert_eq!(
+        eval("polar(0, 2)"),
+        Ok(format!("(2, {})", std::f64::consts::FRAC_PI_2))
+    );
+    assert_eq!(eval("(1, 2) == swap (2, 1)"), Ok("1".to_owned()));
+    assert_eq!(eval("(1, 2) != (1, 2, 3)"), Ok("1".to_owned()));
+    assert_eq!(
+        eval("swap 1"),
+        Err("Expected a tuple of 2 values to take apart into (a, b), got 1".to_owned())
+    );
+    assert_eq!(
+        engine.run("function g x = swap x + 1"),
+        Err("In function g: Type mismatch: expected num, got ('d, 'c) in operand of +".to_owned())
+    );
+}
+
+#[test]
+fn test_clauses() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    engine.run("function fact 0 = 1").unwrap();
+    engine.run("function fact n = n * fact (n - 1)").unwrap();
+    engine.run("function sign n | n < 0 = -1").unwrap();
+    engine.run("function sign 0 = 0").unwrap();
+    engine.run("function half n | n % 2 == 0 = n / 2").unwrap();
+    engine
+        .run("function quadrant (x, y) = match (x > 0, y > 0) { (1, 1) => 1, (0, 1) => 2, (0, 0) => 3, _ => 4 }")
+        .unwrap();
+    assert_eq!(
+        engine.run("function half a b = a"),
+        Err("Every clause of half has to take 1 argument(s)".to_owned())
+    );
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+
+    assert_eq!(eval("fact 5"), Ok("120".to_owned()));
+
+    // Declarations display as they were written, followed by the inferred signature
+    let shown =
+        |ident: &str| engine.context.functions[&Ident::new(ident.to_owned()).unwrap()].to_string();
+    assert_eq!(
+        shown("fact"),
+        "function fact 0 = 1\nfunction fact n = n * fact (n - 1)  # fn(num) -> num"
+    );
+    assert_eq!(
+        shown("sign"),
+        "function sign n | n < 0 = -1\nfunction sign 0 = 0  # fn(num) -> num"
+    );
+    assert_eq!(eval("sign (-3) + sign 0"), Ok("-1".to_owned()));
+    assert_eq!(
+        eval("sign 2"),
+        Err("No clause of sign matches 2".to_owned())
+    );
+    assert_eq!(eval("half 8"), Ok("4".to_owned()));
+    assert_eq!(eval("quadrant (-1, 2)"), Ok("2".to_owned()));
+    assert_eq!(eval("quadrant (3, -2)"), Ok("4".to_owned()));
+    assert_eq!(
+        eval("match 3 { 1 => 10, n if n > 2 => n * 2, _ => 0 }"),
+        Ok("6".to_owned())
+    );
+    assert_eq!(
+        eval("match (1, (2, 3)) { (a, (b, 4)) => a, (a, (b, c)) => a + b + c }"),
+        Ok("6".to_owned())
+    );
+    assert_eq!(
+        eval("match 5 { 1 => 2 }"),
+        Err("5 isn't matched by any arm of the match".to_owned())
+    );
+    assert_eq!(
+        eval("match 1 { (a, b) => a }"),
+        Err("1 isn't matched by any arm of the match".to_owned())
+    );
+}
+
+#[test]
+fn test_try() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    engine
+        .run("function root x = try assert(x >= 0, \"negative\") * sqrt x catch e => -1")
+        .unwrap();
+    engine.run("function forever x = forever (x + 1)").unwrap();
+    // The fallback has the type of the body
+    engine
+        .run("function f x = try x catch e => \"none\"")
+        .unwrap();
+    assert_eq!(
+        engine.context.functions[&Ident::new("f".to_owned()).unwrap()]
+            .sig
+            .as_ref()
+            .map(|sig| sig.to_string()),
+        Some("fn(str) -> str".to_owned())
+    );
+    assert_eq!(
+        engine.run("function g x = try x + 1 catch e => e.message"),
+        Err(
+            "In function g: Type mismatch: expected num, got str in the fallback of catch e"
+                .to_owned()
+        )
+    );
+    engine.set_instruction_limit(Some(100));
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+
+    assert_eq!(eval("root 9 + root (-9)"), Ok("2".to_owned()));
+    assert_eq!(
+        eval("try error(\"out of range\") catch e => e"),
+        Ok("{ kind: \"error\", message: \"out of range\" }".to_owned())
+    );
+    assert_eq!(
+        eval("try assert(0, \"x > 0\") catch e => e.kind ++ \": \" ++ e.message"),
+        Ok("\"assert: Assertion failed: x > 0\"".to_owned())
+    );
+    assert_eq!(
+        eval("try sqrt \"4\" catch e => e.kind"),
+        Ok("\"runtime\"".to_owned())
+    );
+    // The error of a fallback isn't caught by the same try, but a try around it catches it
+    assert_eq!(
+        eval("try (try error(\"a\") catch e => error(e.message ++ \"b\")) catch e => e.message"),
+        Ok("\"ab\"".to_owned())
+    );
+    assert_eq!(
+        eval("1 + try error(\"a\") catch e => 2"),
+        Ok("3".to_owned())
+    );
+    assert_eq!(eval("error(\"uncaught\")"), Err("uncaught".to_owned()));
+    assert_eq!(
+        eval("assert(1 > 2, \"one is small\")"),
+        Err("Assertion failed: one is small".to_owned())
+    );
+    // Reaching the instruction limit can't be caught
+    assert_eq!(
+        eval("try forever 0 catch e => 0"),
+        Err("Stopped after reaching the limit of 100 instructions".to_owned())
+    );
+}
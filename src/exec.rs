@@ -0,0 +1,361 @@
+use crate::{
+    eval::eval,
+    interpreter::Context,
+    stdlib,
+    token::{Ident, Operator},
+    trace::Trace,
+    tree::{Expr, Stmt},
+    types::Type,
+    value::Value,
+};
+
+/// Where control goes after a statement
+#[derive(PartialEq, Debug, Clone)]
+pub enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// Runs statements. A `var` inside a block is local to that block, assignment updates the innermost
+/// variable with the name, falling back to the global variables of the context.
+pub struct Executor<'a> {
+    ctx: &'a Context,
+    locals: Vec<(Ident, Value)>,
+    /// Declared types of `locals`
+    annotations: Vec<Option<Type>>,
+    /// The trace of every evaluated expression, while tracing
+    steps: Vec<Trace>,
+}
+impl<'a> Executor<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        Executor {
+            ctx,
+            locals: Vec::new(),
+            annotations: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// For a function body, where the arguments are already in scope
+    pub fn with_locals(ctx: &'a Context, locals: Vec<(Ident, Value)>) -> Self {
+        Executor {
+            ctx,
+            annotations: vec![None; locals.len()],
+            locals,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Runs the body of a function, which has to `return` a value
+    pub fn body(mut self, body: &[Stmt]) -> Result<Value, String> {
+        match self.block(body)? {
+            Flow::Return(val) => {
+                if self.ctx.tracing.get() {
+                    *self.ctx.last_trace.borrow_mut() = Some(Trace::Block {
+                        steps: self.steps,
+                        result: val.clone(),
+                    });
+                }
+                Ok(val)
+            }
+            _ => Err(match self.ctx.frames.borrow().last() {
+                Some(frame) => format!("{} finished without returning a value", frame.ident),
+                None => "Finished without returning a value".to_owned(),
+            }),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        let val = eval(expr, self.ctx, self.locals.clone())?;
+        if let Some(trace) = self.ctx.last_trace.borrow_mut().take() {
+            self.steps.push(trace);
+        }
+        Ok(val)
+    }
+
+    fn declare(&mut self, ident: Ident, ann: Option<Type>, val: Value) {
+        self.locals.push((ident, val));
+        self.annotations.push(ann);
+    }
+
+    /// Runs `body`, dropping the variables declared in it afterwards
+    fn block(&mut self, body: &[Stmt]) -> Result<Flow, String> {
+        let depth = self.locals.len();
+        let mut flow = Ok(Flow::Next);
+        for stmt in body {
+            flow = self.statement(stmt);
+            if flow != Ok(Flow::Next) {
+                break;
+            }
+        }
+        self.locals.truncate(depth);
+        self.annotations.truncate(depth);
+        flow
+    }
+
+    pub fn statement(&mut self, stmt: &Stmt) -> Result<Flow, String> {
+        self.ctx.tick()?;
+        match stmt {
+            Stmt::Function(func) => {
+                return Err(format!(
+                    "Function {} can only be declared at the top level",
+                    func.ident
+                ))
+            }
+            Stmt::Import { .. } => return Err("Imports can only be at the top level".to_owned()),
+            Stmt::Use { .. } => return Err("use can only be used at the top level".to_owned()),
+            Stmt::Override(_) => {
+                return Err("override can only be used at the top level".to_owned())
+            }
+            Stmt::Const { .. } => {
+                return Err("Constants can only be declared at the top level".to_owned())
+            }
+            Stmt::Var { ident, ann, expr } => {
+                let val = self.eval(expr)?;
+                if let Some(ty) = ann {
+                    if !ty.accepts(&val) {
+                        return Err(format!("Variable {} expects {}, got {}", ident, ty, val));
+                    }
+                }
+                self.declare(ident.clone(), ann.clone(), val);
+            }
+            Stmt::Destructure { pattern, expr } => {
+                let mut vars = Vec::new();
+                pattern.destructure(&self.eval(expr)?, &mut vars)?;
+                for (ident, val) in vars {
+                    self.declare(ident, None, val);
+                }
+            }
+            Stmt::Assign {
+                ident, op, expr, ..
+            } => {
+                self.assign(ident, *op, expr)?;
+            }
+            Stmt::While { cond, body } => {
+                while self.eval(cond)?.num()? != 0.0 {
+                    self.ctx.tick()?;
+                    match self.block(body)? {
+                        Flow::Break => break,
+                        Flow::Return(val) => return Ok(Flow::Return(val)),
+                        Flow::Next | Flow::Continue => (),
+                    }
+                }
+            }
+            Stmt::For {
+                ident,
+                from,
+                to,
+                body,
+            } => {
+                let (from, to) = (self.eval(from)?.num()?, self.eval(to)?.num()?);
+                let mut i = from;
+                while i <= to {
+                    self.ctx.tick()?;
+                    self.declare(ident.clone(), None, Value::Num(i));
+                    let flow = self.block(body);
+                    self.locals.pop();
+                    self.annotations.pop();
+                    match flow? {
+                        Flow::Break => break,
+                        Flow::Return(val) => return Ok(Flow::Return(val)),
+                        Flow::Next | Flow::Continue => (),
+                    }
+                    i += 1.0;
+                }
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                return if self.eval(cond)?.num()? != 0.0 {
+                    self.block(then)
+                } else {
+                    self.block(otherwise)
+                }
+            }
+            Stmt::Break => return Ok(Flow::Break),
+            Stmt::Continue => return Ok(Flow::Continue),
+            Stmt::Return(expr) => return Ok(Flow::Return(self.eval(expr)?)),
+            Stmt::Expr(expr) => {
+                self.eval(expr)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Updates the innermost variable named `ident`. With `op`, the new value is `ident op expr`.
+    pub fn assign(
+        &mut self,
+        ident: &Ident,
+        op: Option<Operator>,
+        expr: &Expr,
+    ) -> Result<Value, String> {
+        let mut val = self.eval(expr)?;
+        let local = self.locals.iter().rposition(|(local, _)| local == ident);
+        let global = self.ctx.resolve(ident);
+        let (old, ann) = match local {
+            Some(idx) => (self.locals[idx].1.clone(), self.annotations[idx].clone()),
+            None if stdlib::is_builtin(&global) => {
+                return Err(format!("{} is a builtin and can't be assigned to", ident))
+            }
+            None if self.ctx.readonly.contains_key(&global) => {
+                return Err(format!(
+                    "{} is {} and can't be assigned to",
+                    ident, self.ctx.readonly[&global]
+                ))
+            }
+            None => match self.ctx.variables.borrow().get(&global) {
+                Some(old) => (old.clone(), self.ctx.annotations.get(&global).cloned()),
+                None if self.ctx.functions.contains_key(&global) => {
+                    return Err(format!("{} is a function and can't be assigned to", ident))
+                }
+                None => {
+                    return Err(format!(
+                        "Unknown variable {}, declare it with var first",
+                        ident
+                    ))
+                }
+            },
+        };
+
+        if let Some(op) = op {
+            let operand = |val: &Value| {
+                val.num()
+                    .map_err(|_| format!("Operator {} expects numbers, got {}", op, val))
+            };
+            val = Value::Num(op.operate(operand(&old)?, operand(&val)?)?);
+        }
+        if let Some(ty) = ann {
+            if !ty.accepts(&val) {
+                return Err(format!("Variable {} expects {}, got {}", ident, ty, val));
+            }
+        }
+
+        match local {
+            Some(idx) => self.locals[idx].1 = val.clone(),
+            None => {
+                self.ctx.variables.borrow_mut().insert(global, val.clone());
+            }
+        }
+        Ok(val)
+    }
+}
+
+#[test]
+fn test_loops() {
+    use crate::engine::{Engine, Outcome};
+    let mut engine = Engine::new();
+    let mut run = |src: &str| engine.run(src);
+    let num = |n: f64| Ok(Outcome::Value(Value::Num(n)));
+
+    run("var total = 0\nfor i in 1..10 do total += i end").unwrap();
+    assert_eq!(run("total"), num(55.0));
+
+    // Fibonacci
+    run("var a = 0; var b = 1; var n = 0").unwrap();
+    run("while n < 10 do\n  var next = a + b\n  a = b\n  b = next\n  n += 1\nend").unwrap();
+    assert_eq!(run("a"), num(55.0));
+    assert!(run("next").is_err());
+
+    // break and continue affect the innermost loop
+    run("var count = 0").unwrap();
+    run("for i in 1..4 do\n  for j in 1..10 do\n    count += 1\n    break\n  end\nend").unwrap();
+    assert_eq!(run("count"), num(4.0));
+    run("count = 0").unwrap();
+    run("for k in 1..10 do count += 1; break; count = 100 end").unwrap();
+    assert_eq!(run("count"), num(1.0));
+    run("count = 0").unwrap();
+    run("for k in 1..10 do continue; count += 1 end").unwrap();
+    assert_eq!(run("count"), num(0.0));
+
+    assert_eq!(
+        run("var x: int = 1; x /= 2"),
+        Err("Variable x expects int, got 0.5".to_owned())
+    );
+    assert_eq!(run("x"), num(1.0));
+    assert_eq!(
+        run("y = 1"),
+        Err("Unknown variable y, declare it with var first".to_owned())
+    );
+    run("function f a = a").unwrap();
+    assert_eq!(
+        run("f = 1"),
+        Err("f is a function and can't be assigned to".to_owned())
+    );
+    assert_eq!(
+        run("sin = 1"),
+        Err("sin is a builtin and can't be assigned to".to_owned())
+    );
+    assert_eq!(
+        run("break"),
+        Err("break can only be used inside a loop".to_owned())
+    );
+    assert_eq!(
+        run("while 1 do x = 1"),
+        Err("Expected 'end' to close the block".to_owned())
+    );
+
+    engine.set_instruction_limit(Some(1000));
+    assert_eq!(
+        engine.run("while 1 do end"),
+        Err("Stopped after reaching the limit of 1000 instructions".to_owned())
+    );
+    assert!(engine.run("for i in 1..100 do x += 1 end").is_ok());
+}
+
+#[test]
+fn test_block_functions() {
+    use crate::engine::{Engine, Outcome};
+    let mut engine = Engine::new();
+    for src in [
+        "function sign x {\n  if x < 0 then\n    return -1\n  else if x == 0 then\n    return 0\n  end\n  return 1\n}",
+        "function first_over limit {\n  for i in 1..100 do\n    var sq = i * i\n    if sq > limit then return i end\n  end\n  return 0\n}",
+        "function fact (n: int): int {\n  var acc = 1\n  while n > 1 do\n    acc *= n\n    n -= 1\n  end\n  return acc\n}",
+        "function missing x { var y = x }",
+    ] {
+        assert!(matches!(engine.run(src), Ok(Outcome::Function(_))), "{}", src);
+    }
+    let eval = |s: &str| engine.eval(s).and_then(|val| val.num());
+
+    assert_eq!(eval("sign (-3)"), Ok(-1.0));
+    assert_eq!(eval("sign 0"), Ok(0.0));
+    assert_eq!(eval("sign 5"), Ok(1.0));
+    assert_eq!(eval("first_over 50"), Ok(8.0));
+    assert_eq!(eval("fact 5"), Ok(120.0));
+    assert_eq!(
+        eval("missing 1"),
+        Err("missing finished without returning a value".to_owned())
+    );
+
+    // Locals stay inside the call
+    for ident in ["acc", "sq", "i", "n"] {
+        assert!(!engine
+            .context
+            .variables
+            .borrow()
+            .contains_key(&Ident::new(ident.to_owned()).unwrap()));
+    }
+
+    assert_eq!(
+        format!(
+            "{}",
+            engine.context.functions[&Ident::new("fact".to_owned()).unwrap()]
+        ),
+        "function fact (n: int): int { var acc = 1; while n > 1 do acc *= n; n -= 1 end; return acc }  # fn(int) -> int"
+    );
+    assert_eq!(
+        engine.run("return 1"),
+        Err("return can only be used inside a function body".to_owned())
+    );
+    assert_eq!(
+        engine.run("function f x {\n  return x"),
+        Err("Expected '}' to close the block".to_owned())
+    );
+    assert!(engine
+        .run("function f x {\n  function g y = y\n  return x\n}")
+        .is_err());
+    assert!(engine.run("function f x { return x + sign }").is_err());
+}
@@ -1,132 +1,367 @@
 use {
     crate::{
-        interpreter::{evaluate, Context},
-        token::{Ident, Keyword, Operator, Token},
+        debugger::Frame,
+        eval::eval,
+        interpreter::Context,
+        token::{Ident, Span, Token},
+        tree::{parse, Expr, Pattern, Stmt},
+        types::Type,
+        value::Value,
     },
     std::{
-        collections::HashMap,
         fmt::{Debug, Display, Formatter, Result as fmt_Result},
+        path::Path,
+        rc::Rc,
     },
 };
 
-fn has_dups(idents: &Vec<Ident>) -> Option<Vec<Ident>> {
-    let mut map = HashMap::new();
-    let mut dups = Vec::new();
-
-    for (idx, ident) in idents.iter().enumerate() {
-        if let Some(_) = map.insert(ident, idx) {
-            dups.push(ident.clone());
-        }
-    }
-
-    if dups.is_empty() {
-        None
-    } else {
-        Some(dups)
-    }
-}
-
 #[derive(PartialEq, Clone, Debug)]
 pub struct Function {
     pub ident: Ident,
     pub args: Vec<Ident>,
-    pub code: Vec<Token>,
+    pub body: Rc<Expr>,
+    /// Declared types of `args`, `None` where the argument wasn't annotated
+    pub annotations: Vec<Option<Type>>,
+    /// Values of `args` left out of a call, `None` where the argument is required
+    pub defaults: Vec<Option<Rc<Expr>>>,
+    /// Patterns `args` are matched against, `function dist (x, y) = ...` or `function fact 0 = 1`. The
+    /// argument itself is unnamed.
+    pub patterns: Vec<Option<Pattern>>,
+    /// `| cond`, the clause only applies to arguments for which it holds
+    pub guard: Option<Rc<Expr>>,
+    /// Further clauses, `function fact n = ...` after `function fact 0 = 1`, tried in order when this one
+    /// doesn't accept the arguments
+    pub clauses: Vec<Function>,
+    /// Whether the last argument collects any extra arguments into a list, `...xs`
+    pub variadic: bool,
+    /// Declared return type
+    pub ret: Option<Type>,
+    /// Inferred when the function is added to a `Context`
+    pub sig: Option<Type>,
+    /// The file it's declared in, `None` for code given to the REPL or `Engine::run`
+    pub file: Option<Rc<Path>>,
+    /// Made by evaluating `\x -> body` rather than declared
+    pub lambda: bool,
 }
 impl Function {
-    pub fn new(tokens: &Vec<Token>) -> Result<Self, String> {
-        if let Some(Token::Keyword(Keyword::Function)) = tokens.first() {
-            if let Some(Token::Identifier(ident)) = tokens.get(1) {
-                let mut args = Vec::new();
-                let mut idx = 2;
-                while let Some(tok) = tokens.get(idx) {
-                    idx += 1;
-                    match tok {
-                        Token::Operator(Operator::Assign) => break,
-                        Token::Identifier(ident) => args.push(ident.clone()),
-                        _ => {
-                            return Err(format!(
-                                "Expected identifier in declaration of {}, got: {:?}",
-                                ident, tok
-                            ))
-                        }
-                    }
-                }
+    pub fn new(tokens: &[Token]) -> Result<Self, String> {
+        match parse(tokens)? {
+            Stmt::Function(func) => Ok(func),
+            _ => unreachable!(),
+        }
+    }
 
-                let code = tokens.get(idx..).unwrap().to_vec();
-
-                if code.is_empty() {
-                    Err("Function declaration with no body".to_owned())
-                } else {
-                    if let Some(dups) = has_dups(&args) {
-                        Err(format!("Encountered multiple declarations of ({}) in argslist, dont do that :)",
-                                    dups.iter()
-                                        .map(|ident| ident.internal_cloned())
-                                        .collect::<Vec<String>>()
-                                        .join(", ")
-                        ))
-                    } else {
-                        Ok(Function {
-                            ident: ident.clone(),
-                            args,
-                            code,
-                        })
-                    }
+    /// An anonymous function, created by evaluating `\x -> body`
+    pub fn lambda(params: Vec<Ident>, body: Rc<Expr>) -> Self {
+        Function {
+            ident: Ident::new("λ".to_owned()).unwrap(),
+            annotations: vec![None; params.len()],
+            defaults: vec![None; params.len()],
+            patterns: vec![None; params.len()],
+            guard: None,
+            clauses: Vec::new(),
+            variadic: false,
+            args: params,
+            body,
+            ret: None,
+            sig: None,
+            file: None,
+            lambda: true,
+        }
+    }
+
+    /// Evaluates the body of the first clause accepting `args`, on top of the locals a lambda captured
+    pub fn call(
+        &self,
+        args: &[Value],
+        captured: &[(Ident, Value)],
+        ctx: &Context,
+    ) -> Result<Value, String> {
+        if args.len() != self.args.len() {
+            return Err(format!(
+                "Did not recieve the right amount of arguments. Expected {}, got {}",
+                self.args.len(),
+                args.len()
+            ));
+        }
+        // Guards can call functions too, so the call counts from before a clause is chosen
+        ctx.enter(&self.ident)?;
+        // Where the call is, in the caller's file, before the guards or the body make calls of their own
+        let span = ctx.call_site.take();
+        let caller = ctx.source.replace(self.file.clone());
+        let val = self.dispatch(args, captured, ctx, span, caller.clone());
+        ctx.source.replace(caller);
+        ctx.leave();
+        val
+    }
+
+    /// Evaluates the first clause accepting `args`, called at `span` in `source`
+    fn dispatch(
+        &self,
+        args: &[Value],
+        captured: &[(Ident, Value)],
+        ctx: &Context,
+        span: Span,
+        source: Option<Rc<Path>>,
+    ) -> Result<Value, String> {
+        let mut chosen = None;
+        for clause in std::iter::once(self).chain(&self.clauses) {
+            if let Some(locals) = clause.accepts(args, captured, ctx)? {
+                chosen = Some((clause, locals));
+                break;
+            }
+        }
+        let (clause, locals) = chosen.ok_or_else(|| self.unmatched(args))?;
+
+        ctx.frames.borrow_mut().push(Frame {
+            ident: self.ident.clone(),
+            bindings: clause
+                .args
+                .iter()
+                .cloned()
+                .zip(args.iter().cloned())
+                .collect(),
+            span,
+            source,
+        });
+        let val = eval(&clause.body, ctx, locals);
+        let frame = ctx.frames.borrow_mut().pop().unwrap();
+        // An error unwinding out of the call keeps the frame, for the traceback
+        if val.is_err() {
+            ctx.traceback.borrow_mut().push(frame);
+        }
+
+        let val = val?;
+        match &clause.ret {
+            Some(ty) if !ty.accepts(&val) => Err(format!(
+                "{} is declared to return {}, but returned {}",
+                self.ident, ty, val
+            )),
+            _ => Ok(val),
+        }
+    }
+
+    /// The locals the body of this clause sees, `None` if its patterns or guard don't accept `args`
+    fn accepts(
+        &self,
+        args: &[Value],
+        captured: &[(Ident, Value)],
+        ctx: &Context,
+    ) -> Result<Option<Vec<(Ident, Value)>>, String> {
+        for ((arg, ann), val) in self.args.iter().zip(&self.annotations).zip(args) {
+            let ty = match ann {
+                Some(ty) => ty,
+                None => continue,
+            };
+            // The annotation of `...(xs: type)` is the type of each argument it collects
+            let vals = match val {
+                Value::List(items) if self.variadic && arg == self.args.last().unwrap() => {
+                    items.as_slice()
                 }
-            } else {
-                Err("`function` keyword not followed by an identifier".to_owned())
+                val => std::slice::from_ref(val),
+            };
+            if let Some(val) = vals.iter().find(|val| !ty.accepts(val)) {
+                return Err(format!(
+                    "Argument {} of {} expects {}, got {}",
+                    arg, self.ident, ty, val
+                ));
             }
-        } else {
-            unreachable!()
-        }
-    }
-
-    pub fn call(&self, args: &Vec<Token>, ctx: &Context) -> Result<f64, String> {
-        let mut code = Vec::new();
-
-        for tok in &self.code {
-            match tok {
-                Token::Identifier(ident) => {
-                    if self.args.contains(&ident) {
-                        let idx = self.args.iter().position(|id| id == ident).unwrap();
-                        if let Some(arg) = args.get(idx) {
-                            code.push(arg.clone());
-                        } else {
-                            return Err(format!("Did not recieve the right amount of arguments. Expected {}, got {}", self.args.len(), args.len()));
-                        }
-                    } else if let Some(val) = ctx.variables.get(&ident) {
-                        code.push(Token::Value(*val));
-                    } else {
-                        return Err(format!("Unknown identifier {}", ident));
-                    }
+        }
+
+        let mut locals = captured.to_vec();
+        locals.extend(self.args.iter().cloned().zip(args.iter().cloned()));
+        for (pattern, val) in self.patterns.iter().zip(args) {
+            match pattern {
+                Some(pattern) if !pattern.matches(val, &mut locals) => return Ok(None),
+                _ => (),
+            }
+        }
+        match &self.guard {
+            Some(guard) if eval(guard, ctx, locals.clone())?.num()? == 0.0 => Ok(None),
+            _ => Ok(Some(locals)),
+        }
+    }
+
+    /// Why no clause accepts `args`
+    fn unmatched(&self, args: &[Value]) -> String {
+        // Without numbers or guards only a tuple of the wrong shape is turned down, `destructure` says how
+        if !self.is_partial() {
+            for (pattern, val) in self.patterns.iter().zip(args) {
+                if let Some(Err(e)) = pattern
+                    .as_ref()
+                    .map(|pattern| pattern.destructure(val, &mut Vec::new()))
+                {
+                    return e;
                 }
-                _ => code.push(tok.clone()),
             }
         }
+        format!(
+            "No clause of {} matches {}",
+            self.ident,
+            args.iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
 
-        evaluate(&code, ctx)
+    /// Fails when `given` arguments are more than it takes and what it returns can't take the rest. `args` are
+    /// the first of them, if they're known yet.
+    pub fn check_argc(&self, given: usize, args: &[Value]) -> Result<(), String> {
+        if self.variadic || given <= self.argc() || self.may_return_function(args) {
+            return Ok(());
+        }
+        Err(format!(
+            "{} expects {} argument(s), but {} are given",
+            self.ident,
+            self.argc(),
+            given
+        ))
+    }
+
+    /// Whether a call with `args` can return a function. Without a signature it might.
+    fn may_return_function(&self, args: &[Value]) -> bool {
+        match &self.sig {
+            Some(Type::Fn(params, ret)) => match &**ret {
+                Type::Fn(..) => true,
+                // Returns one of its arguments, like `println`
+                Type::Var(_) => match params.iter().position(|param| param == &**ret) {
+                    Some(idx) => {
+                        !matches!(args.get(idx), Some(val) if !matches!(val, Value::Func(_)))
+                    }
+                    None => true,
+                },
+                _ => false,
+            },
+            _ => true,
+        }
+    }
+
+    /// Whether some arguments don't match this clause, so another clause can follow it
+    pub fn is_partial(&self) -> bool {
+        self.guard.is_some() || self.patterns.iter().flatten().any(Pattern::is_refutable)
+    }
+
+    /// The clause a further declaration of the function follows
+    pub fn last_clause(&self) -> &Function {
+        self.clauses.last().unwrap_or(self)
     }
 
     pub fn argc(&self) -> usize {
         self.args.len()
     }
+
+    /// The names the body can refer to, each argument or the names it's taken apart into
+    pub fn names(&self) -> Vec<Ident> {
+        self.args
+            .iter()
+            .zip(&self.patterns)
+            .flat_map(|(arg, pattern)| match pattern {
+                Some(pattern) => pattern.idents().into_iter().cloned().collect(),
+                None => vec![arg.clone()],
+            })
+            .collect()
+    }
+
+    /// How many arguments are needed before the function is called, the ones without defaults
+    pub fn required(&self) -> usize {
+        let fixed = self.args.len() - self.variadic as usize;
+        self.defaults[..fixed]
+            .iter()
+            .position(Option::is_some)
+            .unwrap_or(fixed)
+    }
+
+    /// Matches the arguments of a call to `args`, filling in defaults and collecting the extra arguments of a
+    /// variadic function into a list. Arguments left over once every argument has a value are returned separately.
+    pub fn bind(
+        &self,
+        mut positional: Vec<Value>,
+        named: Vec<(Ident, Value)>,
+        ctx: &Context,
+    ) -> Result<(Vec<Value>, Vec<Value>), String> {
+        let fixed = self.args.len() - self.variadic as usize;
+        let extra = positional.split_off(positional.len().min(fixed));
+        let mut slots = positional.into_iter().map(Some).collect::<Vec<_>>();
+        slots.resize(fixed, None);
+        for (ident, val) in named {
+            match self.args[..fixed].iter().position(|arg| *arg == ident) {
+                Some(idx) if slots[idx].is_some() => {
+                    return Err(format!(
+                        "Argument {} of {} was given twice",
+                        ident, self.ident
+                    ))
+                }
+                Some(idx) => slots[idx] = Some(val),
+                None => return Err(format!("{} has no argument named {}", self.ident, ident)),
+            }
+        }
+
+        // Defaults can refer to the arguments before them
+        let mut bound = Vec::new();
+        for ((arg, slot), default) in self.args.iter().zip(slots).zip(&self.defaults) {
+            let val = match (slot, default) {
+                (Some(val), _) => val,
+                (None, Some(default)) => eval(default, ctx, bound.clone())?,
+                (None, None) => return Err(format!("{} is missing argument {}", self.ident, arg)),
+            };
+            bound.push((arg.clone(), val));
+        }
+        let mut args = bound.into_iter().map(|(_, val)| val).collect::<Vec<_>>();
+        if self.variadic {
+            args.push(Value::List(extra));
+            Ok((args, Vec::new()))
+        } else {
+            Ok((args, extra))
+        }
+    }
 }
 impl Display for Function {
     fn fmt(&self, f: &mut Formatter) -> fmt_Result {
-        write!(
-            f,
-            "function {} {} = {}",
-            self.ident,
-            self.args
-                .iter()
-                .map(|i| i.internal_cloned())
-                .collect::<Vec<String>>()
-                .join(" "),
-            self.code
-                .iter()
-                .map(|tok| format!("{}", tok))
-                .collect::<Vec<String>>()
-                .join(" ")
-        )
+        let code = &self.body;
+        // `{ }` bodies follow the signature directly
+        let assign = if let Expr::Block(_) = **code {
+            " "
+        } else {
+            " = "
+        };
+
+        write!(f, "function {}", self.ident)?;
+        for (idx, ((arg, ann), default)) in self
+            .args
+            .iter()
+            .zip(&self.annotations)
+            .zip(&self.defaults)
+            .enumerate()
+        {
+            if self.variadic && idx + 1 == self.args.len() {
+                write!(f, " ...")?;
+            } else {
+                write!(f, " ")?;
+            }
+            match (ann, default) {
+                (Some(ty), Some(default)) => write!(f, "({}: {} = {})", arg, ty, default)?,
+                (Some(ty), None) => write!(f, "({}: {})", arg, ty)?,
+                (None, Some(default)) => write!(f, "({} = {})", arg, default)?,
+                (None, None) => write!(f, "{}", arg)?,
+            }
+        }
+        if let Some(guard) = &self.guard {
+            write!(f, " | {}", guard)?;
+        }
+        match &self.ret {
+            Some(ty) => write!(f, ": {}{}{}", ty, assign, code)?,
+            None => write!(f, "{}{}", assign, code)?,
+        }
+        // Each further clause is a declaration of its own
+        for clause in &self.clauses {
+            write!(f, "\n{}", clause)?;
+        }
+        // The inferred signature, in a comment so the declaration still reads back as written
+        match &self.sig {
+            Some(sig) => write!(f, "  # {}", sig),
+            None => Ok(()),
+        }
     }
 }
 
@@ -143,13 +378,19 @@ fn test_function_new() {
             Ident::new("a".to_owned()).unwrap(),
             Ident::new("b".to_owned()).unwrap(),
         ],
-        code: vec![
-            Token::new("2".to_owned()).unwrap(),
-            Token::new("*".to_owned()).unwrap(),
-            Token::new("a".to_owned()).unwrap(),
-            Token::new("+".to_owned()).unwrap(),
-            Token::new("b".to_owned()).unwrap(),
-        ],
+        body: Rc::new(
+            crate::tree::parse_expression(&tokenize("2 * a + b".to_owned()).unwrap()).unwrap(),
+        ),
+        annotations: vec![None, None],
+        defaults: vec![None, None],
+        patterns: vec![None, None],
+        guard: None,
+        clauses: Vec::new(),
+        variadic: false,
+        ret: None,
+        sig: None,
+        file: None,
+        lambda: false,
     };
 
     assert_eq!(
@@ -157,3 +398,94 @@ fn test_function_new() {
         expected
     );
 }
+
+#[test]
+fn test_function_annotations() {
+    use crate::token::tokenize;
+    let func = Function::new(
+        &tokenize("function area (w: num) (h: int): num = w * h".to_owned()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(func.args.len(), 2);
+    assert_eq!(func.annotations, vec![Some(Type::Num), Some(Type::Int)]);
+    assert_eq!(func.ret, Some(Type::Num));
+    assert_eq!(
+        format!("{}", func),
+        "function area (w: num) (h: int): num = w * h"
+    );
+
+    assert!(Function::new(&tokenize("function f (a: text) = a".to_owned()).unwrap()).is_err());
+    assert!(Function::new(&tokenize("function f (a num) = a".to_owned()).unwrap()).is_err());
+    assert!(Function::new(&tokenize("function f a: num a = a".to_owned()).unwrap()).is_err());
+
+    let mut ctx = Context::new();
+    let half = ctx
+        .new_func(&tokenize("function half (n: int): int = n / 2".to_owned()).unwrap())
+        .unwrap();
+    assert_eq!(
+        half.call(&[Value::Num(4.0)], &[], &ctx),
+        Ok(Value::Num(2.0))
+    );
+    assert_eq!(
+        half.call(&[Value::Num(2.5)], &[], &ctx),
+        Err("Argument n of half expects int, got 2.5".to_owned())
+    );
+    assert_eq!(
+        half.call(&[Value::Num(3.0)], &[], &ctx),
+        Err("half is declared to return int, but returned 1.5".to_owned())
+    );
+}
+
+#[test]
+fn test_arguments() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    engine
+        .run("function digits a (b = 2) (c = a + b) = a * 100 + b * 10 + c")
+        .unwrap();
+    engine.run("function avg ...xs = sum xs / len xs").unwrap();
+    engine.run("var d = digits").unwrap();
+    let eval = |s: &str| engine.eval(s).and_then(|val| val.num());
+
+    // Missing arguments take their defaults, which can use the arguments before them
+    assert_eq!(eval("digits 1"), Ok(123.0));
+    assert_eq!(eval("digits 1 5"), Ok(156.0));
+    assert_eq!(eval("d 1 5 0"), Ok(150.0));
+    assert_eq!(eval("digits(1, c: 0)"), Ok(120.0));
+    assert_eq!(eval("digits(b: 3, a: 1)"), Ok(134.0));
+    assert_eq!(eval("digits(1) + 1"), Ok(124.0));
+    assert_eq!(
+        eval("digits(1, d: 2)"),
+        Err("digits has no argument named d".to_owned())
+    );
+    assert_eq!(
+        eval("digits(1, a: 2)"),
+        Err("Argument a of digits was given twice".to_owned())
+    );
+    assert_eq!(
+        eval("digits(b: 1)"),
+        Err("digits is missing argument a".to_owned())
+    );
+    assert!(eval("digits(a: 1, 2)").is_err());
+
+    // Variadic arguments are collected into a list
+    assert_eq!(eval("avg 1 2 3 6"), Ok(3.0));
+    assert_eq!(eval("avg(2, 4)"), Ok(3.0));
+    assert!(eval("avg()").unwrap().is_nan());
+    assert_eq!(eval("min 3 1 2"), Ok(1.0));
+    assert_eq!(eval("max(4, 9, 2)"), Ok(9.0));
+    assert_eq!(eval("max 2 (3)"), Ok(3.0));
+    assert_eq!(
+        engine.eval("min()"),
+        Err("Expected at least 1 argument, got 0".to_owned())
+    );
+
+    for src in [
+        "function f (a = 1) b = a",
+        "function f ...xs y = y",
+        "function f ...(xs = 1) = xs",
+        "function f (a) = a",
+    ] {
+        assert!(engine.run(src).is_err(), "{}", src);
+    }
+}
@@ -1,23 +1,39 @@
 use {
     crate::{
+        debugger::{Debugger, Event, Frame},
+        engine::{Engine, Outcome},
+        eval::eval,
         function::Function,
-        rpn::{rpn_eval, rpn_gen},
-        token::{tokenize, Ident, Keyword, Operator, Token},
+        output::{Output, Stdout},
+        stdlib,
+        token::{Ident, Span, Token},
+        trace::Trace,
+        tree::{parse, parse_expression, Expr, Pattern, Stmt},
+        types::{infer_expression, infer_function, Type},
+        value::{Closure, Value},
     },
     std::{
+        cell::{Cell, RefCell},
         collections::HashMap,
+        fmt::{self, Display, Formatter},
         io::{stdin, stdout, Write},
+        path::Path,
+        rc::Rc,
     },
 };
 
+/// How many calls of a traceback the REPL shows, innermost first
+const TRACEBACK_LINES: usize = 10;
+
 pub struct Interpreter {
-    context: Context,
+    engine: Engine,
 }
 impl Interpreter {
     pub fn new() -> Self {
-        Interpreter {
-            context: Context::new(),
-        }
+        let mut engine = Engine::new();
+        // `main` runs the REPL on a stack of `STACK_SIZE`
+        engine.set_stack_limit(STACK_SIZE / 2);
+        Interpreter { engine }
     }
     pub fn begin(mut self) -> Result<(), usize> {
         let stdin = stdin();
@@ -28,378 +44,701 @@ impl Interpreter {
 
             let mut userin = String::new();
 
-            stdin.read_line(&mut userin).unwrap();
+            if stdin.read_line(&mut userin).unwrap() == 0 {
+                return Ok(());
+            }
+
+            if let Some(command) = userin.trim().strip_prefix(':') {
+                if let Err(e) = self.command(command) {
+                    eprintln!("Error: {}", e);
+                }
+                continue;
+            }
 
-            let tokens = match tokenize(userin.clone()) {
-                Ok(toks) => toks,
+            // Keep reading until every block is closed
+            while self.engine.incomplete(&userin) {
+                print!(".. ");
+                stdout().flush().unwrap();
+                if stdin.read_line(&mut userin).unwrap() == 0 {
+                    break;
+                }
+            }
+
+            match self.engine.run(&userin) {
+                Ok(Outcome::Function(func)) => println!("{}", func),
+                Ok(Outcome::Variable(ident, val)) => println!("{} = {}", ident, val),
+                Ok(Outcome::Variables(vars)) => {
+                    for (ident, val) in vars {
+                        println!("{} = {}", ident, val);
+                    }
+                }
+                Ok(Outcome::Module(ns)) => println!("Imported {}", ns),
+                Ok(Outcome::Value(val)) => println!("{}", val),
+                Ok(Outcome::Empty) => (),
                 Err(e) => {
                     eprintln!("Error: {}", e);
-                    continue;
+                    let traceback = self.engine.traceback();
+                    for frame in traceback.iter().rev().take(TRACEBACK_LINES) {
+                        eprintln!("  in {}", frame);
+                    }
+                    if traceback.len() > TRACEBACK_LINES {
+                        eprintln!("  ... and {} more", traceback.len() - TRACEBACK_LINES);
+                    }
                 }
-            };
-
-            if let Some(Token::Keyword(kw)) = tokens.get(0) {
-                match kw {
-                    Keyword::Function => {
-                        match self.context.new_func(&tokens) {
-                            Ok(func) => println!("{}", func),
-                            Err(e) => eprintln!("Error: {}", e),
-                        };
-                        continue;
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str) -> Result<(), String> {
+        let (name, rest) = match command.find(char::is_whitespace) {
+            Some(idx) => (&command[..idx], command[idx..].trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "type" | "t" => {
+                let expr = self.engine.parse_expression(rest)?;
+                // A bare function name shows its signature rather than calling it
+                if let Expr::Var(ident, _) = &expr {
+                    if let Some(Function { sig: Some(sig), .. }) = self
+                        .engine
+                        .context
+                        .functions
+                        .get(&self.engine.context.resolve(ident))
+                    {
+                        println!("{} : {}", ident, sig);
+                        return Ok(());
                     }
-                    Keyword::Variable => match self.context.new_var(&tokens) {
-                        Ok((ident, val)) => println!("{} = {}", ident, val),
-                        Err(e) => eprintln!("Error: {}", e),
-                    },
                 }
-            } else {
-                match evaluate(&tokens, &self.context) {
-                    Ok(val) => println!("{}", val),
-                    Err(e) => eprintln!("Error: {:?}", e),
-                };
+                println!(
+                    "{} : {}",
+                    rest,
+                    infer_expression(&expr, &self.engine.context)?
+                );
+                Ok(())
+            }
+            "break" | "b" => {
+                if rest.is_empty() {
+                    let mut names = self
+                        .engine
+                        .context
+                        .debugger
+                        .borrow()
+                        .breakpoints
+                        .iter()
+                        .map(|ident| ident.internal_cloned())
+                        .collect::<Vec<String>>();
+                    names.sort();
+                    println!("Breakpoints: {}", names.join(", "));
+                    return Ok(());
+                }
+                let ident = Ident::new(rest.to_owned())
+                    .map(|ident| self.engine.context.resolve(&ident))
+                    .filter(|ident| self.engine.context.functions.contains_key(ident))
+                    .ok_or_else(|| format!("Unknown function {}", rest))?;
+                self.engine
+                    .context
+                    .debugger
+                    .borrow_mut()
+                    .breakpoints
+                    .insert(ident);
+                Ok(())
+            }
+            "delete" | "d" => {
+                let ident = Ident::new(rest.to_owned())
+                    .map(|ident| self.engine.context.resolve(&ident))
+                    .ok_or_else(|| format!("Unknown function {}", rest))?;
+                if self
+                    .engine
+                    .context
+                    .debugger
+                    .borrow_mut()
+                    .breakpoints
+                    .remove(&ident)
+                {
+                    Ok(())
+                } else {
+                    Err(format!("No breakpoint on {}", rest))
+                }
             }
+            "step" | "s" => {
+                let expr = self.engine.parse_expression(rest)?;
+                self.engine.context.instructions.set(0);
+                self.engine.context.debugger.borrow_mut().stepping = true;
+                let val = eval(&expr, &self.engine.context, Vec::new());
+                self.engine.context.debugger.borrow_mut().stepping = false;
+                println!("{}", val?);
+                Ok(())
+            }
+            "explain" | "e" => {
+                print!("{}", self.engine.explain(rest)?);
+                Ok(())
+            }
+            "calc" => {
+                match rest {
+                    "on" => self.engine.set_calculator(true),
+                    "off" => self.engine.set_calculator(false),
+                    "" => (),
+                    _ => return Err(format!("Expected :calc on or :calc off, got {}", rest)),
+                }
+                println!(
+                    "Calculator mode is {}",
+                    if self.engine.calculator() {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                );
+                Ok(())
+            }
+            "limit" => {
+                match rest {
+                    "off" => self.engine.set_instruction_limit(None),
+                    "" => (),
+                    _ => self
+                        .engine
+                        .set_instruction_limit(Some(rest.parse().map_err(|_| {
+                            format!("Expected :limit followed by a number or off, got {}", rest)
+                        })?)),
+                }
+                match self.engine.instruction_limit() {
+                    Some(limit) => println!("Instruction limit is {}", limit),
+                    None => println!("There is no instruction limit"),
+                }
+                Ok(())
+            }
+            _ => Err(format!("Unknown command :{}", name)),
         }
     }
 }
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-pub fn evaluate(tokens: &Vec<Token>, ctx: &Context) -> Result<f64, String> {
-    let rpn = rpn_gen(tokens, ctx)?;
-    rpn_eval(&rpn, ctx)
+pub fn evaluate(tokens: &[Token], ctx: &Context) -> Result<Value, String> {
+    eval(&parse_expression(tokens)?, ctx, Vec::new())
 }
 
+/// What `catch` tells about an error besides its message, as the `kind` field of the caught error
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ErrorKind {
+    /// Raised by the script with `error`
+    Raised,
+    /// A failed `assert`
+    Assertion,
+    /// Calls nesting deeper than `Context::max_depth`, usually recursion that never stops
+    Overflow,
+    /// Anything else going wrong while evaluating, like an argument of the wrong type
+    Runtime,
+    /// Reaching the instruction limit or being aborted by the debugger, which `catch` doesn't stop
+    Fatal,
+}
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Raised => write!(f, "error"),
+            Self::Assertion => write!(f, "assert"),
+            Self::Overflow => write!(f, "overflow"),
+            Self::Runtime => write!(f, "runtime"),
+            Self::Fatal => write!(f, "fatal"),
+        }
+    }
+}
+
+/// Why a script can't change a global
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ReadOnly {
+    /// Declared with `const`
+    Const,
+    /// Provided by the program embedding minilang, see `Engine::set_input`
+    Input,
+}
+impl Display for ReadOnly {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Const => write!(f, "a constant"),
+            Self::Input => write!(f, "an input"),
+        }
+    }
+}
+
+/// Deep enough for recursion over a list of several hundred items, given the stack for it
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+/// Half the 2 MiB stack of a spawned thread, the rest is left to the program embedding minilang. A call takes a
+/// few kilobytes of stack, or tens of kilobytes in a debug build.
+pub const DEFAULT_STACK_LIMIT: usize = 1 << 20;
+/// The stack the REPL runs on, so recursion can go `DEFAULT_MAX_DEPTH` calls deep
+pub const STACK_SIZE: usize = 256 << 20;
+
 pub struct Context {
     pub functions: HashMap<Ident, Function>,
-    pub variables: HashMap<Ident, f64>,
+    /// Global variables, assignment statements can update them during evaluation
+    pub variables: RefCell<HashMap<Ident, Value>>,
+    /// Declared types of variables, enforced whenever they're bound
+    pub annotations: HashMap<Ident, Type>,
+    /// Globals scripts can read but not assign to or redeclare
+    pub readonly: HashMap<Ident, ReadOnly>,
+    /// Namespaces whose names can be used unqualified, see `resolve`
+    pub uses: Vec<Ident>,
+    pub debugger: RefCell<Debugger>,
+    /// Whether evaluation records a trace of every evaluation into `last_trace`
+    pub tracing: Cell<bool>,
+    pub last_trace: RefCell<Option<Trace>>,
+    /// User functions currently being called, innermost last
+    pub frames: RefCell<Vec<Frame>>,
+    /// Calls of user functions in progress, including any still evaluating guards to choose a clause
+    pub depth: Cell<usize>,
+    /// How deep `depth` can get, see `Engine::set_max_depth`
+    pub max_depth: usize,
+    /// Where the stack was when the outermost call started, see `enter`
+    pub stack_base: Cell<usize>,
+    /// How many bytes of stack nested calls can take, see `Engine::set_stack_limit`
+    pub stack_limit: usize,
+    /// Where the next user function is called from, taken by the frame it pushes
+    pub call_site: Cell<Span>,
+    /// The file of the code being evaluated, which spans are in. `None` for code given to the REPL or
+    /// `Engine::run`.
+    pub source: RefCell<Option<Rc<Path>>>,
+    /// The calls the error being returned unwound through, innermost first
+    pub traceback: RefCell<Vec<Frame>>,
+    /// Whether this is an imported file being run or checked, see `protect`
+    pub module: bool,
+    /// Calculator mode, see `Engine::set_calculator`
+    pub calculator: bool,
+    /// Evaluation fails once this many operators, calls and statements have run, see `Engine::set_instruction_limit`
+    pub limit: Option<u64>,
+    /// Counted towards `limit`, reset before every evaluation
+    pub instructions: Cell<u64>,
+    /// Where `print` and `println` write, see `Engine::set_output`
+    pub output: RefCell<Box<dyn Output>>,
+    /// The kind of the error being returned, when it isn't `ErrorKind::Runtime`
+    pub raised: Cell<Option<ErrorKind>>,
 }
 impl Context {
     pub fn new() -> Self {
-        let mut functions = HashMap::new();
-        functions.insert(
-            Ident::new("sin".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("sin".to_owned()).unwrap(),
-                args: vec![Ident::new("a".to_owned()).unwrap()],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("cos".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("cos".to_owned()).unwrap(),
-                args: vec![Ident::new("a".to_owned()).unwrap()],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("tan".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("tan".to_owned()).unwrap(),
-                args: vec![Ident::new("a".to_owned()).unwrap()],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("asin".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("asin".to_owned()).unwrap(),
-                args: vec![Ident::new("a".to_owned()).unwrap()],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("acos".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("acos".to_owned()).unwrap(),
-                args: vec![Ident::new("a".to_owned()).unwrap()],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("atan".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("atan".to_owned()).unwrap(),
-                args: vec![Ident::new("a".to_owned()).unwrap()],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("min".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("min".to_owned()).unwrap(),
-                args: vec![
-                    Ident::new("a".to_owned()).unwrap(),
-                    Ident::new("b".to_owned()).unwrap(),
-                ],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-        functions.insert(
-            Ident::new("max".to_owned()).unwrap(),
-            Function {
-                ident: Ident::new("max".to_owned()).unwrap(),
-                args: vec![
-                    Ident::new("a".to_owned()).unwrap(),
-                    Ident::new("b".to_owned()).unwrap(),
-                ],
-                code: vec![Token::Identifier(Ident::new("a".to_owned()).unwrap())],
-            },
-        );
-
         Context {
-            functions,
-            variables: HashMap::new(),
+            functions: stdlib::functions(),
+            variables: RefCell::new(stdlib::variables()),
+            annotations: HashMap::new(),
+            readonly: HashMap::new(),
+            uses: stdlib::PRELUDE
+                .iter()
+                .map(|ns| Ident::new((*ns).to_owned()).unwrap())
+                .collect(),
+            debugger: RefCell::new(Debugger::new()),
+            tracing: Cell::new(false),
+            last_trace: RefCell::new(None),
+            frames: RefCell::new(Vec::new()),
+            depth: Cell::new(0),
+            max_depth: DEFAULT_MAX_DEPTH,
+            stack_base: Cell::new(0),
+            stack_limit: DEFAULT_STACK_LIMIT,
+            call_site: Cell::new(Span::default()),
+            source: RefCell::new(None),
+            traceback: RefCell::new(Vec::new()),
+            module: false,
+            calculator: false,
+            limit: None,
+            instructions: Cell::new(0),
+            output: RefCell::new(Box::new(Stdout)),
+            raised: Cell::new(None),
         }
     }
 
-    pub fn new_var(&mut self, tokens: &Vec<Token>) -> Result<(Ident, f64), String> {
-        if let Some(Token::Keyword(Keyword::Variable)) = tokens.first() {
-            if let Some(Token::Identifier(ident)) = tokens.get(1) {
-                if tokens.get(2) != Some(&Token::Operator(Operator::Assign)) {
-                    Err(format!(
-                        "Unexpected token before assignment operator in varible assignment: {:?}",
-                        tokens.get(2)
-                    ))
-                } else {
-                    let code = tokens.clone().drain(3..).collect::<Vec<Token>>();
-                    let val = evaluate(&code, &self)?;
-                    self.variables.insert(ident.clone(), val);
-                    Ok((ident.clone(), val))
-                }
-            } else {
-                Err("`var` keyword not followed by an identifier".to_owned())
-            }
+    fn declared(&self, ident: &Ident) -> bool {
+        self.functions.contains_key(ident) || self.variables.borrow().contains_key(ident)
+    }
+
+    /// What a name refers to: itself if it's declared, otherwise the same name inside the most recently used
+    /// namespace that has it, so `sin` is `math.sin`
+    pub fn resolve(&self, ident: &Ident) -> Ident {
+        if self.declared(ident) {
+            return ident.clone();
+        }
+        self.uses
+            .iter()
+            .rev()
+            .map(|ns| ident.qualified(ns))
+            .find(|qualified| self.declared(qualified))
+            .unwrap_or_else(|| ident.clone())
+    }
+
+    /// Fails if declaring `ident` would hide a builtin, which needs `override`. Declarations of an imported
+    /// file end up inside its namespace, where they can't.
+    pub fn protect(&self, ident: &Ident) -> Result<(), String> {
+        if !self.module && stdlib::is_builtin(&self.resolve(ident)) {
+            Err(format!(
+                "{} is a builtin, use override to replace it",
+                ident
+            ))
         } else {
-            unreachable!()
+            Ok(())
         }
     }
 
-    pub fn new_func(&mut self, tokens: &Vec<Token>) -> Result<Function, String> {
-        let func = Function::new(tokens)?;
-        self.functions.insert(func.ident.clone(), func.clone());
-        Ok(func)
+    /// Makes the names inside `ns` usable without `ns.`, taking precedence over namespaces used before
+    pub fn use_namespace(&mut self, ns: Ident) -> Result<(), String> {
+        let prefix = format!("{}.", ns);
+        let known = self
+            .functions
+            .keys()
+            .chain(self.variables.borrow().keys())
+            .any(|ident| ident.internal_cloned().starts_with(&prefix));
+        if !known {
+            return Err(format!("Unknown namespace {}", ns));
+        }
+        self.uses.retain(|used| *used != ns);
+        self.uses.push(ns);
+        Ok(())
     }
 
-    pub fn call_function(&self, ident: Ident, args: &Vec<Token>) -> Result<f64, String> {
-        if let Some(func) = self.functions.get(&ident) {
-            match ident.internal_cloned().as_str() {
-                "sin" => {
-                    if args.len() != 1 {
-                        Err(format!("Expected 1 argument, got {}", args.len()))
-                    } else {
-                        let a = args.get(0).unwrap();
-                        if let Token::Value(v) = a {
-                            Ok(v.sin())
-                        } else {
-                            Err(format!("Expected Value, got {}", a))
-                        }
-                    }
-                }
-                "cos" => {
-                    if args.len() != 1 {
-                        Err(format!("Expected 1 argument, got {}", args.len()))
-                    } else {
-                        let a = args.get(0).unwrap();
-                        if let Token::Value(v) = a {
-                            Ok(v.sin())
-                        } else {
-                            Err(format!("Expected Value, got {}", a))
-                        }
-                    }
-                }
-                "tan" => {
-                    if args.len() != 1 {
-                        Err(format!("Expected 1 argument, got {}", args.len()))
-                    } else {
-                        let a = args.get(0).unwrap();
-                        if let Token::Value(v) = a {
-                            Ok(v.sin())
-                        } else {
-                            Err(format!("Expected Value, got {}", a))
-                        }
-                    }
-                }
-                "asin" => {
-                    if args.len() != 1 {
-                        Err(format!("Expected 1 argument, got {}", args.len()))
-                    } else {
-                        let a = args.get(0).unwrap();
-                        if let Token::Value(v) = a {
-                            Ok(v.sin())
-                        } else {
-                            Err(format!("Expected Value, got {}", a))
-                        }
-                    }
-                }
-                "acos" => {
-                    if args.len() != 1 {
-                        Err(format!("Expected 1 argument, got {}", args.len()))
-                    } else {
-                        let a = args.get(0).unwrap();
-                        if let Token::Value(v) = a {
-                            Ok(v.sin())
-                        } else {
-                            Err(format!("Expected Value, got {}", a))
-                        }
-                    }
-                }
-                "atan" => {
-                    if args.len() != 1 {
-                        Err(format!("Expected 1 argument, got {}", args.len()))
-                    } else {
-                        let a = args.get(0).unwrap();
-                        if let Token::Value(v) = a {
-                            Ok(v.sin())
-                        } else {
-                            Err(format!("Expected Value, got {}", a))
-                        }
-                    }
-                }
-                "min" => {
-                    if args.len() != 2 {
-                        Err(format!("Expected 2 arguments, got {}", args.len()))
-                    } else {
-                        let (a, b) = (args.get(0).unwrap(), args.get(1).unwrap());
-                        if let (Token::Value(va), Token::Value(vb)) = (a, b) {
-                            Ok(va.min(*vb))
-                        } else {
-                            Err(format!("Expected (Value, Value), got ({}, {})", a, b))
-                        }
-                    }
-                }
-                "max" => {
-                    if args.len() != 2 {
-                        Err(format!("Expected 2 arguments, got {}", args.len()))
-                    } else {
-                        let (a, b) = (args.get(0).unwrap(), args.get(1).unwrap());
-                        if let (Token::Value(va), Token::Value(vb)) = (a, b) {
-                            Ok(va.max(*vb))
-                        } else {
-                            Err(format!("Expected (Value, Value), got ({}, {})", a, b))
-                        }
-                    }
+    /// Declares the variables of a `var` or `const` statement, several when it takes apart a tuple
+    pub fn new_var(&mut self, tokens: &[Token]) -> Result<Vec<(Ident, Value)>, String> {
+        match parse(tokens)? {
+            Stmt::Var { ident, ann, expr } => self.bind(ident, ann, &expr).map(|var| vec![var]),
+            Stmt::Const { ident, ann, expr } => {
+                self.constant(ident, ann, &expr).map(|var| vec![var])
+            }
+            Stmt::Destructure { pattern, expr } => self.destructure(&pattern, &expr),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Evaluates `expr` and binds it to `ident`, enforcing the annotation if there is one
+    pub fn bind(
+        &mut self,
+        ident: Ident,
+        ann: Option<Type>,
+        expr: &Expr,
+    ) -> Result<(Ident, Value), String> {
+        self.writable(&ident)?;
+        self.unshadowed(&ident)?;
+        let mut val = eval(expr, self, Vec::new())?;
+        match ann {
+            Some(ty) if !ty.accepts(&val) => {
+                return Err(format!("Variable {} expects {}, got {}", ident, ty, val))
+            }
+            Some(ty) => self.annotations.insert(ident.clone(), ty),
+            None => self.annotations.remove(&ident),
+        };
+
+        // Give lambdas a signature, so `:type` and inference know what they take
+        if let Value::Func(closure) = &mut val {
+            if closure.func.sig.is_none() && closure.applied.is_empty() {
+                if let Ok(sig) = infer_expression(expr, self) {
+                    Rc::make_mut(&mut closure.func).sig = Some(sig);
                 }
-                _ => func.call(args, &self),
             }
+        }
+        self.variables
+            .borrow_mut()
+            .insert(ident.clone(), val.clone());
+        Ok((ident, val))
+    }
+
+    /// Evaluates `expr` and binds the names in `pattern` to its parts
+    pub fn destructure(
+        &mut self,
+        pattern: &Pattern,
+        expr: &Expr,
+    ) -> Result<Vec<(Ident, Value)>, String> {
+        for ident in pattern.idents() {
+            self.writable(ident)?;
+            self.unshadowed(ident)?;
+        }
+        let mut vars = Vec::new();
+        pattern.destructure(&eval(expr, self, Vec::new())?, &mut vars)?;
+        for (ident, val) in &vars {
+            self.annotations.remove(ident);
+            self.variables
+                .borrow_mut()
+                .insert(ident.clone(), val.clone());
+        }
+        Ok(vars)
+    }
+
+    /// Binds `ident` like `bind`, after which it can't be changed
+    pub fn constant(
+        &mut self,
+        ident: Ident,
+        ann: Option<Type>,
+        expr: &Expr,
+    ) -> Result<(Ident, Value), String> {
+        let (ident, val) = self.bind(ident, ann, expr)?;
+        self.readonly.insert(ident.clone(), ReadOnly::Const);
+        Ok((ident, val))
+    }
+
+    /// Fails if `ident` is a constant or an input, which can't be redeclared
+    pub fn writable(&self, ident: &Ident) -> Result<(), String> {
+        match self.readonly.get(ident) {
+            Some(readonly) => Err(format!("{} is {} and can't be redeclared", ident, readonly)),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if `ident` is a function, which would hide a variable with its name
+    pub fn unshadowed(&self, ident: &Ident) -> Result<(), String> {
+        if self.functions.contains_key(ident) {
+            return Err(format!("{} is a function and can't be a variable", ident));
+        }
+        Ok(())
+    }
+
+    pub fn new_func(&mut self, tokens: &[Token]) -> Result<Function, String> {
+        self.declare(Function::new(tokens)?)
+    }
+
+    /// Adds `func` once its signature has been inferred
+    pub fn declare(&mut self, func: Function) -> Result<Function, String> {
+        self.writable(&func.ident)?;
+        let mut func = self.merge_clause(func)?;
+        // Insert before inferring so that recursive references resolve, and restore on failure
+        let prev = self.functions.insert(func.ident.clone(), func.clone());
+        match infer_function(&func, self) {
+            Ok(sig) => {
+                func.sig = Some(sig);
+                self.functions.insert(func.ident.clone(), func.clone());
+                Ok(func)
+            }
+            Err(e) => {
+                match prev {
+                    Some(prev) => self.functions.insert(func.ident.clone(), prev),
+                    None => self.functions.remove(&func.ident),
+                };
+                Err(e)
+            }
+        }
+    }
+
+    /// A function whose last clause doesn't accept every argument, like `function fact 0 = 1`, is continued by
+    /// the next declaration with its name instead of being replaced
+    pub fn merge_clause(&self, func: Function) -> Result<Function, String> {
+        let prev = match self.functions.get(&func.ident) {
+            Some(prev) if prev.last_clause().is_partial() => prev,
+            _ => return Ok(func),
+        };
+        if func.argc() != prev.argc() || func.variadic != prev.variadic {
+            return Err(format!(
+                "Every clause of {} has to take {} argument(s)",
+                func.ident,
+                prev.argc()
+            ));
+        }
+        let mut merged = prev.clone();
+        merged.clauses.push(func);
+        Ok(merged)
+    }
+
+    /// Counts one instruction towards the limit
+    pub fn tick(&self) -> Result<(), String> {
+        let count = self.instructions.get() + 1;
+        self.instructions.set(count);
+        match self.limit {
+            Some(limit) if count > limit => Err(self.raise(
+                ErrorKind::Fatal,
+                format!("Stopped after reaching the limit of {} instructions", limit),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a call of `ident` towards `depth`, failing if it would nest deeper than `max_depth` or the calls
+    /// already take more than `stack_limit` of the stack. Every successful `enter` has to be followed by a
+    /// `leave`.
+    pub fn enter(&self, ident: &Ident) -> Result<(), String> {
+        // Any local shows where the stack is
+        let marker = 0u8;
+        let here = &marker as *const u8 as usize;
+        if self.depth.get() == 0 {
+            self.stack_base.set(here);
+        }
+        let message = if self.depth.get() >= self.max_depth {
+            format!(
+                "Calling {} would nest more than {} calls deep",
+                ident, self.max_depth
+            )
+        } else if self.stack_base.get().abs_diff(here) > self.stack_limit {
+            format!(
+                "Calling {} would run out of stack, {} calls deep",
+                ident,
+                self.depth.get()
+            )
         } else {
-            Err(format!("Unknown function {}", ident))
+            self.depth.set(self.depth.get() + 1);
+            return Ok(());
+        };
+        Err(self.raise(ErrorKind::Overflow, message))
+    }
+
+    pub fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// Hands an evaluation event to the debugger, which may pause or abort
+    pub fn debug(&self, event: Event, stack: &[Value]) -> Result<(), String> {
+        if !self.debugger.borrow().pauses(&event) {
+            return Ok(());
+        }
+        let mut frames = self.frames.borrow().clone();
+        // Paused on entering a user function, whose arguments are already bound
+        if let Event::Call { func, args } = &event {
+            if !stdlib::is_builtin(&func.ident) {
+                frames.push(Frame {
+                    ident: func.ident.clone(),
+                    bindings: func
+                        .args
+                        .iter()
+                        .cloned()
+                        .zip(args.iter().cloned())
+                        .collect(),
+                    span: self.call_site.get(),
+                    source: self.source.borrow().clone(),
+                });
+            }
+        }
+        self.debugger
+            .borrow_mut()
+            .hook(event, stack, &frames)
+            .map_err(|e| self.raise(ErrorKind::Fatal, e))
+    }
+
+    /// Records the kind of the error with `message` that's about to be returned, for `catch`
+    pub fn raise(&self, kind: ErrorKind, message: String) -> String {
+        self.raised.set(Some(kind));
+        message
+    }
+
+    /// Calls a function value with exactly as many arguments as it still needs. It's the function the value was
+    /// made from, even if one with its name has been declared since.
+    pub fn call(&self, closure: &Closure, args: &[Value]) -> Result<Value, String> {
+        let func = &closure.func;
+        stdlib::call(&func.ident, args, self)
+            .unwrap_or_else(|| func.call(args, &closure.captured, self))
+    }
+
+    pub fn call_function(&self, ident: Ident, args: &[Value]) -> Result<Value, String> {
+        let ident = self.resolve(&ident);
+        match self.functions.get(&ident) {
+            Some(func) => {
+                stdlib::call(&ident, args, self).unwrap_or_else(|| func.call(args, &[], self))
+            }
+            None => Err(format!("Unknown function {}", ident)),
         }
     }
 }
 
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[test]
 fn test_builtin_call() {
     let ctx = Context::new();
 
     assert!(ctx
-        .call_function(
-            Ident::new("sin".to_owned()).unwrap(),
-            &vec![Token::Value(1.0)]
-        )
+        .call_function(Ident::new("sin".to_owned()).unwrap(), &[Value::Num(1.0)])
         .is_ok());
     assert!(ctx
-        .call_function(Ident::new("sin".to_owned()).unwrap(), &vec![])
+        .call_function(Ident::new("sin".to_owned()).unwrap(), &[])
         .is_err());
 
     assert!(ctx
-        .call_function(
-            Ident::new("cos".to_owned()).unwrap(),
-            &vec![Token::Value(1.0)]
-        )
+        .call_function(Ident::new("cos".to_owned()).unwrap(), &[Value::Num(1.0)])
         .is_ok());
     assert!(ctx
-        .call_function(Ident::new("cos".to_owned()).unwrap(), &vec![])
+        .call_function(Ident::new("cos".to_owned()).unwrap(), &[])
         .is_err());
 
     assert!(ctx
-        .call_function(
-            Ident::new("tan".to_owned()).unwrap(),
-            &vec![Token::Value(1.0)]
-        )
+        .call_function(Ident::new("tan".to_owned()).unwrap(), &[Value::Num(1.0)])
         .is_ok());
     assert!(ctx
-        .call_function(Ident::new("tan".to_owned()).unwrap(), &vec![])
+        .call_function(Ident::new("tan".to_owned()).unwrap(), &[])
         .is_err());
 
     assert!(ctx
-        .call_function(
-            Ident::new("asin".to_owned()).unwrap(),
-            &vec![Token::Value(1.0)]
-        )
+        .call_function(Ident::new("asin".to_owned()).unwrap(), &[Value::Num(1.0)])
         .is_ok());
     assert!(ctx
-        .call_function(Ident::new("asin".to_owned()).unwrap(), &vec![])
+        .call_function(Ident::new("asin".to_owned()).unwrap(), &[])
         .is_err());
 
     assert!(ctx
-        .call_function(
-            Ident::new("acos".to_owned()).unwrap(),
-            &vec![Token::Value(1.0)]
-        )
+        .call_function(Ident::new("acos".to_owned()).unwrap(), &[Value::Num(1.0)])
         .is_ok());
     assert!(ctx
-        .call_function(Ident::new("acos".to_owned()).unwrap(), &vec![])
+        .call_function(Ident::new("acos".to_owned()).unwrap(), &[])
         .is_err());
 
     assert!(ctx
-        .call_function(
-            Ident::new("atan".to_owned()).unwrap(),
-            &vec![Token::Value(1.0)]
-        )
+        .call_function(Ident::new("atan".to_owned()).unwrap(), &[Value::Num(1.0)])
         .is_ok());
     assert!(ctx
-        .call_function(Ident::new("atan".to_owned()).unwrap(), &vec![])
+        .call_function(Ident::new("atan".to_owned()).unwrap(), &[])
         .is_err());
 
-    assert!(ctx
-        .call_function(
+    // min and max take any number of arguments, collected into a list
+    let list = |items: &[f64]| [Value::List(items.iter().cloned().map(Value::Num).collect())];
+    assert_eq!(
+        ctx.call_function(
             Ident::new("min".to_owned()).unwrap(),
-            &vec![Token::Value(10.0), Token::Value(15.0)]
-        )
-        .is_ok());
+            &list(&[10.0, 15.0, 5.0])
+        ),
+        Ok(Value::Num(5.0))
+    );
+    assert_eq!(
+        ctx.call_function(Ident::new("max".to_owned()).unwrap(), &list(&[10.0])),
+        Ok(Value::Num(10.0))
+    );
     assert!(ctx
-        .call_function(
-            Ident::new("min".to_owned()).unwrap(),
-            &vec![Token::Value(10.0)]
-        )
+        .call_function(Ident::new("max".to_owned()).unwrap(), &list(&[]))
         .is_err());
-
-    assert!(ctx
-        .call_function(
-            Ident::new("max".to_owned()).unwrap(),
-            &vec![Token::Value(10.0), Token::Value(15.0)]
-        )
-        .is_ok());
     assert!(ctx
         .call_function(
-            Ident::new("max".to_owned()).unwrap(),
-            &vec![Token::Value(10.0)]
+            Ident::new("min".to_owned()).unwrap(),
+            &[Value::Num(10.0), Value::Num(15.0)]
         )
         .is_err());
 
     assert_eq!(
         ctx.call_function(
-            Ident::new("min".to_owned()).unwrap(),
-            &vec![Token::Value(10.0), Token::Value(15.0)]
-        )
-        .unwrap(),
-        10.0
+            Ident::new("sum".to_owned()).unwrap(),
+            &list(&[1.0, 2.0, 3.0])
+        ),
+        Ok(Value::Num(6.0))
     );
     assert_eq!(
-        ctx.call_function(
-            Ident::new("max".to_owned()).unwrap(),
-            &vec![Token::Value(10.0), Token::Value(15.0)]
-        )
-        .unwrap(),
-        15.0
+        ctx.call_function(Ident::new("len".to_owned()).unwrap(), &list(&[1.0, 2.0])),
+        Ok(Value::Num(2.0))
+    );
+    assert_eq!(
+        ctx.call_function(Ident::new("println".to_owned()).unwrap(), &[]),
+        Err("Expected 1 argument, got 0".to_owned())
+    );
+    assert_eq!(
+        ctx.call_function(Ident::new("error".to_owned()).unwrap(), &[]),
+        Err("Expected 1 argument, got 0".to_owned())
+    );
+    assert_eq!(
+        ctx.call_function(Ident::new("assert".to_owned()).unwrap(), &[Value::Num(1.0)]),
+        Err("Expected 2 arguments, got 1".to_owned())
     );
 }
 
 #[test]
 fn test_greater_eval() {
+    use crate::token::tokenize;
+
     let mut ctx = Context::new();
 
     ctx.variables
-        .insert(Ident::new("a".to_owned()).unwrap(), 10.0);
+        .borrow_mut()
+        .insert(Ident::new("a".to_owned()).unwrap(), Value::Num(10.0));
 
     let with_vars = evaluate(&tokenize("a + 2".to_owned()).unwrap(), &ctx);
     eprintln!("With vars: {:?}", with_vars);
@@ -410,14 +749,62 @@ fn test_greater_eval() {
             Ident::new("a".to_owned()).unwrap(),
             Ident::new("b".to_owned()).unwrap(),
         ],
-        code: vec![
-            Token::new("a".to_owned()).unwrap(),
-            Token::new("+".to_owned()).unwrap(),
-            Token::new("b".to_owned()).unwrap(),
-        ],
+        body: Rc::new(parse_expression(&tokenize("a + b".to_owned()).unwrap()).unwrap()),
+        annotations: vec![None, None],
+        defaults: vec![None, None],
+        patterns: vec![None, None],
+        guard: None,
+        clauses: Vec::new(),
+        variadic: false,
+        ret: None,
+        file: None,
+        lambda: false,
+        sig: None,
     };
     ctx.functions
         .insert(Ident::new("add".to_owned()).unwrap(), add.clone());
     let with_fn_call = evaluate(&tokenize("add 1 2".to_owned()).unwrap(), &ctx);
     eprintln!("With fn call: {:?}", with_fn_call);
 }
+
+#[test]
+fn test_var_annotations() {
+    use crate::token::tokenize;
+
+    let mut ctx = Context::new();
+
+    assert_eq!(
+        ctx.new_var(&tokenize("var n: int = 3".to_owned()).unwrap()),
+        Ok(vec![(Ident::new("n".to_owned()).unwrap(), Value::Num(3.0))])
+    );
+    assert_eq!(
+        ctx.annotations.get(&Ident::new("n".to_owned()).unwrap()),
+        Some(&Type::Int)
+    );
+    assert_eq!(
+        ctx.new_var(&tokenize("var m: int = 7 / 2".to_owned()).unwrap()),
+        Err("Variable m expects int, got 3.5".to_owned())
+    );
+    assert!(ctx
+        .new_var(&tokenize("var m: text = 1".to_owned()).unwrap())
+        .is_err());
+    assert!(ctx
+        .new_var(&tokenize("var x: num = 0.5".to_owned()).unwrap())
+        .is_ok());
+}
+
+#[test]
+fn test_math_aliases() {
+    use crate::token::tokenize;
+
+    let ctx = Context::new();
+    let eval = |s: &str| {
+        evaluate(&tokenize(s.to_owned()).unwrap(), &ctx)
+            .and_then(|val| val.num())
+            .unwrap()
+    };
+
+    assert_eq!(eval("√16 × 2"), 8.0);
+    assert_eq!(eval("2 × π ÷ 2 − π"), 0.0);
+    assert_eq!(eval("−e"), -std::f64::consts::E);
+}
@@ -1,10 +1,62 @@
+pub mod check;
+pub mod debugger;
+pub mod engine;
+pub mod eval;
+pub mod exec;
 pub mod function;
 pub mod interpreter;
-pub mod rpn;
+pub mod module;
+pub mod output;
+pub mod stdlib;
 pub mod token;
+pub mod trace;
 pub mod tree;
+pub mod types;
+pub mod value;
+
+use std::{env, fs, path::Path, process, thread};
 
 fn main() {
-    let interpreter = interpreter::Interpreter::new();
-    interpreter.begin().unwrap();
+    let args = env::args().collect::<Vec<String>>();
+
+    match args.get(1).map(String::as_str) {
+        Some("check") => {
+            let path = match args.get(2) {
+                Some(path) => path,
+                None => {
+                    eprintln!("Usage: minilang check <file>");
+                    process::exit(2);
+                }
+            };
+            let source = match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(e) => {
+                    eprintln!("Error: Failed to read {}: {}", path, e);
+                    process::exit(2);
+                }
+            };
+
+            let diagnostics = check::Checker::new()
+                .relative_to(Path::new(path).parent())
+                .check(&source);
+            for diag in &diagnostics {
+                eprintln!("{}:{}", path, diag);
+            }
+            if diagnostics
+                .iter()
+                .any(|diag| diag.severity == check::Severity::Error)
+            {
+                process::exit(1);
+            }
+        }
+        _ => {
+            // Deep recursion fails with an error long before it runs out of this stack
+            thread::Builder::new()
+                .stack_size(interpreter::STACK_SIZE)
+                .spawn(|| interpreter::Interpreter::new().begin().unwrap())
+                .unwrap()
+                .join()
+                .unwrap();
+        }
+    }
 }
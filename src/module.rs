@@ -0,0 +1,656 @@
+use {
+    crate::{
+        check::Checker,
+        engine::Engine,
+        function::Function,
+        interpreter::{Context, ReadOnly},
+        stdlib,
+        token::Ident,
+        tree::{Arm, Expr, Stmt},
+        types::Type,
+        value::{Closure, Value},
+    },
+    std::{
+        cell::RefCell,
+        collections::{HashMap, HashSet},
+        env, fs,
+        path::{Path, PathBuf},
+        rc::Rc,
+    },
+};
+
+/// Everything a file declares at the top level, which importing it makes available inside a namespace
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub variables: Vec<(Ident, Value)>,
+    pub annotations: HashMap<Ident, Type>,
+    /// Namespaces the file used, which its declarations can refer to without qualifying
+    pub uses: Vec<Ident>,
+    /// Constants stay constant in the namespace
+    pub readonly: HashMap<Ident, ReadOnly>,
+}
+impl Module {
+    /// The declarations in `ctx` that aren't builtins
+    fn new(ctx: &Context) -> Self {
+        let builtins = Context::new();
+        let functions = ctx
+            .functions
+            .values()
+            .filter(|func| builtins.functions.get(&func.ident) != Some(func))
+            .cloned()
+            .collect();
+        let variables = ctx
+            .variables
+            .borrow()
+            .iter()
+            .filter(|(ident, val)| builtins.variables.borrow().get(ident) != Some(val))
+            .map(|(ident, val)| (ident.clone(), val.clone()))
+            .collect();
+        Module {
+            functions,
+            variables,
+            annotations: ctx.annotations.clone(),
+            uses: ctx.uses.clone(),
+            readonly: ctx.readonly.clone(),
+        }
+    }
+}
+
+/// Runs imported files. Each file is run once, in an engine of its own, and what it declares is cached by path,
+/// so importing it again or from another file doesn't run it again.
+#[derive(Debug, Default)]
+pub struct Loader {
+    modules: RefCell<HashMap<PathBuf, Rc<Module>>>,
+    /// Files being run, outermost first
+    loading: RefCell<Vec<PathBuf>>,
+    /// Only check files to find out what they declare, never running them
+    checking: bool,
+    /// Where imports not found next to the importing file are looked for, in order
+    search: Vec<PathBuf>,
+}
+impl Loader {
+    /// A loader searching the directories listed in `MINILANG_PATH`
+    pub fn new() -> Self {
+        Loader {
+            search: env::var_os("MINILANG_PATH")
+                .map(|paths| env::split_paths(&paths).collect())
+                .unwrap_or_default(),
+            ..Self::default()
+        }
+    }
+
+    /// A loader for the checker, which reads the declarations of files without running anything in them.
+    /// Their variables all hold 0.
+    pub fn checking() -> Self {
+        Loader {
+            checking: true,
+            ..Self::new()
+        }
+    }
+
+    /// Searches `dirs` for imports instead of `MINILANG_PATH`
+    pub fn searching(self, dirs: Vec<PathBuf>) -> Self {
+        Loader {
+            search: dirs,
+            ..self
+        }
+    }
+
+    pub fn load(self: &Rc<Self>, path: &Path) -> Result<Rc<Module>, String> {
+        if let Some(module) = self.modules.borrow().get(path) {
+            return Ok(module.clone());
+        }
+        let loading = self.loading.borrow().clone();
+        if let Some(idx) = loading.iter().position(|file| file == path) {
+            let cycle = loading[idx..]
+                .iter()
+                .chain(Some(&path.to_owned()))
+                .map(|file| file_name(file))
+                .collect::<Vec<String>>();
+            return Err(format!("Import cycle: {}", cycle.join(" -> ")));
+        }
+
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        self.loading.borrow_mut().push(path.to_owned());
+        let res = if self.checking {
+            Checker::with_loader(self.clone())
+                .in_module()
+                .relative_to(path.parent())
+                .declarations(&source)
+                .map_err(|e| format!("{}:{}", file_name(path), e))
+        } else {
+            let mut engine = Engine::with_loader(self.clone());
+            engine.context.module = true;
+            engine
+                .run_source(&source, path)
+                .map(|_| engine.context)
+                .map_err(|e| format!("In {}: {}", file_name(path), e))
+        };
+        self.loading.borrow_mut().pop();
+        let ctx = res?;
+
+        let module = Rc::new(Module::new(&ctx));
+        self.modules
+            .borrow_mut()
+            .insert(path.to_owned(), module.clone());
+        Ok(module)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+/// Finds the file an import names, looking next to the importing file (or in the working directory when there
+/// isn't one) and then in each directory of `search`
+pub fn resolve(path: &str, dir: Option<&Path>, search: &[PathBuf]) -> Result<PathBuf, String> {
+    Some(dir.map_or_else(PathBuf::new, Path::to_owned))
+        .into_iter()
+        .chain(search.iter().cloned())
+        .map(|dir| dir.join(path))
+        .find(|file| file.is_file())
+        .ok_or_else(|| format!("Cannot find {} to import", path))?
+        .canonicalize()
+        .map_err(|e| format!("Failed to read {}: {}", path, e))
+}
+
+/// Runs the file `path` names, unless `loader` already has, and adds what it declares to `ctx` inside the
+/// namespace `alias`, or the name of the file. Returns the namespace.
+pub fn import(
+    ctx: &mut Context,
+    loader: &Rc<Loader>,
+    path: &str,
+    alias: Option<&Ident>,
+    dir: Option<&Path>,
+) -> Result<Ident, String> {
+    let file = resolve(path, dir, &loader.search)?;
+    let ns = match alias {
+        Some(alias) => alias.clone(),
+        None => file
+            .file_stem()
+            .and_then(|stem| Ident::new(stem.to_string_lossy().into_owned()))
+            .ok_or_else(|| format!("{} isn't a valid namespace, import it with as", path))?,
+    };
+    if stdlib::is_namespace(&ns) {
+        return Err(format!(
+            "{} is a builtin namespace, import {} with as",
+            ns, path
+        ));
+    }
+    let module = loader.load(&file)?;
+    install(ctx, &ns, &module);
+    Ok(ns)
+}
+
+/// Adds every declaration of `module` to `ctx` as `ns.name`
+pub fn install(ctx: &mut Context, ns: &Ident, module: &Module) {
+    let qualifier = Qualifier {
+        ns,
+        names: module
+            .functions
+            .iter()
+            .map(|func| func.ident.clone())
+            .chain(module.variables.iter().map(|(ident, _)| ident.clone()))
+            .collect(),
+        uses: &module.uses,
+    };
+    for func in &module.functions {
+        let func = qualifier.function(func, &[]);
+        ctx.functions.insert(func.ident.clone(), func);
+    }
+    for (ident, val) in &module.variables {
+        if let Some(ty) = module.annotations.get(ident) {
+            ctx.annotations.insert(ident.qualified(ns), ty.clone());
+        }
+        if let Some(readonly) = module.readonly.get(ident) {
+            ctx.readonly.insert(ident.qualified(ns), *readonly);
+        }
+        ctx.variables
+            .borrow_mut()
+            .insert(ident.qualified(ns), qualifier.value(val));
+    }
+}
+
+/// Rewrites declarations from a module so the names it declares refer to their namespaced versions
+struct Qualifier<'a> {
+    ns: &'a Ident,
+    names: HashSet<Ident>,
+    uses: &'a [Ident],
+}
+impl Qualifier<'_> {
+    /// What `ident` is called outside the module, unless a local hides the module's declaration
+    fn name(&self, ident: &Ident, locals: &[Ident]) -> Ident {
+        if locals.contains(ident) {
+            return ident.clone();
+        }
+        if self.names.contains(ident) {
+            return ident.qualified(self.ns);
+        }
+        // The fields of a record, `config.rate`
+        for (base, fields) in ident.fields() {
+            if locals.contains(&base) {
+                return ident.clone();
+            }
+            if self.names.contains(&base) {
+                // Each field is qualified by everything before it
+                return fields.iter().fold(base.qualified(self.ns), |name, field| {
+                    field.qualified(&name)
+                });
+            }
+        }
+        // A name the module found through `use`, which the importing file might not be using
+        let used = self
+            .uses
+            .iter()
+            .rev()
+            .map(|ns| ident.qualified(ns))
+            .find(|used| self.names.contains(used) || stdlib::is_builtin(used));
+        match used {
+            Some(used) if self.names.contains(&used) => used.qualified(self.ns),
+            Some(used) => used,
+            None => ident.clone(),
+        }
+    }
+
+    /// `captured` are the locals a lambda can see besides its arguments
+    fn function(&self, func: &Function, captured: &[Ident]) -> Function {
+        let mut locals = captured.to_vec();
+        let mut defaults = Vec::new();
+        for (arg, default) in func.args.iter().zip(&func.defaults) {
+            defaults.push(
+                default
+                    .as_ref()
+                    .map(|expr| Rc::new(self.expr(expr, &locals))),
+            );
+            locals.push(arg.clone());
+        }
+        locals.extend(func.names());
+        Function {
+            ident: self.name(&func.ident, &[]),
+            body: Rc::new(self.expr(&func.body, &locals)),
+            defaults,
+            guard: func
+                .guard
+                .as_ref()
+                .map(|guard| Rc::new(self.expr(guard, &locals))),
+            clauses: func
+                .clauses
+                .iter()
+                .map(|clause| self.function(clause, captured))
+                .collect(),
+            ..func.clone()
+        }
+    }
+
+    fn value(&self, val: &Value) -> Value {
+        match val {
+            Value::Num(v) => Value::Num(*v),
+            Value::Str(text) => Value::Str(text.clone()),
+            Value::List(items) => Value::List(items.iter().map(|item| self.value(item)).collect()),
+            Value::Tuple(items) => {
+                Value::Tuple(items.iter().map(|item| self.value(item)).collect())
+            }
+            Value::Record(fields) => Value::Record(
+                fields
+                    .iter()
+                    .map(|(ident, val)| (ident.clone(), self.value(val)))
+                    .collect(),
+            ),
+            Value::Func(closure) => {
+                let captured = closure
+                    .captured
+                    .iter()
+                    .map(|(ident, _)| ident.clone())
+                    .collect::<Vec<Ident>>();
+                Value::Func(Closure {
+                    func: Rc::new(self.function(&closure.func, &captured)),
+                    captured: closure
+                        .captured
+                        .iter()
+                        .map(|(ident, val)| (ident.clone(), self.value(val)))
+                        .collect(),
+                    applied: closure.applied.iter().map(|val| self.value(val)).collect(),
+                })
+            }
+        }
+    }
+
+    fn expr(&self, expr: &Expr, locals: &[Ident]) -> Expr {
+        let boxed = |expr: &Expr| Box::new(self.expr(expr, locals));
+        match expr {
+            Expr::Num(v) => Expr::Num(*v),
+            Expr::Str(text) => Expr::Str(text.clone()),
+            Expr::Var(ident, span) => Expr::Var(self.name(ident, locals), *span),
+            Expr::Unary { op, operand } => Expr::Unary {
+                op: *op,
+                operand: boxed(operand),
+            },
+            Expr::Binary { op, lhs, rhs } => Expr::Binary {
+                op: *op,
+                lhs: boxed(lhs),
+                rhs: boxed(rhs),
+            },
+            Expr::App { callee, args, span } => Expr::App {
+                callee: boxed(callee),
+                args: args.iter().map(|arg| self.expr(arg, locals)).collect(),
+                span: *span,
+            },
+            Expr::Call {
+                callee,
+                args,
+                named,
+                span,
+            } => Expr::Call {
+                callee: boxed(callee),
+                args: args.iter().map(|arg| self.expr(arg, locals)).collect(),
+                named: named
+                    .iter()
+                    .map(|(ident, arg)| (ident.clone(), self.expr(arg, locals)))
+                    .collect(),
+                span: *span,
+            },
+            Expr::Lambda { params, body } => {
+                let mut locals = locals.to_vec();
+                locals.extend(params.iter().cloned());
+                Expr::Lambda {
+                    params: params.clone(),
+                    body: Rc::new(self.expr(body, &locals)),
+                }
+            }
+            Expr::Block(body) => Expr::Block(self.block(body, &mut locals.to_vec())),
+            Expr::Record(fields) => Expr::Record(self.fields(fields, locals)),
+            Expr::With { record, fields } => Expr::With {
+                record: boxed(record),
+                fields: self.fields(fields, locals),
+            },
+            Expr::Tuple(items) => {
+                Expr::Tuple(items.iter().map(|item| self.expr(item, locals)).collect())
+            }
+            Expr::Try {
+                body,
+                ident,
+                fallback,
+            } => {
+                let mut locals = locals.to_vec();
+                locals.push(ident.clone());
+                Expr::Try {
+                    body: boxed(body),
+                    ident: ident.clone(),
+                    fallback: Box::new(self.expr(fallback, &locals)),
+                }
+            }
+            Expr::Match { value, arms, span } => Expr::Match {
+                value: boxed(value),
+                arms: arms
+                    .iter()
+                    .map(|arm| {
+                        let mut locals = locals.to_vec();
+                        locals.extend(arm.pattern.idents().into_iter().cloned());
+                        Arm {
+                            pattern: arm.pattern.clone(),
+                            guard: arm.guard.as_ref().map(|guard| self.expr(guard, &locals)),
+                            body: self.expr(&arm.body, &locals),
+                        }
+                    })
+                    .collect(),
+                span: *span,
+            },
+        }
+    }
+
+    fn fields(&self, fields: &[(Ident, Expr)], locals: &[Ident]) -> Vec<(Ident, Expr)> {
+        fields
+            .iter()
+            .map(|(ident, expr)| (ident.clone(), self.expr(expr, locals)))
+            .collect()
+    }
+
+    fn block(&self, body: &[Stmt], locals: &mut Vec<Ident>) -> Vec<Stmt> {
+        let depth = locals.len();
+        let body = body
+            .iter()
+            .map(|stmt| self.statement(stmt, locals))
+            .collect();
+        locals.truncate(depth);
+        body
+    }
+
+    /// Variables declared in a block are added to `locals`
+    fn statement(&self, stmt: &Stmt, locals: &mut Vec<Ident>) -> Stmt {
+        match stmt {
+            Stmt::Function(func) => Stmt::Function(self.function(func, locals)),
+            Stmt::Var { ident, ann, expr } => {
+                let expr = self.expr(expr, locals);
+                locals.push(ident.clone());
+                Stmt::Var {
+                    ident: ident.clone(),
+                    ann: ann.clone(),
+                    expr,
+                }
+            }
+            Stmt::Destructure { pattern, expr } => {
+                let expr = self.expr(expr, locals);
+                locals.extend(pattern.idents().into_iter().cloned());
+                Stmt::Destructure {
+                    pattern: pattern.clone(),
+                    expr,
+                }
+            }
+            Stmt::Assign {
+                ident,
+                op,
+                expr,
+                span,
+            } => Stmt::Assign {
+                ident: self.name(ident, locals),
+                op: *op,
+                expr: self.expr(expr, locals),
+                span: *span,
+            },
+            Stmt::While { cond, body } => Stmt::While {
+                cond: self.expr(cond, locals),
+                body: self.block(body, locals),
+            },
+            Stmt::For {
+                ident,
+                from,
+                to,
+                body,
+            } => {
+                let (from, to) = (self.expr(from, locals), self.expr(to, locals));
+                locals.push(ident.clone());
+                let body = self.block(body, locals);
+                locals.pop();
+                Stmt::For {
+                    ident: ident.clone(),
+                    from,
+                    to,
+                    body,
+                }
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => Stmt::If {
+                cond: self.expr(cond, locals),
+                then: self.block(then, locals),
+                otherwise: self.block(otherwise, locals),
+            },
+            Stmt::Return(expr) => Stmt::Return(self.expr(expr, locals)),
+            Stmt::Expr(expr) => Stmt::Expr(self.expr(expr, locals)),
+            Stmt::Import { .. }
+            | Stmt::Use { .. }
+            | Stmt::Override(_)
+            | Stmt::Const { .. }
+            | Stmt::Break
+            | Stmt::Continue => stmt.clone(),
+        }
+    }
+}
+
+#[test]
+fn test_imports() {
+    use crate::{check::Checker, engine::Outcome};
+    // Removed however the test ends
+    struct TempDir(PathBuf);
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+    let temp = TempDir(env::temp_dir().join(format!("minilang-imports-{}", std::process::id())));
+    let dir = temp.0.clone();
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    let write = |name: &str, src: &str| fs::write(dir.join(name), src).unwrap();
+    write(
+        "stats.mini",
+        "var scale = 2\nfunction mean ...xs = sum xs / len xs\nfunction scaled x = x * scale\nfunction spread a b = scaled (b - a)\nfunction bump by {\n  scale += by\n  return scale\n}\nvar double = \\x -> scaled x\nconst unit = 1",
+    );
+    write(
+        "lib/finance.mini",
+        "import \"../stats.mini\" as st\nfunction interest p r = p * r / st.scale",
+    );
+    write("a.mini", "import b\nvar x = 1");
+    write("b.mini", "import a");
+
+    let mut engine = Engine::new();
+    let mut run = |src: &str| engine.run_in(src, Some(&dir));
+    assert_eq!(
+        run("import stats as s"),
+        Ok(Outcome::Module(Ident::new("s".to_owned()).unwrap()))
+    );
+    let num = |n: f64| Ok(Outcome::Value(Value::Num(n)));
+    assert_eq!(run("s.mean 1 2 3"), num(2.0));
+    // Declared inside the namespace, mean doesn't need override
+    let diags = Checker::new()
+        .relative_to(Some(&dir))
+        .check("import stats as s\ns.mean 1 2 3");
+    assert!(diags.is_empty(), "{:?}", diags);
+    assert_eq!(run("s.spread(1, 4)"), num(6.0));
+    assert_eq!(run("s.double 5"), num(10.0));
+    assert_eq!(run("scaled 1"), Err("Unknown identifier scaled".to_owned()));
+
+    // Declarations of the file refer to each other inside the namespace
+    assert_eq!(run("s.bump 1"), num(3.0));
+    assert_eq!(run("s.scale"), num(3.0));
+    assert_eq!(run("s.spread 1 4"), num(9.0));
+    assert_eq!(
+        run("s.unit = 2"),
+        Err("s.unit is a constant and can't be assigned to".to_owned())
+    );
+
+    assert_eq!(
+        run("import lib.finance"),
+        Ok(Outcome::Module(Ident::new("finance".to_owned()).unwrap()))
+    );
+    assert_eq!(run("finance.interest 100 0.1"), num(5.0));
+
+    // `use` brings a namespace into scope, ahead of the builtin stats.mean
+    assert_eq!(
+        run("import stats"),
+        Err("stats is a builtin namespace, import stats.mini with as".to_owned())
+    );
+    run("use s").unwrap();
+    assert_eq!(run("mean 1 2 3"), num(2.0));
+    run("scale = 5").unwrap();
+    assert_eq!(run("s.scale"), num(5.0));
+
+    // Files only run once, later imports reuse what they declared
+    write("stats.mini", "var scale = 100");
+    run("import stats as t").unwrap();
+    assert_eq!(run("t.scale"), num(2.0));
+
+    assert_eq!(
+        run("import a"),
+        Err("In a.mini: In b.mini: Import cycle: a.mini -> b.mini -> a.mini".to_owned())
+    );
+    assert_eq!(
+        run("import nothing"),
+        Err("Cannot find nothing.mini to import".to_owned())
+    );
+    assert_eq!(
+        run("if 1 then import a end"),
+        Err("Imports can only be at the top level".to_owned())
+    );
+
+    // A traceback names the file each call is in
+    write(
+        "fails.mini",
+        "function positive x = assert(x > 0, \"not positive\")\nfunction total a b = positive (a + b)",
+    );
+    let mut engine = Engine::new();
+    engine.run_in("import fails", Some(&dir)).unwrap();
+    assert_eq!(
+        engine.run("fails.total 1 (-3)"),
+        Err("Assertion failed: not positive".to_owned())
+    );
+    let file = dir.join("fails.mini").canonicalize().unwrap();
+    let frames = engine.traceback();
+    assert_eq!(frames[0].source, None);
+    assert_eq!(frames[1].source.as_deref(), Some(file.as_path()));
+    assert_eq!(
+        frames[1].to_string(),
+        format!("fails.positive(x = -2) at {}:2:22", file.display())
+    );
+
+    // Not found next to the importing file, so the search path is
+    let loader = Loader::new().searching(vec![dir.join("lib")]);
+    assert!(Engine::with_loader(Rc::new(loader))
+        .run("import finance as f")
+        .is_ok());
+
+    let diags = Checker::new()
+        .relative_to(Some(&dir))
+        .check("import lib.finance\nfinance.interest 1 2\nfinance.nope\nimport missing");
+    assert_eq!(
+        diags
+            .iter()
+            .map(|diag| format!("{}", diag))
+            .collect::<Vec<String>>(),
+        vec![
+            "3:1: error: Unknown identifier finance.nope".to_owned(),
+            "4:8: error: Cannot find missing.mini to import".to_owned(),
+        ]
+    );
+
+    // The checker reads what a file declares without running it, which here would never finish
+    write(
+        "script.mini",
+        "println \"loading\"\nvar rate = num \"x\"\nwhile 1 do end\nfunction f x = x * rate",
+    );
+    write("broken.mini", "var y = 1\nfunction g = nope");
+    let check = |src: &str| {
+        Checker::new()
+            .relative_to(Some(&dir))
+            .check(src)
+            .iter()
+            .map(|diag| format!("{}", diag))
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(
+        check("import script\nscript.f script.rate"),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        check("import script\nscript.f"),
+        vec!["2:1: error: script.f expects 1 argument(s), but only 0 are available".to_owned()]
+    );
+    assert_eq!(
+        check("import broken"),
+        vec!["1:8: error: broken.mini:2:14: Unknown identifier nope".to_owned()]
+    );
+    // Each position comes after the name of the file it's in
+    assert_eq!(
+        check("import a"),
+        vec![
+            "1:8: error: a.mini:1:8: b.mini:1:8: Import cycle: a.mini -> b.mini -> a.mini"
+                .to_owned()
+        ]
+    );
+}
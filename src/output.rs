@@ -0,0 +1,108 @@
+use std::{
+    cell::RefCell,
+    io::{stdout, Write},
+    rc::Rc,
+};
+
+/// Where `print` and `println` write. The REPL writes to stdout, a program embedding minilang can collect the
+/// output instead, see `Engine::set_output`.
+pub trait Output {
+    fn write(&mut self, text: &str) -> Result<(), String>;
+}
+
+pub struct Stdout;
+impl Output for Stdout {
+    fn write(&mut self, text: &str) -> Result<(), String> {
+        let mut out = stdout();
+        out.write_all(text.as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Failed to print: {}", e))
+    }
+}
+
+/// Keeps everything written. Clones share the text, so one can be given to an engine and the other read.
+#[derive(Debug, Clone)]
+pub struct Buffer {
+    text: Rc<RefCell<String>>,
+}
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer {
+            text: Rc::new(RefCell::new(String::new())),
+        }
+    }
+
+    /// Everything written so far
+    pub fn contents(&self) -> String {
+        self.text.borrow().clone()
+    }
+
+    /// Empties the buffer, returning what it held
+    pub fn take(&self) -> String {
+        self.text.take()
+    }
+}
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Output for Buffer {
+    fn write(&mut self, text: &str) -> Result<(), String> {
+        self.text.borrow_mut().push_str(text);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_output() {
+    use crate::engine::{Engine, Outcome};
+    use crate::value::Value;
+    let mut engine = Engine::new();
+    let out = Buffer::new();
+    engine.set_output(Box::new(out.clone()));
+
+    engine
+        .run("function report ...xs {\n  println(format(\"n = {}\", len xs))\n  print \"sum = \"\n  return println(sum xs)\n}")
+        .unwrap();
+    assert_eq!(
+        engine.run("2 * report 1 2 3"),
+        Ok(Outcome::Value(Value::Num(12.0)))
+    );
+    assert_eq!(out.take(), "n = 3\nsum = 6\n");
+
+    // Strings are printed without quotes, anything else as the REPL shows it
+    engine
+        .run("print \"a\\tb\"; print (1, \"c\"); println \"\"")
+        .unwrap();
+    assert_eq!(out.take(), "a\tb(1, \"c\")\n");
+
+    // Too many arguments are an error before anything is printed, unless what's returned can take them
+    engine.run("function add a b = a + b").unwrap();
+    for (src, e) in [
+        (
+            "println(1, 2)",
+            "io.println expects 1 argument(s), but 2 are given",
+        ),
+        (
+            "println 1 2",
+            "io.println expects 1 argument(s), but 2 are given",
+        ),
+        (
+            "(println) 1 2",
+            "io.println expects 1 argument(s), but 2 are given",
+        ),
+        (
+            "add 1 2 (println 3)",
+            "add expects 2 argument(s), but 3 are given",
+        ),
+    ] {
+        assert_eq!(engine.run(src), Err(e.to_owned()), "{}", src);
+    }
+    assert_eq!(out.contents(), "");
+    assert_eq!(
+        engine.run("println (add 1) 2"),
+        Ok(Outcome::Value(Value::Num(3.0)))
+    );
+    assert_eq!(out.contents(), "add 1\n");
+}
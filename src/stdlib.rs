@@ -0,0 +1,488 @@
+use {
+    crate::{
+        function::Function,
+        interpreter::{Context, ErrorKind},
+        token::{quote, Ident, Operator, Span},
+        tree::Expr,
+        types::Type,
+        value::Value,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        rc::Rc,
+    },
+};
+
+/// Namespaces every program starts out using, so `sin` and `sum` work without `math.` or `stats.` as they did
+/// before builtins had namespaces
+pub const PRELUDE: [&str; 5] = ["math", "stats", "text", "io", "core"];
+
+/// The builtin functions, by their qualified names
+pub fn functions() -> HashMap<Ident, Function> {
+    let list = |ty: Type| Type::List(Box::new(ty));
+    let rest = Type::Rest(Box::new(Type::Num));
+    let pair = Type::Tuple(vec![Type::Num, Type::Num]);
+    let num = |arg| vec![(arg, Type::Num)];
+    let str = |arg| vec![(arg, Type::Str)];
+    let two = |a, b| vec![(a, Type::Str), (b, Type::Str)];
+    let table = [
+        ("math.sin", num("a"), Type::Num),
+        ("math.cos", num("a"), Type::Num),
+        ("math.tan", num("a"), Type::Num),
+        ("math.asin", num("a"), Type::Num),
+        ("math.acos", num("a"), Type::Num),
+        ("math.atan", num("a"), Type::Num),
+        ("math.sqrt", num("a"), Type::Num),
+        ("math.min", vec![("xs", rest.clone())], Type::Num),
+        ("math.max", vec![("xs", rest.clone())], Type::Num),
+        ("math.minmax", vec![("xs", rest)], pair.clone()),
+        (
+            "math.divmod",
+            vec![("a", Type::Num), ("b", Type::Num)],
+            pair.clone(),
+        ),
+        ("math.polar", vec![("x", Type::Num), ("y", Type::Num)], pair),
+        ("stats.sum", vec![("xs", list(Type::Num))], Type::Num),
+        ("stats.mean", vec![("xs", list(Type::Num))], Type::Num),
+        // Counts the items of a list or the characters of a string
+        ("stats.len", vec![("xs", Type::Any)], Type::Num),
+        ("text.str", vec![("x", Type::Any)], Type::Str),
+        ("text.num", str("s"), Type::Num),
+        (
+            "text.format",
+            vec![
+                ("template", Type::Str),
+                ("values", Type::Rest(Box::new(Type::Any))),
+            ],
+            Type::Str,
+        ),
+        ("text.upper", str("s"), Type::Str),
+        ("text.lower", str("s"), Type::Str),
+        ("text.split", two("s", "sep"), list(Type::Str)),
+        (
+            "text.join",
+            vec![("parts", list(Type::Str)), ("sep", Type::Str)],
+            Type::Str,
+        ),
+        ("text.contains", two("s", "part"), Type::Num),
+        // Print a value and return it, so they can be used inside expressions
+        ("io.print", vec![("x", Type::Var(0))], Type::Var(0)),
+        ("io.println", vec![("x", Type::Var(0))], Type::Var(0)),
+        // Never returns, so it fits wherever a value is expected
+        ("core.error", str("message"), Type::Var(0)),
+        (
+            "core.assert",
+            vec![("cond", Type::Num), ("message", Type::Str)],
+            Type::Num,
+        ),
+    ];
+    table
+        .iter()
+        .map(|(name, params, ret)| {
+            let func = builtin(name, params, ret.clone());
+            (func.ident.clone(), func)
+        })
+        .collect()
+}
+
+/// A function taking `params` and returning `ret`. Its body is never evaluated, `call` computes the result.
+fn builtin(name: &str, params: &[(&str, Type)], ret: Type) -> Function {
+    let args = params
+        .iter()
+        .map(|(arg, _)| Ident::new((*arg).to_owned()).unwrap())
+        .collect::<Vec<Ident>>();
+    let annotations = params
+        .iter()
+        .map(|(_, param)| match param {
+            Type::Rest(ty) => Some(ty.as_ref()),
+            ty => Some(ty),
+        })
+        .map(|ty| ty.filter(|ty| matches!(ty, Type::Num | Type::Str)).cloned())
+        .collect();
+    let tys = params
+        .iter()
+        .map(|(_, param)| param.clone())
+        .collect::<Vec<Type>>();
+    Function {
+        ident: Ident::new(name.to_owned()).unwrap(),
+        body: Rc::new(Expr::Var(args[0].clone(), Span::default())),
+        annotations,
+        defaults: vec![None; args.len()],
+        patterns: vec![None; args.len()],
+        guard: None,
+        clauses: Vec::new(),
+        variadic: matches!(tys.last(), Some(Type::Rest(_))),
+        ret: Some(ret.clone()),
+        sig: Some(Type::Fn(tys, Box::new(ret))),
+        file: None,
+        lambda: false,
+        args,
+    }
+}
+
+/// The builtin constants, by their qualified names
+pub fn variables() -> HashMap<Ident, Value> {
+    [
+        ("math.pi", std::f64::consts::PI),
+        ("math.e", std::f64::consts::E),
+    ]
+    .iter()
+    .map(|(name, v)| (Ident::new((*name).to_owned()).unwrap(), Value::Num(*v)))
+    .collect()
+}
+
+thread_local! {
+    /// The qualified names of every builtin, built once rather than on every lookup
+    static NAMES: HashSet<Ident> = functions().into_keys().chain(variables().into_keys()).collect();
+}
+
+pub fn is_builtin(ident: &Ident) -> bool {
+    ident.is_qualified() && NAMES.with(|names| names.contains(ident))
+}
+
+/// Whether `ns` holds builtins, like `math`
+pub fn is_namespace(ns: &Ident) -> bool {
+    let prefix = format!("{}.", ns);
+    NAMES.with(|names| {
+        names
+            .iter()
+            .any(|ident| ident.internal_cloned().starts_with(&prefix))
+    })
+}
+
+/// Computes the builtin `ident`, `None` if it isn't one
+pub fn call(ident: &Ident, args: &[Value], ctx: &Context) -> Option<Result<Value, String>> {
+    let unary: fn(f64) -> f64 =
+        match ident.internal_cloned().as_str() {
+            "math.sin" => f64::sin,
+            "math.cos" => f64::cos,
+            "math.tan" => f64::tan,
+            "math.asin" => f64::asin,
+            "math.acos" => f64::acos,
+            "math.atan" => f64::atan,
+            "math.sqrt" => f64::sqrt,
+            "math.min" => return Some(extreme(args, f64::min)),
+            "math.max" => return Some(extreme(args, f64::max)),
+            "math.minmax" => {
+                return Some(
+                    extreme(args, f64::min)
+                        .and_then(|min| Ok(Value::Tuple(vec![min, extreme(args, f64::max)?]))),
+                )
+            }
+            "math.divmod" => {
+                return Some(pair(args).and_then(|(a, b)| {
+                    Ok(Value::Tuple(vec![
+                        Value::Num(Operator::FloorDiv.operate(a, b)?),
+                        Value::Num(Operator::Mod.operate(a, b)?),
+                    ]))
+                }))
+            }
+            "math.polar" => {
+                return Some(pair(args).map(|(x, y)| {
+                    Value::Tuple(vec![Value::Num(x.hypot(y)), Value::Num(y.atan2(x))])
+                }))
+            }
+            "stats.sum" => return Some(numbers(args).map(|xs| Value::Num(xs.iter().sum()))),
+            "stats.mean" => {
+                return Some(
+                    numbers(args).map(|xs| Value::Num(xs.iter().sum::<f64>() / xs.len() as f64)),
+                )
+            }
+            "stats.len" => {
+                return Some(match args {
+                    [Value::List(items)] => Ok(Value::Num(items.len() as f64)),
+                    [Value::Str(text)] => Ok(Value::Num(text.chars().count() as f64)),
+                    [val] => Err(format!("Expected a list or a string, got {}", val)),
+                    _ => Err(format!("Expected 1 argument, got {}", args.len())),
+                })
+            }
+            name if name.starts_with("text.") => return Some(text(name, args)),
+            "io.print" | "io.println" => {
+                let val = match args {
+                    [val] => val,
+                    _ => return Some(Err(format!("Expected 1 argument, got {}", args.len()))),
+                };
+                let mut text = val.text();
+                if ident.internal_cloned() == "io.println" {
+                    text.push('\n');
+                }
+                return Some(ctx.output.borrow_mut().write(&text).map(|_| val.clone()));
+            }
+            "core.error" => {
+                return Some(match args {
+                    [message] => message
+                        .str()
+                        .and_then(|message| Err(ctx.raise(ErrorKind::Raised, message.to_owned()))),
+                    _ => Err(format!("Expected 1 argument, got {}", args.len())),
+                })
+            }
+            "core.assert" => {
+                let (cond, message) = match args {
+                    [cond, message] => (cond, message),
+                    _ => return Some(Err(format!("Expected 2 arguments, got {}", args.len()))),
+                };
+                return Some(cond.num().and_then(|cond| {
+                    let message = message.str()?;
+                    if cond == 0.0 {
+                        Err(ctx.raise(
+                            ErrorKind::Assertion,
+                            format!("Assertion failed: {}", message),
+                        ))
+                    } else {
+                        Ok(Value::Num(cond))
+                    }
+                }));
+            }
+            _ => return None,
+        };
+    Some(match args {
+        [Value::Num(v)] => Ok(Value::Num(unary(*v))),
+        [val] => Err(format!("Expected a number, got {}", val)),
+        _ => Err(format!("Expected 1 argument, got {}", args.len())),
+    })
+}
+
+/// The builtins in `text`
+fn text(name: &str, args: &[Value]) -> Result<Value, String> {
+    let argc = match name {
+        "text.format" | "text.split" | "text.join" | "text.contains" => 2,
+        _ => 1,
+    };
+    if args.len() != argc {
+        return Err(format!(
+            "Expected {} argument{}, got {}",
+            argc,
+            if argc == 1 { "" } else { "s" },
+            args.len()
+        ));
+    }
+    let string = |idx: usize| args[idx].str();
+    Ok(match name {
+        "text.str" => Value::Str(args[0].text()),
+        "text.num" => {
+            let s = string(0)?;
+            Value::Num(
+                s.trim()
+                    .parse()
+                    .map_err(|_| format!("Can't read {} as a number", args[0]))?,
+            )
+        }
+        "text.format" => Value::Str(format(string(0)?, args[1].list()?)?),
+        "text.upper" => Value::Str(string(0)?.to_uppercase()),
+        "text.lower" => Value::Str(string(0)?.to_lowercase()),
+        "text.split" => match string(1)? {
+            "" => return Err("Can't split on an empty separator".to_owned()),
+            sep => Value::List(
+                string(0)?
+                    .split(sep)
+                    .map(|part| Value::Str(part.to_owned()))
+                    .collect(),
+            ),
+        },
+        "text.join" => Value::Str(
+            args[0]
+                .list()?
+                .iter()
+                .map(Value::str)
+                .collect::<Result<Vec<&str>, String>>()?
+                .join(string(1)?),
+        ),
+        "text.contains" => Value::Num(string(0)?.contains(string(1)?) as u8 as f64),
+        _ => return Err(format!("Unknown builtin {}", name)),
+    })
+}
+
+/// Fills in the placeholders of `template` with `values` in order. `{}` shows a value like `str` does and
+/// `{:.2}` shows a number with 2 decimals. `{{` and `}}` are literal braces.
+fn format(template: &str, values: &[Value]) -> Result<String, String> {
+    let given = values.len();
+    let mut out = String::new();
+    let mut values = values.iter();
+    let mut placeholders = 0;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let len = rest
+                    .find('}')
+                    .ok_or_else(|| format!("Unclosed {{ in format {}", quote(template)))?;
+                let spec = &rest[..len];
+                chars = rest[len + 1..].chars();
+                placeholders += 1;
+                let val = match values.next() {
+                    Some(val) => val,
+                    None => continue,
+                };
+                match spec {
+                    "" => out.push_str(&val.text()),
+                    _ => {
+                        let decimals = spec
+                            .strip_prefix(":.")
+                            .and_then(|digits| digits.parse::<usize>().ok())
+                            .ok_or_else(|| {
+                                format!(
+                                    "Unknown placeholder {{{}}}, expected {{}} or {{:.2}}",
+                                    spec
+                                )
+                            })?;
+                        out.push_str(&format!("{:.*}", decimals, val.num()?));
+                    }
+                }
+            }
+            '}' => return Err(format!("Unmatched }} in format {}", quote(template))),
+            c => out.push(c),
+        }
+    }
+    if placeholders != given {
+        return Err(format!(
+            "The format has {} placeholder(s), but {} value(s) were given",
+            placeholders, given
+        ));
+    }
+    Ok(out)
+}
+
+fn extreme(args: &[Value], pick: fn(f64, f64) -> f64) -> Result<Value, String> {
+    match numbers(args)?.into_iter().reduce(pick) {
+        Some(v) => Ok(Value::Num(v)),
+        None => Err("Expected at least 1 argument, got 0".to_owned()),
+    }
+}
+
+/// The arguments of a builtin taking two numbers
+fn pair(args: &[Value]) -> Result<(f64, f64), String> {
+    match args {
+        [a, b] => Ok((a.num()?, b.num()?)),
+        _ => Err(format!("Expected 2 arguments, got {}", args.len())),
+    }
+}
+
+/// The numbers in the list a builtin taking a list is called with
+fn numbers(args: &[Value]) -> Result<Vec<f64>, String> {
+    match args {
+        [list] => list.list()?.iter().map(Value::num).collect(),
+        _ => Err(format!("Expected a list, got {} arguments", args.len())),
+    }
+}
+
+#[test]
+fn test_stdlib() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    let eval = |engine: &Engine, s: &str| engine.eval(s).and_then(|val| val.num());
+    assert_eq!(eval(&engine, "math.cos 0"), Ok(1.0));
+    assert_eq!(eval(&engine, "cos 0 + tan 0 + asin 0 + atan 0"), Ok(1.0));
+    assert_eq!(eval(&engine, "acos 1 + math.max 2 5"), Ok(5.0));
+
+    // Declarations can't replace a builtin by accident
+    assert_eq!(
+        engine.run("function sin x = x"),
+        Err("sin is a builtin, use override to replace it".to_owned())
+    );
+    assert_eq!(
+        engine.run("var pi = 3"),
+        Err("pi is a builtin, use override to replace it".to_owned())
+    );
+    assert_eq!(
+        engine.run("math.pi = 3"),
+        Err("math.pi is a builtin and can't be assigned to".to_owned())
+    );
+    assert!(engine.run("function math.f x = x").is_err());
+    assert!(engine.run("override function sin x = x / 2").is_ok());
+    assert_eq!(eval(&engine, "sin 4 + math.sin 0"), Ok(2.0));
+    assert!(engine.run("override var e = 2").is_ok());
+    assert_eq!(eval(&engine, "e + math.e"), Ok(2.0 + std::f64::consts::E));
+
+    engine.run("function avg ...xs = mean xs").unwrap();
+    assert_eq!(eval(&engine, "avg 1 2 6"), Ok(3.0));
+    assert!(engine.run("use math").is_ok());
+    assert_eq!(
+        engine.run("use nothing"),
+        Err("Unknown namespace nothing".to_owned())
+    );
+}
+
+#[test]
+fn test_text() {
+    use crate::engine::Engine;
+    let mut engine = Engine::new();
+    engine
+        .run("function label (x: num): str = format(\"{:.2} USD\", x)")
+        .unwrap();
+    assert_eq!(
+        engine.run("function f x = x ++ 1"),
+        Err("In function f: Type mismatch: expected str, got num in operand of ++".to_owned())
+    );
+    let eval = |s: &str| engine.eval(s).map(|val| val.to_string());
+
+    assert_eq!(eval("label 1234.5"), Ok("\"1234.50 USD\"".to_owned()));
+    assert_eq!(
+        eval("format(\"{} owes {:.1} {{}}\", \"Ann\", 2)"),
+        Ok("\"Ann owes 2.0 {}\"".to_owned())
+    );
+    assert_eq!(
+        eval("format(\"{} and {}\", 1)"),
+        Err("The format has 2 placeholder(s), but 1 value(s) were given".to_owned())
+    );
+    assert_eq!(
+        eval("format(\"{:x}\", 1)"),
+        Err("Unknown placeholder {:x}, expected {} or {:.2}".to_owned())
+    );
+    assert_eq!(
+        eval("\"total: \" ++ str(1 + 2) ++ str \"!\""),
+        Ok("\"total: 3!\"".to_owned())
+    );
+    assert_eq!(eval("num \" 2.5 \" * 2"), Ok("5".to_owned()));
+    assert_eq!(
+        eval("num \"two\""),
+        Err("Can't read \"two\" as a number".to_owned())
+    );
+    assert_eq!(
+        eval("split(\"a,b,,c\", \",\")"),
+        Ok("[\"a\", \"b\", \"\", \"c\"]".to_owned())
+    );
+    assert_eq!(
+        eval("join(split(\"a b c\", \" \"), \"-\")"),
+        Ok("\"a-b-c\"".to_owned())
+    );
+    assert_eq!(
+        eval("upper \"ab\" ++ lower \"CD\""),
+        Ok("\"ABcd\"".to_owned())
+    );
+    assert_eq!(
+        eval("contains(\"minilang\", \"lang\") + len \"héllo\""),
+        Ok("6".to_owned())
+    );
+    assert_eq!(eval("\"a\" == \"a\""), Ok("1".to_owned()));
+    assert_eq!(
+        eval("\"a\" ++ 1"),
+        Err("Operator ++ expects strings, got 1".to_owned())
+    );
+    assert_eq!(
+        eval("len 3"),
+        Err("Expected a list or a string, got 3".to_owned())
+    );
+
+    // Called directly, nothing has checked the arguments yet
+    let str_ = Ident::new("text.str".to_owned()).unwrap();
+    let format = Ident::new("text.format".to_owned()).unwrap();
+    assert_eq!(
+        engine.context.call_function(str_, &[]),
+        Err("Expected 1 argument, got 0".to_owned())
+    );
+    assert_eq!(
+        engine
+            .context
+            .call_function(format, &[Value::Str("{}".to_owned())]),
+        Err("Expected 2 arguments, got 1".to_owned())
+    );
+}
@@ -1,20 +1,62 @@
 use {
-    crate::function::Function,
-    regex::Regex,
-    std::fmt::{self, Debug, Display, Formatter},
+    std::{
+        fmt::{self, Debug, Display, Formatter},
+        iter::Peekable,
+        str::CharIndices,
+    },
+    unicode_xid::UnicodeXID,
 };
 
+/// Identifiers follow the Unicode XID rules, so `x1`, `rate_2`, `Δt` and `θ` are all valid
+fn is_ident_start(c: char) -> bool {
+    c == '_' || c.is_xid_start()
+}
+
+/// A name, optionally qualified by the namespaces it was imported into, e.g. `stats.mean`
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct Ident(String);
 impl Ident {
     pub fn new(s: String) -> Option<Self> {
-        if Regex::new(r#"^((\s)+)?(\D\w*)(\d)?"#).unwrap().is_match(&s) {
+        let valid = s.split('.').all(|part| {
+            let mut chars = part.chars();
+            matches!(chars.next(), Some(c) if is_ident_start(c))
+                && chars.all(UnicodeXID::is_xid_continue)
+        });
+        if valid {
             Some(Self(s))
         } else {
             None
         }
     }
 
+    /// A name source code can't refer to, like the `(x, y)` of an argument that is taken apart
+    pub fn unnamed(s: String) -> Self {
+        Self(s)
+    }
+
+    /// `self` inside the namespace `ns`, `ns.self`
+    pub fn qualified(&self, ns: &Ident) -> Self {
+        Self(format!("{}.{}", ns.0, self.0))
+    }
+
+    /// Whether this is a name inside a namespace, like `math.sin`
+    pub fn is_qualified(&self) -> bool {
+        self.0.contains('.')
+    }
+
+    /// The ways of reading a dotted name as a name followed by field accesses, longest name first:
+    /// `a.b.c` is `a.b` then `c`, or `a` then `b` and `c`
+    pub fn fields(&self) -> Vec<(Ident, Vec<Ident>)> {
+        let parts = self.0.split('.').collect::<Vec<&str>>();
+        (1..parts.len())
+            .rev()
+            .map(|len| {
+                let fields = parts[len..].iter().map(|part| Self((*part).to_owned()));
+                (Self(parts[..len].join(".")), fields.collect())
+            })
+            .collect()
+    }
+
     pub fn internal_cloned(&self) -> String {
         self.0.clone()
     }
@@ -29,54 +71,204 @@ impl Display for Ident {
 pub enum Operator {
     Add,
     Sub,
+    /// `++`, joining two strings
+    Concat,
     Mul,
+    /// Juxtaposition in calculator mode, `2pi` or `3(a + b)`
+    ImplicitMul,
     Div,
+    FloorDiv,
+    Mod,
     USub,
     Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    Factorial,
+    Percent,
     Assign,
+    /// `+=` and friends, `x += 1` is `x = x + 1`
+    AddAssign,
+    SubAssign,
+    MulAssign,
+    DivAssign,
+    FloorDivAssign,
+    ModAssign,
+    PowAssign,
+    /// `a..b` in a for loop
+    Range,
+    /// `...xs`, a parameter taking any number of arguments
+    Ellipsis,
+    Colon,
+    Comma,
+    /// Starts a lambda, `\x -> x + 1`
+    Backslash,
+    Arrow,
+    FatArrow,
     LParen,
     RParen,
+    LBrace,
+    RBrace,
 }
 impl Operator {
     fn new(s: String) -> Option<Self> {
         match s.as_str() {
             "+" => Some(Self::Add),
+            "++" => Some(Self::Concat),
             "-" => Some(Self::Sub),
             "*" => Some(Self::Mul),
             "/" => Some(Self::Div),
+            "//" => Some(Self::FloorDiv),
+            "%" => Some(Self::Mod),
             "^" => Some(Self::Pow),
+            "&" => Some(Self::BitAnd),
+            "|" => Some(Self::BitOr),
+            "xor" => Some(Self::BitXor),
+            "~" => Some(Self::BitNot),
+            "<<" => Some(Self::Shl),
+            ">>" => Some(Self::Shr),
+            "<" => Some(Self::Lt),
+            ">" => Some(Self::Gt),
+            "<=" => Some(Self::Le),
+            ">=" => Some(Self::Ge),
+            "==" => Some(Self::Eq),
+            "!=" => Some(Self::Ne),
             "=" => Some(Self::Assign),
+            "+=" => Some(Self::AddAssign),
+            "-=" => Some(Self::SubAssign),
+            "*=" => Some(Self::MulAssign),
+            "/=" => Some(Self::DivAssign),
+            "//=" => Some(Self::FloorDivAssign),
+            "%=" => Some(Self::ModAssign),
+            "^=" => Some(Self::PowAssign),
+            ".." => Some(Self::Range),
+            "..." => Some(Self::Ellipsis),
+            ":" => Some(Self::Colon),
+            "," => Some(Self::Comma),
+            "\\" => Some(Self::Backslash),
+            "->" => Some(Self::Arrow),
+            "=>" => Some(Self::FatArrow),
             "(" => Some(Self::LParen),
             ")" => Some(Self::RParen),
+            "{" => Some(Self::LBrace),
+            "}" => Some(Self::RBrace),
             _ => None,
         }
     }
 
-    pub fn operate(self, a: f64, b: f64) -> Result<f64, String> {
+    /// Applies a binary operator, `lhs` is the operand written on the left.
+    ///
+    /// `%` and `//` round towards negative infinity, so the result of `%` has the sign of the divisor
+    /// and `a == (a // b) * b + a % b` always holds, e.g. `-7 % 3 == 2` and `-7 // 3 == -3`.
+    pub fn operate(self, lhs: f64, rhs: f64) -> Result<f64, String> {
         match self {
-            Self::Add => Ok(a + b),
-            Self::Sub => Ok(a - b),
-            Self::Mul => Ok(a * b),
-            Self::Div => Ok(b / a),
-            Self::Pow => Ok(b.powf(a)),
-            Self::USub => Ok(-a),
+            Self::Add => Ok(lhs + rhs),
+            Self::Sub => Ok(lhs - rhs),
+            Self::Mul | Self::ImplicitMul => Ok(lhs * rhs),
+            Self::Div => Ok(lhs / rhs),
+            Self::FloorDiv => Ok((lhs / rhs).floor()),
+            Self::Mod => Ok(lhs - rhs * (lhs / rhs).floor()),
+            Self::Pow => Ok(lhs.powf(rhs)),
+            Self::BitAnd => Ok((self.integer(lhs)? & self.integer(rhs)?) as f64),
+            Self::BitOr => Ok((self.integer(lhs)? | self.integer(rhs)?) as f64),
+            Self::BitXor => Ok((self.integer(lhs)? ^ self.integer(rhs)?) as f64),
+            Self::Shl | Self::Shr => {
+                let (lhs, rhs) = (self.integer(lhs)?, self.integer(rhs)?);
+                if !(0..64).contains(&rhs) {
+                    return Err(format!("Cannot shift by {}, expected 0 to 63", rhs));
+                }
+                if self == Self::Shl {
+                    Ok(lhs.wrapping_shl(rhs as u32) as f64)
+                } else {
+                    Ok((lhs >> rhs) as f64)
+                }
+            }
+            // Comparisons are 1 when true and 0 when false
+            Self::Lt => Ok((lhs < rhs) as u8 as f64),
+            Self::Gt => Ok((lhs > rhs) as u8 as f64),
+            Self::Le => Ok((lhs <= rhs) as u8 as f64),
+            Self::Ge => Ok((lhs >= rhs) as u8 as f64),
+            Self::Eq => Ok((lhs == rhs) as u8 as f64),
+            Self::Ne => Ok((lhs != rhs) as u8 as f64),
             _ => Err(format!("operate should not be called on {}", self)),
         }
     }
 
+    pub fn operate_unary(self, a: f64) -> Result<f64, String> {
+        match self {
+            Self::USub => Ok(-a),
+            Self::BitNot => Ok(!self.integer(a)? as f64),
+            Self::Factorial => factorial(a),
+            Self::Percent => Ok(a / 100.0),
+            _ => Err(format!("operate_unary should not be called on {}", self)),
+        }
+    }
+
+    /// Bitwise operators only work on integers exactly representable as an f64
+    fn integer(self, v: f64) -> Result<i64, String> {
+        if v.fract() == 0.0 && v.abs() <= 2f64.powi(53) {
+            Ok(v as i64)
+        } else {
+            Err(format!("Operator {} expects integers, got {}", self, v))
+        }
+    }
+
+    /// How many operands the operator takes
+    pub fn arity(self) -> usize {
+        match self {
+            Self::USub | Self::BitNot | Self::Factorial | Self::Percent => 1,
+            _ => 2,
+        }
+    }
+
+    /// The operator a compound assignment applies, `Add` for `+=`
+    pub fn compound(self) -> Option<Operator> {
+        match self {
+            Self::AddAssign => Some(Self::Add),
+            Self::SubAssign => Some(Self::Sub),
+            Self::MulAssign => Some(Self::Mul),
+            Self::DivAssign => Some(Self::Div),
+            Self::FloorDivAssign => Some(Self::FloorDiv),
+            Self::ModAssign => Some(Self::Mod),
+            Self::PowAssign => Some(Self::Pow),
+            _ => None,
+        }
+    }
+
+    /// Postfix operators follow their operand and bind tighter than anything else, so `-3!` is `-(3!)`
+    pub fn is_postfix(self) -> bool {
+        matches!(self, Self::Factorial | Self::Percent)
+    }
+
     pub fn associativity(self) -> OperatorAssociativity {
         match self {
-            Self::Pow => OperatorAssociativity::Right,
+            Self::Pow | Self::USub | Self::BitNot => OperatorAssociativity::Right,
             _ => OperatorAssociativity::Left,
         }
     }
 
     pub fn precedence(self) -> u8 {
         match self {
-            Self::USub => 7,
-            Self::Pow => 6,
-            Self::Mul | Self::Div => 5,
-            Self::Add | Self::Sub => 4,
+            Self::Factorial | Self::Percent => 11,
+            Self::USub | Self::BitNot => 10,
+            Self::Pow => 9,
+            Self::ImplicitMul => 8,
+            Self::Mul | Self::Div | Self::FloorDiv | Self::Mod => 7,
+            Self::Add | Self::Sub | Self::Concat => 6,
+            Self::Shl | Self::Shr => 5,
+            Self::BitAnd => 4,
+            Self::BitXor => 3,
+            Self::BitOr => 2,
+            Self::Lt | Self::Gt | Self::Le | Self::Ge | Self::Eq | Self::Ne => 1,
             _ => 0,
         }
     }
@@ -88,19 +280,95 @@ impl Display for Operator {
             "{}",
             match self {
                 Self::Add => "+",
+                Self::Concat => "++",
                 Self::Sub => "-",
-                Self::Mul => "*",
+                Self::Mul | Self::ImplicitMul => "*",
                 Self::Div => "/",
+                Self::FloorDiv => "//",
+                Self::Mod => "%",
                 Self::Pow => "^",
                 Self::USub => "u",
+                Self::BitAnd => "&",
+                Self::BitOr => "|",
+                Self::BitXor => "xor",
+                Self::BitNot => "~",
+                Self::Shl => "<<",
+                Self::Shr => ">>",
+                Self::Lt => "<",
+                Self::Gt => ">",
+                Self::Le => "<=",
+                Self::Ge => ">=",
+                Self::Eq => "==",
+                Self::Ne => "!=",
+                Self::Factorial => "!",
+                Self::Percent => "%",
                 Self::Assign => "=",
+                Self::AddAssign => "+=",
+                Self::SubAssign => "-=",
+                Self::MulAssign => "*=",
+                Self::DivAssign => "/=",
+                Self::FloorDivAssign => "//=",
+                Self::ModAssign => "%=",
+                Self::PowAssign => "^=",
+                Self::Range => "..",
+                Self::Ellipsis => "...",
+                Self::Colon => ":",
+                Self::Comma => ",",
+                Self::Backslash => "\\",
+                Self::Arrow => "->",
+                Self::FatArrow => "=>",
                 Self::LParen => "(",
                 Self::RParen => ")",
+                Self::LBrace => "{",
+                Self::RBrace => "}",
             }
         )
     }
 }
 
+/// `x!`, extended to non-integers by `Γ(x + 1)`. Integers are multiplied out so they stay exact.
+fn factorial(x: f64) -> Result<f64, String> {
+    if x.fract() != 0.0 || x.is_infinite() {
+        return Ok(gamma(x + 1.0));
+    }
+    if x < 0.0 {
+        return Err(format!(
+            "Factorial of a negative integer {} is undefined",
+            x
+        ));
+    }
+    Ok((2..=(x.min(171.0) as u64)).fold(1.0, |acc, n| acc * n as f64))
+}
+
+/// Lanczos approximation (g = 7, n = 9), reflected below 0.5
+fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |acc, (i, c)| {
+            acc + c / (x + i as f64 + 1.0)
+        });
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
 pub enum OperatorAssociativity {
     Left,
     Right,
@@ -110,12 +378,55 @@ pub enum OperatorAssociativity {
 pub enum Keyword {
     Function,
     Variable,
+    /// `fn(x, y) => x * y`
+    Fn,
+    While,
+    For,
+    In,
+    Do,
+    End,
+    Break,
+    Continue,
+    If,
+    Then,
+    Else,
+    Return,
+    Import,
+    As,
+    Use,
+    Override,
+    Const,
+    With,
+    Match,
+    Try,
+    Catch,
 }
 impl Keyword {
     pub fn new(s: String) -> Option<Self> {
         match s.as_str() {
             "function" => Some(Self::Function),
             "var" => Some(Self::Variable),
+            "fn" => Some(Self::Fn),
+            "while" => Some(Self::While),
+            "for" => Some(Self::For),
+            "in" => Some(Self::In),
+            "do" => Some(Self::Do),
+            "end" => Some(Self::End),
+            "break" => Some(Self::Break),
+            "continue" => Some(Self::Continue),
+            "if" => Some(Self::If),
+            "then" => Some(Self::Then),
+            "else" => Some(Self::Else),
+            "return" => Some(Self::Return),
+            "import" => Some(Self::Import),
+            "as" => Some(Self::As),
+            "use" => Some(Self::Use),
+            "override" => Some(Self::Override),
+            "const" => Some(Self::Const),
+            "with" => Some(Self::With),
+            "match" => Some(Self::Match),
+            "try" => Some(Self::Try),
+            "catch" => Some(Self::Catch),
             _ => None,
         }
     }
@@ -125,9 +436,12 @@ impl Keyword {
 pub enum Token {
     Value(f64),
     Operator(Operator),
-    Function(Function),
     Identifier(Ident),
     Keyword(Keyword),
+    /// `"text"`, a string or the file name in an import. Escapes have been replaced.
+    Str(String),
+    /// A newline or `;`, ending a statement
+    Separator,
 }
 impl Token {
     pub fn new(s: String) -> Option<Self> {
@@ -137,10 +451,8 @@ impl Token {
             Some(Self::Operator(op))
         } else if let Ok(v) = s.parse::<f64>() {
             Some(Self::Value(v))
-        } else if let Some(ident) = Ident::new(s.clone()) {
-            Some(Self::Identifier(ident))
         } else {
-            None
+            Ident::new(s).map(Self::Identifier)
         }
     }
 }
@@ -152,100 +464,453 @@ impl Display for Token {
             match self {
                 Self::Value(v) => format!("{}", v),
                 Self::Operator(op) => format!("{}", op),
-                Self::Function(func) => format!("{}", func),
                 Self::Identifier(ident) => ident.internal_cloned(),
                 Self::Keyword(kw) => match kw {
                     Keyword::Function => "function".to_owned(),
                     Keyword::Variable => "var".to_owned(),
+                    Keyword::Fn => "fn".to_owned(),
+                    Keyword::While => "while".to_owned(),
+                    Keyword::For => "for".to_owned(),
+                    Keyword::In => "in".to_owned(),
+                    Keyword::Do => "do".to_owned(),
+                    Keyword::End => "end".to_owned(),
+                    Keyword::Break => "break".to_owned(),
+                    Keyword::Continue => "continue".to_owned(),
+                    Keyword::If => "if".to_owned(),
+                    Keyword::Then => "then".to_owned(),
+                    Keyword::Else => "else".to_owned(),
+                    Keyword::Return => "return".to_owned(),
+                    Keyword::Import => "import".to_owned(),
+                    Keyword::As => "as".to_owned(),
+                    Keyword::Use => "use".to_owned(),
+                    Keyword::Override => "override".to_owned(),
+                    Keyword::Const => "const".to_owned(),
+                    Keyword::With => "with".to_owned(),
+                    Keyword::Match => "match".to_owned(),
+                    Keyword::Try => "try".to_owned(),
+                    Keyword::Catch => "catch".to_owned(),
                 },
+                Self::Str(text) => quote(text),
+                Self::Separator => ";".to_owned(),
             }
         )
     }
 }
 
-pub fn tokenize(s: String) -> Result<Vec<Token>, String> {
-    let mut nbuffer = String::new();
-    let mut idbuffer = String::new();
-    let mut idx = 0;
-    let mut tokens = Vec::new();
-
-    while let Some(c) = s.chars().nth(idx) {
-        // check for unary operators (will always be first or directly following another operator (thanks greg!))
-        // unwrap or will make this evalute true if it's the first item in the expression
-        if c == '-' && nbuffer.is_empty() {
-            if let Some(&Token::Operator(_)) = tokens.last() {
-                tokens.push(Token::Operator(Operator::USub));
-                idx += 1;
-                continue;
-            }
+/// Where a token came from in the source. `start` and `end` are byte offsets, `line` and `col` start at 1
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+impl Display for LexError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+/// Splits source into tokens in a single pass. `#` starts a comment running to the end of the line,
+/// `/* */` comments can span lines. After an error the lexer skips the offending input and carries on,
+/// so every error in a file can be reported.
+pub struct Lexer<'a> {
+    src: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    col: usize,
+    /// Whether a `-` here negates rather than subtracts, i.e. it's first or follows another operator
+    unary: bool,
+    /// Calculator mode, where a `%` that isn't followed by an operand means percent rather than modulo
+    /// and juxtaposition is multiplication
+    calculator: bool,
+    /// End of the last token if it can be the left side of an implicit multiplication
+    product_end: Option<usize>,
+    /// The token following an implicit multiplication, returned on the next call
+    pending: Option<(Token, Span)>,
+}
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Self {
+        Lexer {
+            src,
+            chars: src.char_indices().peekable(),
+            line: 1,
+            col: 1,
+            unary: true,
+            calculator: false,
+            product_end: None,
+            pending: None,
+        }
+    }
+
+    pub fn calculator(mut self, calculator: bool) -> Self {
+        self.calculator = calculator;
+        self
+    }
+
+    /// Whether the next token after the character at `pos` starts an operand
+    fn operand_follows(&self, pos: usize) -> bool {
+        let mut rest = self.src[pos..]
+            .chars()
+            .skip(1)
+            .skip_while(|c| c.is_whitespace() && *c != '\n');
+        rest.next().is_some_and(|c| {
+            c.is_ascii_digit() || is_ident_start(c) || matches!(c, '.' | '(' | '~' | '√' | 'π')
+        })
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn pos(&mut self) -> usize {
+        self.chars.peek().map_or(self.src.len(), |(idx, _)| *idx)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn bump_while(&mut self, pred: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&pred) {
+            self.bump();
         }
-        // put numbers into the buffer if idbuffer is empty
-        else if "1234567890.".contains(c) && idbuffer.is_empty() {
-            if c == '.' && nbuffer.contains('.') {
+    }
+
+    /// Lexes a numeric literal starting at `start`: decimal with an optional fraction and exponent, or an integer
+    /// in hexadecimal (`0x`), octal (`0o`) or binary (`0b`). `_` can separate digits anywhere in either.
+    fn number(&mut self, start: usize) -> Result<f64, String> {
+        let (radix, name) = match self.src.get(start..start + 2) {
+            Some("0x") | Some("0X") => (16, "hexadecimal"),
+            Some("0o") | Some("0O") => (8, "octal"),
+            Some("0b") | Some("0B") => (2, "binary"),
+            _ => (10, "decimal"),
+        };
+
+        if radix != 10 {
+            self.bump();
+            self.bump();
+            let from = self.pos();
+            self.bump_while(|c| c.is_ascii_alphanumeric() || c == '_');
+            if self.peek() == Some('.') && !self.src[self.pos()..].starts_with("..") {
+                self.bump_while(|c| c.is_alphanumeric() || c == '.' || c == '_');
                 return Err(format!(
-                    "Encountered unexpected '.' when parsing {}",
-                    nbuffer
+                    "The {} literal {} can't have a fraction",
+                    name,
+                    &self.src[start..self.pos()]
                 ));
-            } else {
-                nbuffer.push(c);
             }
+            let text = &self.src[start..self.pos()];
+            let digits = &self.src[from..self.pos()];
+
+            if digits.is_empty() {
+                return Err(format!("Missing digits after {}", text));
+            }
+            if let Some(bad) = digits.chars().find(|c| *c != '_' && !c.is_digit(radix)) {
+                return Err(format!(
+                    "Invalid digit '{}' in {} literal {}",
+                    bad, name, text
+                ));
+            }
+            check_separators(digits, text)?;
+            return u64::from_str_radix(&digits.replace('_', ""), radix)
+                .map(|v| v as f64)
+                .map_err(|_| format!("The {} literal {} is too large", name, text));
         }
-        // make a token from the buffer, add it, clear the buffer
-        else if !nbuffer.is_empty() {
-            if let Some(tok) = Token::new(nbuffer) {
-                tokens.push(tok)
-            } else {
-                unreachable!()
+
+        self.bump_while(|c| c.is_ascii_digit() || c == '_');
+        if self.peek() == Some('.') && !self.src[self.pos()..].starts_with("..") {
+            self.bump();
+            self.bump_while(|c| c.is_ascii_digit() || c == '_');
+        }
+
+        // Only an exponent if digits follow. In calculator mode `2e` is left alone so it can mean 2 times e,
+        // otherwise it's a mistake, as is anything written directly after the exponent.
+        let mut exp = self.src[self.pos()..].chars();
+        if matches!(exp.next(), Some('e') | Some('E')) {
+            let has_digits = match exp.next() {
+                Some('+') | Some('-') => exp.next().is_some_and(|c| c.is_ascii_digit()),
+                Some(c) => c.is_ascii_digit(),
+                None => false,
+            };
+            if has_digits || !self.calculator {
+                self.bump();
+                if matches!(self.peek(), Some('+') | Some('-')) {
+                    self.bump();
+                }
+                self.bump_while(|c| c.is_ascii_digit() || c == '_');
+                if !has_digits || self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    self.bump_while(|c| c.is_alphanumeric() || c == '.' || c == '_');
+                    return Err(format!(
+                        "Malformed exponent in number {}",
+                        &self.src[start..self.pos()]
+                    ));
+                }
             }
-            nbuffer = String::new();
-            idx -= 1;
         }
-        // check for operators, parens
-        else if let Some(op) = Operator::new(format!("{}", c)) {
-            tokens.push(Token::Operator(op));
+
+        if self.peek() == Some('.') && !self.src[self.pos()..].starts_with("..") {
+            self.bump_while(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_');
+            return Err(format!(
+                "The number {} has more than one '.'",
+                &self.src[start..self.pos()]
+            ));
         }
-        // put characters in the identifier buffer
-        else if c.is_ascii_alphabetic() {
-            idbuffer.push(c);
+
+        let text = &self.src[start..self.pos()];
+        for group in text.split(['.', 'e', 'E', '+', '-']) {
+            check_separators(group, text)?;
         }
-        // Check for keywords and identifiers
-        else if !idbuffer.is_empty() {
-            if let Some(tok) = Token::new(idbuffer.clone()) {
-                tokens.push(tok);
-                idbuffer = String::new();
-            } else {
-                return Err(format!(
-                    "Something went wrong! {} is not a valid identifier",
-                    idbuffer
-                ));
+        text.replace('_', "")
+            .parse::<f64>()
+            .map_err(|_| format!("Invalid number {}", text))
+    }
+
+    /// Lexes a string literal up to its closing quote, which has to be on the same line. `\"`, `\\`, `\n` and `\t`
+    /// are the escapes.
+    fn string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut text = String::new();
+        let mut unknown = None;
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err("Unterminated string".to_owned()),
+                Some('"') => break,
+                Some('\\') => {
+                    self.bump();
+                    match self.peek() {
+                        None | Some('\n') => continue,
+                        Some('"') => text.push('"'),
+                        Some('\\') => text.push('\\'),
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        // Carry on to the closing quote, so the rest of the string isn't lexed as code
+                        Some(c) => {
+                            unknown.get_or_insert(c);
+                        }
+                    }
+                }
+                Some(c) => text.push(c),
             }
-            idx -= 1;
+            self.bump();
+        }
+        self.bump();
+        match unknown {
+            Some(c) => Err(format!("Unknown escape '\\{}' in string", c)),
+            None => Ok(text),
         }
-        // increment the pointer
-        idx += 1;
     }
 
-    // check the buffers for missed tokens
-    if !idbuffer.is_empty() {
-        if let Some(tok) = Token::new(idbuffer.clone()) {
-            tokens.push(tok);
-        } else {
-            return Err(format!(
-                "Something went wrong! {} is not a valid identifier",
-                idbuffer
-            ));
+    fn error(&self, message: String, span: Span) -> Option<Result<(Token, Span), LexError>> {
+        Some(Err(LexError { message, span }))
+    }
+}
+/// `text` as a string literal, the inverse of what the lexer does
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
+}
 
-    if !nbuffer.is_empty() {
-        if let Some(tok) = Token::new(nbuffer) {
-            tokens.push(tok)
-        } else {
-            unreachable!()
+/// Mathematical symbols accepted in place of their ASCII spelling
+fn alias(c: char) -> Option<Token> {
+    match c {
+        '×' => Some(Token::Operator(Operator::Mul)),
+        '÷' => Some(Token::Operator(Operator::Div)),
+        '−' => Some(Token::Operator(Operator::Sub)),
+        '√' => Ident::new("sqrt".to_owned()).map(Token::Identifier),
+        'π' => Ident::new("pi".to_owned()).map(Token::Identifier),
+        _ => None,
+    }
+}
+
+/// `_` is only allowed between two digits
+fn check_separators(digits: &str, text: &str) -> Result<(), String> {
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        Err(format!("Misplaced '_' in number {}", text))
+    } else {
+        Ok(())
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<(Token, Span), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pending) = self.pending.take() {
+            return Some(Ok(pending));
+        }
+        loop {
+            self.bump_while(|c| c.is_whitespace() && c != '\n');
+
+            let mut span = Span {
+                start: self.pos(),
+                end: self.pos(),
+                line: self.line,
+                col: self.col,
+            };
+            let c = self.peek()?;
+            let rest = &self.src[span.start..];
+
+            let tok = if c == '\n' || c == ';' {
+                self.bump();
+                Token::Separator
+            } else if c == '#' {
+                self.bump_while(|c| c != '\n');
+                continue;
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                // After the opener, so `/*/` doesn't close itself
+                match comment.find("*/") {
+                    Some(len) => {
+                        while self.pos() < span.start + len + 4 {
+                            self.bump();
+                        }
+                        continue;
+                    }
+                    None => {
+                        self.bump_while(|_| true);
+                        return self.error("Unterminated block comment".to_owned(), span);
+                    }
+                }
+            } else if c.is_ascii_digit()
+                || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+            {
+                let val = self.number(span.start);
+                span.end = self.pos();
+                match val {
+                    Ok(v) => Token::Value(v),
+                    Err(message) => return self.error(message, span),
+                }
+            } else if let Some(alias) = alias(c) {
+                self.bump();
+                match alias {
+                    Token::Operator(Operator::Sub) if self.unary => Token::Operator(Operator::USub),
+                    tok => tok,
+                }
+            } else if c == '"' {
+                let text = self.string();
+                span.end = self.pos();
+                match text {
+                    Ok(text) => Token::Str(text),
+                    Err(message) => return self.error(message, span),
+                }
+            } else if is_ident_start(c) {
+                self.bump_while(UnicodeXID::is_xid_continue);
+                // `stats.mean`, a name inside a namespace
+                while self.peek() == Some('.')
+                    && self.src[self.pos() + 1..].starts_with(is_ident_start)
+                {
+                    self.bump();
+                    self.bump_while(UnicodeXID::is_xid_continue);
+                }
+                match &self.src[span.start..self.pos()] {
+                    "inf" => Token::Value(f64::INFINITY),
+                    "nan" => Token::Value(f64::NAN),
+                    // Not `Token::new`, which would also read `NaN` or `Infinity` as numbers
+                    text => Keyword::new(text.to_owned())
+                        .map(Token::Keyword)
+                        .or_else(|| Operator::new(text.to_owned()).map(Token::Operator))
+                        .or_else(|| Ident::new(text.to_owned()).map(Token::Identifier))
+                        .unwrap(),
+                }
+            } else if let Some((len, op)) = [3, 2].iter().find_map(|len| {
+                let op = rest.get(..*len).and_then(|s| Operator::new(s.to_owned()))?;
+                Some((*len, op))
+            }) {
+                for _ in 0..len {
+                    self.bump();
+                }
+                Token::Operator(op)
+            } else if c == '!' {
+                self.bump();
+                if self.unary {
+                    span.end = self.pos();
+                    return self.error("Expected a value before '!'".to_owned(), span);
+                }
+                Token::Operator(Operator::Factorial)
+            } else if c == '%'
+                && self.calculator
+                && !self.unary
+                && !self.operand_follows(span.start)
+            {
+                self.bump();
+                Token::Operator(Operator::Percent)
+            } else if c == '-' && self.unary {
+                self.bump();
+                Token::Operator(Operator::USub)
+            } else if let Some(op) = Operator::new(c.to_string()) {
+                self.bump();
+                Token::Operator(op)
+            } else {
+                self.bump();
+                span.end = self.pos();
+                return self.error(format!("Unexpected character '{}'", c), span);
+            };
+
+            span.end = self.pos();
+            self.unary = match tok {
+                Token::Operator(op) => op != Operator::RParen && !op.is_postfix(),
+                Token::Keyword(_) | Token::Separator => true,
+                _ => false,
+            };
+
+            // A number, `)` or postfix operator directly followed by an identifier or `(`, with no space between
+            let product = self.product_end == Some(span.start)
+                && matches!(
+                    tok,
+                    Token::Identifier(_) | Token::Operator(Operator::LParen)
+                );
+            self.product_end = match tok {
+                Token::Value(_) | Token::Operator(Operator::RParen) => Some(span.end),
+                Token::Operator(op) if op.is_postfix() => Some(span.end),
+                _ => None,
+            }
+            .filter(|_| self.calculator);
+
+            if product {
+                self.pending = Some((tok, span));
+                let at = Span {
+                    end: span.start,
+                    ..span
+                };
+                return Some(Ok((Token::Operator(Operator::ImplicitMul), at)));
+            }
+            return Some(Ok((tok, span)));
         }
     }
+}
 
-    Ok(tokens)
+pub fn tokenize(s: String) -> Result<Vec<Token>, String> {
+    Lexer::new(&s)
+        .map(|res| res.map(|(tok, _)| tok).map_err(|e| e.to_string()))
+        .collect()
 }
 
 #[test]
@@ -338,3 +1003,328 @@ fn test_tokenize() {
         expected
     );
 }
+
+#[test]
+fn test_tokenize_adjacent() {
+    assert_eq!(
+        tokenize("var n: int = (a)-1".to_owned()).unwrap(),
+        vec![
+            Token::Keyword(Keyword::Variable),
+            Token::new("n".to_owned()).unwrap(),
+            Token::Operator(Operator::Colon),
+            Token::new("int".to_owned()).unwrap(),
+            Token::Operator(Operator::Assign),
+            Token::Operator(Operator::LParen),
+            Token::new("a".to_owned()).unwrap(),
+            Token::Operator(Operator::RParen),
+            Token::Operator(Operator::Sub),
+            Token::Value(1.0),
+        ]
+    );
+    assert_eq!(
+        tokenize("-2 * -x".to_owned()).unwrap(),
+        vec![
+            Token::Operator(Operator::USub),
+            Token::Value(2.0),
+            Token::Operator(Operator::Mul),
+            Token::Operator(Operator::USub),
+            Token::new("x".to_owned()).unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn test_lexer() {
+    let lexed = Lexer::new("var x = 2 # the answer\n/* multi\nline */ x*-3")
+        .collect::<Result<Vec<(Token, Span)>, LexError>>()
+        .unwrap();
+    let spans = lexed
+        .iter()
+        .map(|(tok, span)| {
+            (
+                format!("{}", tok),
+                span.line,
+                span.col,
+                span.end - span.start,
+            )
+        })
+        .collect::<Vec<(String, usize, usize, usize)>>();
+    assert_eq!(
+        spans,
+        vec![
+            ("var".to_owned(), 1, 1, 3),
+            ("x".to_owned(), 1, 5, 1),
+            ("=".to_owned(), 1, 7, 1),
+            ("2".to_owned(), 1, 9, 1),
+            (";".to_owned(), 1, 23, 1),
+            ("x".to_owned(), 3, 9, 1),
+            ("*".to_owned(), 3, 10, 1),
+            ("u".to_owned(), 3, 11, 1),
+            ("3".to_owned(), 3, 12, 1),
+        ]
+    );
+
+    assert_eq!(
+        tokenize("10 $ 3".to_owned()),
+        Err("Unexpected character '$' at 1:4".to_owned())
+    );
+    assert_eq!(
+        tokenize("1 + 2 /* oops".to_owned()),
+        Err("Unterminated block comment at 1:7".to_owned())
+    );
+    assert_eq!(
+        tokenize("1 /*/ 2 */ + 3".to_owned()).map(|toks| toks.len()),
+        Ok(3)
+    );
+    assert_eq!(
+        tokenize("1 /**/ + /*/ 2 **/ 3".to_owned()).map(|toks| toks.len()),
+        Ok(3)
+    );
+    assert_eq!(
+        tokenize("1 /*/ 2".to_owned()),
+        Err("Unterminated block comment at 1:3".to_owned())
+    );
+    assert_eq!(
+        tokenize("1.2.3".to_owned()),
+        Err("The number 1.2.3 has more than one '.' at 1:1".to_owned())
+    );
+
+    // Errors don't stop the lexer
+    let errors = Lexer::new("1 @ 2 $ 3")
+        .filter_map(Result::err)
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(
+        errors,
+        vec![
+            "Unexpected character '@' at 1:3".to_owned(),
+            "Unexpected character '$' at 1:7".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn test_statement_tokens() {
+    use Operator::*;
+    let ops = |s: &str| {
+        tokenize(s.to_owned())
+            .unwrap()
+            .into_iter()
+            .filter_map(|tok| match tok {
+                Token::Operator(op) => Some(op),
+                _ => None,
+            })
+            .collect::<Vec<Operator>>()
+    };
+
+    assert_eq!(
+        ops("a += b -= c *= d /= e //= f %= g ^= h = i"),
+        vec![
+            AddAssign,
+            SubAssign,
+            MulAssign,
+            DivAssign,
+            FloorDivAssign,
+            ModAssign,
+            PowAssign,
+            Assign
+        ]
+    );
+    assert_eq!(
+        ops("a < b > c <= d >= e == f != g << h"),
+        vec![Lt, Gt, Le, Ge, Eq, Ne, Shl]
+    );
+    assert_eq!(ops("3!= 4 3! - 4"), vec![Ne, Factorial, Sub]);
+
+    // A range doesn't make a decimal point
+    assert_eq!(
+        tokenize("for i in 1..10".to_owned()).unwrap()[3..],
+        [
+            Token::Value(1.0),
+            Token::Operator(Range),
+            Token::Value(10.0)
+        ]
+    );
+
+    // A separator starts a new operand, so `-` after it negates
+    assert_eq!(
+        tokenize("x; -1\n-2".to_owned()).unwrap()[1..],
+        [
+            Token::Separator,
+            Token::Operator(USub),
+            Token::Value(1.0),
+            Token::Separator,
+            Token::Operator(USub),
+            Token::Value(2.0),
+        ]
+    );
+
+    // Names inside a namespace are a single identifier, a range after a name isn't
+    assert_eq!(
+        tokenize("import \"lib/stats.mini\" as s; s.mean a..b".to_owned()).unwrap(),
+        [
+            Token::Keyword(Keyword::Import),
+            Token::Str("lib/stats.mini".to_owned()),
+            Token::Keyword(Keyword::As),
+            Token::Identifier(Ident::new("s".to_owned()).unwrap()),
+            Token::Separator,
+            Token::Identifier(Ident::new("s.mean".to_owned()).unwrap()),
+            Token::Identifier(Ident::new("a".to_owned()).unwrap()),
+            Token::Operator(Range),
+            Token::Identifier(Ident::new("b".to_owned()).unwrap()),
+        ]
+    );
+    assert!(tokenize("import \"stats".to_owned()).is_err());
+
+    // Escapes are replaced, and an unknown one doesn't end the string early
+    assert_eq!(
+        tokenize(r#""say \"hi\"\t\\" ++ s"#.to_owned()).unwrap()[..2],
+        [
+            Token::Str("say \"hi\"\t\\".to_owned()),
+            Token::Operator(Concat)
+        ]
+    );
+    assert_eq!(quote("say \"hi\"\n"), r#""say \"hi\"\n""#);
+    let errors = Lexer::new("\"a\\qb\" + 1\n\"open")
+        .filter_map(Result::err)
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(
+        errors,
+        [
+            "Unknown escape '\\q' in string at 1:1",
+            "Unterminated string at 2:1"
+        ]
+    );
+}
+
+#[test]
+fn test_number_literals() {
+    let lex = |s: &str| tokenize(s.to_owned());
+    let values = |s: &str| {
+        lex(s)
+            .unwrap()
+            .into_iter()
+            .map(|tok| match tok {
+                Token::Value(v) => v,
+                _ => panic!("{} is not a value", tok),
+            })
+            .collect::<Vec<f64>>()
+    };
+
+    assert_eq!(
+        values("1e-9 6.02e23 2E+3 .5 5."),
+        vec![1e-9, 6.02e23, 2000.0, 0.5, 5.0]
+    );
+    assert_eq!(
+        values("0xFF 0o17 0b1010 0xf_f"),
+        vec![255.0, 15.0, 10.0, 255.0]
+    );
+    assert_eq!(values("1_000_000 1_0.0_1e1_0"), vec![1_000_000.0, 10.01e10]);
+    assert_eq!(values("inf")[0], f64::INFINITY);
+    assert!(values("nan")[0].is_nan());
+    // Only those two spellings
+    for text in ["NaN", "Infinity", "infinity", "INF"] {
+        assert_eq!(
+            lex(text),
+            Ok(vec![Token::Identifier(
+                Ident::new(text.to_owned()).unwrap()
+            )])
+        );
+    }
+    assert_eq!(lex("var NaN = 3 xor 1").map(|toks| toks.len()), Ok(6));
+
+    // In calculator mode `2e` is 2 followed by the identifier e
+    assert_eq!(
+        Lexer::new("2e")
+            .calculator(true)
+            .map(|res| res.map(|(tok, _)| tok))
+            .collect::<Result<Vec<Token>, LexError>>()
+            .unwrap(),
+        vec![
+            Token::Value(2.0),
+            Token::Operator(Operator::ImplicitMul),
+            Token::new("e".to_owned()).unwrap()
+        ]
+    );
+    for (text, e) in [
+        ("2e", "Malformed exponent in number 2e at 1:1"),
+        ("x = 1.5e", "Malformed exponent in number 1.5e at 1:5"),
+        ("1e+", "Malformed exponent in number 1e+ at 1:1"),
+        ("1e_5", "Malformed exponent in number 1e_5 at 1:1"),
+        ("1e5e5", "Malformed exponent in number 1e5e5 at 1:1"),
+        ("1e-3x", "Malformed exponent in number 1e-3x at 1:1"),
+        (
+            "0x1.5",
+            "The hexadecimal literal 0x1.5 can't have a fraction at 1:1",
+        ),
+    ] {
+        assert_eq!(lex(text), Err(e.to_owned()), "{}", text);
+    }
+
+    assert_eq!(lex("0x"), Err("Missing digits after 0x at 1:1".to_owned()));
+    assert_eq!(
+        lex("0b102"),
+        Err("Invalid digit '2' in binary literal 0b102 at 1:1".to_owned())
+    );
+    assert_eq!(
+        lex("1 + 0xfg"),
+        Err("Invalid digit 'g' in hexadecimal literal 0xfg at 1:5".to_owned())
+    );
+    assert_eq!(
+        lex("1__000"),
+        Err("Misplaced '_' in number 1__000 at 1:1".to_owned())
+    );
+    assert_eq!(
+        lex("100_"),
+        Err("Misplaced '_' in number 100_ at 1:1".to_owned())
+    );
+    assert_eq!(
+        lex("1._5"),
+        Err("Misplaced '_' in number 1._5 at 1:1".to_owned())
+    );
+    assert_eq!(
+        lex("0x1_0000_0000_0000_0000"),
+        Err("The hexadecimal literal 0x1_0000_0000_0000_0000 is too large at 1:1".to_owned())
+    );
+    assert_eq!(lex("."), Err("Unexpected character '.' at 1:1".to_owned()));
+}
+
+#[test]
+fn test_unicode_identifiers() {
+    let ident = |s: &str| Token::Identifier(Ident::new(s.to_owned()).unwrap());
+
+    assert_eq!(
+        tokenize("x1 + rate_2 * Δt - θ".to_owned()).unwrap(),
+        vec![
+            ident("x1"),
+            Token::Operator(Operator::Add),
+            ident("rate_2"),
+            Token::Operator(Operator::Mul),
+            ident("Δt"),
+            Token::Operator(Operator::Sub),
+            ident("θ"),
+        ]
+    );
+    assert_eq!(
+        tokenize("−2 × π ÷ √4 − _x".to_owned()).unwrap(),
+        vec![
+            Token::Operator(Operator::USub),
+            Token::Value(2.0),
+            Token::Operator(Operator::Mul),
+            ident("pi"),
+            Token::Operator(Operator::Div),
+            ident("sqrt"),
+            Token::Value(4.0),
+            Token::Operator(Operator::Sub),
+            ident("_x"),
+        ]
+    );
+
+    assert!(Ident::new("1x".to_owned()).is_none());
+    assert!(Ident::new("a-b".to_owned()).is_none());
+    assert_eq!(
+        tokenize("a ∑ b".to_owned()),
+        Err("Unexpected character '∑' at 1:3".to_owned())
+    );
+}
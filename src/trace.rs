@@ -0,0 +1,125 @@
+use {
+    crate::{
+        token::{Ident, Operator},
+        value::Value,
+    },
+    std::fmt::{self, Display, Formatter},
+};
+
+/// How a value was computed, recorded while `Context::tracing` is set
+#[derive(PartialEq, Debug, Clone)]
+pub enum Trace {
+    Value(Value),
+    Operator {
+        op: Operator,
+        operands: Vec<Trace>,
+        result: Value,
+    },
+    Call {
+        ident: Ident,
+        args: Vec<(Ident, Trace)>,
+        /// The evaluation of the function's body, `None` for builtins
+        body: Option<Box<Trace>>,
+        result: Value,
+    },
+    /// The statements of a block-bodied function, in the order they ran
+    Block {
+        steps: Vec<Trace>,
+        result: Value,
+    },
+}
+impl Trace {
+    pub fn value(&self) -> Value {
+        match self {
+            Self::Value(v) => v.clone(),
+            Self::Operator { result, .. }
+            | Self::Call { result, .. }
+            | Self::Block { result, .. } => result.clone(),
+        }
+    }
+
+    /// How the value appears as an operand, percentages keep their `%` so `a + b%` reads as written
+    fn operand(&self) -> String {
+        match self {
+            Self::Operator {
+                op: Operator::Percent,
+                operands,
+                ..
+            } => format!("{}%", operands[0].value()),
+            _ => format!("{}", self.value()),
+        }
+    }
+
+    fn render(&self, depth: usize, f: &mut Formatter) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        match self {
+            Self::Value(v) => writeln!(f, "{}{}", indent, v),
+            Self::Operator {
+                op,
+                operands,
+                result,
+            } => {
+                match operands.as_slice() {
+                    [a] if *op == Operator::USub => {
+                        writeln!(f, "{}{} = -{}", indent, result, a.value())?
+                    }
+                    [a] if op.is_postfix() => {
+                        writeln!(f, "{}{} = {}{}", indent, result, a.value(), op)?
+                    }
+                    [a] => writeln!(f, "{}{} = {}{}", indent, result, op, a.value())?,
+                    [a, b] => writeln!(
+                        f,
+                        "{}{} = {} {} {}",
+                        indent,
+                        result,
+                        a.operand(),
+                        op,
+                        b.operand()
+                    )?,
+                    _ => unreachable!(),
+                }
+                operands
+                    .iter()
+                    .try_for_each(|t| t.render_child(depth + 1, f))
+            }
+            Self::Call {
+                ident,
+                args,
+                body,
+                result,
+            } => {
+                writeln!(
+                    f,
+                    "{}{} = {}({})",
+                    indent,
+                    result,
+                    ident,
+                    args.iter()
+                        .map(|(arg, t)| format!("{} = {}", arg, t.value()))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )?;
+                args.iter()
+                    .try_for_each(|(_, t)| t.render_child(depth + 1, f))?;
+                match body {
+                    Some(body) => body.render_child(depth + 1, f),
+                    None => Ok(()),
+                }
+            }
+            Self::Block { steps, .. } => steps.iter().try_for_each(|t| t.render_child(depth, f)),
+        }
+    }
+
+    /// Plain values are already shown by their parent
+    fn render_child(&self, depth: usize, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Value(_) => Ok(()),
+            _ => self.render(depth, f),
+        }
+    }
+}
+impl Display for Trace {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.render(0, f)
+    }
+}
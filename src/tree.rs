@@ -1 +1,1807 @@
+use {
+    crate::{
+        function::Function,
+        token::{quote, Ident, Keyword, Operator, OperatorAssociativity, Span, Token},
+        types::Type,
+        value::Value,
+    },
+    std::{
+        collections::HashMap,
+        fmt::{self, Display, Formatter},
+        ops::Range,
+        rc::Rc,
+    },
+};
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum Expr {
+    Num(f64),
+    /// `"text"`, with escapes already replaced
+    Str(String),
+    Var(Ident, Span),
+    /// Prefix and postfix operators
+    Unary {
+        op: Operator,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: Operator,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `callee args...`, calls the callee once enough arguments are given
+    App {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    /// `callee(args..., name: arg...)`, calls the callee right away, filling in defaults for missing arguments
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        named: Vec<(Ident, Expr)>,
+        span: Span,
+    },
+    Lambda {
+        params: Vec<Ident>,
+        body: Rc<Expr>,
+    },
+    /// The body of a function declared with `{ }`, evaluates to the value it returns
+    Block(Vec<Stmt>),
+    /// `{ rate: 0.05, term: 30 }`
+    Record(Vec<(Ident, Expr)>),
+    /// `{ record with rate: 0.04 }`, a copy of the record with some of its fields replaced
+    With {
+        record: Box<Expr>,
+        fields: Vec<(Ident, Expr)>,
+    },
+    /// `(q, r)`, two or more values kept together
+    Tuple(Vec<Expr>),
+    /// `match value { pattern => expr, ... }`, the first arm matching the value gives the result
+    Match {
+        value: Box<Expr>,
+        arms: Vec<Arm>,
+        span: Span,
+    },
+    /// `try body catch e => fallback`, the fallback is evaluated with the error as `e` if the body fails
+    Try {
+        body: Box<Expr>,
+        ident: Ident,
+        fallback: Box<Expr>,
+    },
+}
+impl Expr {
+    /// How tightly the expression holds together when printed, anything looser than its surroundings needs parentheses
+    fn binding(&self) -> u8 {
+        match self {
+            Self::Num(_) | Self::Str(_) | Self::Var(_, _) | Self::Call { .. } => u8::MAX,
+            Self::Unary { op, .. } => op.precedence() * 2,
+            // Between prefix and postfix operators, `-f x!` is `-(f (x!))`
+            Self::App { .. } => Operator::USub.precedence() * 2 + 1,
+            Self::Binary { op, .. } => op.precedence() * 2,
+            Self::Lambda { .. } | Self::Try { .. } => 0,
+            Self::Block(_)
+            | Self::Record(_)
+            | Self::With { .. }
+            | Self::Tuple(_)
+            | Self::Match { .. } => u8::MAX,
+        }
+    }
+
+    fn wrapped(&self, parens: bool) -> String {
+        if parens {
+            format!("({})", self)
+        } else {
+            format!("{}", self)
+        }
+    }
+}
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Num(v) => write!(f, "{}", v),
+            Self::Str(text) => write!(f, "{}", quote(text)),
+            Self::Var(ident, _) => write!(f, "{}", ident),
+            Self::Unary { op, operand } if op.is_postfix() => write!(
+                f,
+                "{}{}",
+                operand.wrapped(operand.binding() < self.binding()),
+                op
+            ),
+            Self::Unary { op, operand } => write!(
+                f,
+                "{}{}",
+                if *op == Operator::USub {
+                    "-".to_owned()
+                } else {
+                    format!("{}", op)
+                },
+                operand.wrapped(operand.binding() < self.binding())
+            ),
+            Self::Binary { op, lhs, rhs } => {
+                let (left, right) = match op.associativity() {
+                    OperatorAssociativity::Left => (false, true),
+                    OperatorAssociativity::Right => (true, false),
+                };
+                write!(
+                    f,
+                    "{} {} {}",
+                    lhs.wrapped(
+                        lhs.binding() < self.binding() || (left && lhs.binding() == self.binding())
+                    ),
+                    op,
+                    rhs.wrapped(
+                        rhs.binding() < self.binding()
+                            || (right && rhs.binding() == self.binding())
+                    )
+                )
+            }
+            Self::App { callee, args, .. } => {
+                let postfix = Operator::Factorial.precedence() * 2;
+                write!(f, "{}", callee.wrapped(callee.binding() < postfix))?;
+                for arg in args {
+                    write!(f, " {}", arg.wrapped(arg.binding() < postfix))?;
+                }
+                Ok(())
+            }
+            Self::Call {
+                callee,
+                args,
+                named,
+                ..
+            } => write!(
+                f,
+                "{}({})",
+                callee,
+                args.iter()
+                    .map(|arg| arg.to_string())
+                    .chain(
+                        named
+                            .iter()
+                            .map(|(ident, arg)| format!("{}: {}", ident, arg))
+                    )
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Lambda { params, body } => write!(
+                f,
+                "\\{} -> {}",
+                params
+                    .iter()
+                    .map(|param| param.internal_cloned())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+                body
+            ),
+            Self::Block(body) if body.is_empty() => write!(f, "{{}}"),
+            Self::Block(body) => write!(f, "{{ {} }}", block(body)),
+            Self::Record(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Self::Record(fields) => write!(f, "{{ {} }}", assignments(fields)),
+            Self::With { record, fields } => {
+                write!(f, "{{ {} with {} }}", record, assignments(fields))
+            }
+            Self::Tuple(items) => write!(
+                f,
+                "({})",
+                items
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Match { value, arms, .. } => write!(
+                f,
+                "match {} {{ {} }}",
+                value,
+                arms.iter()
+                    .map(|arm| arm.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Try {
+                body,
+                ident,
+                fallback,
+            } => write!(f, "try {} catch {} => {}", body, ident, fallback),
+        }
+    }
+}
+
+/// `pattern if guard => body` in a `match`
+#[derive(PartialEq, Debug, Clone)]
+pub struct Arm {
+    pub pattern: Pattern,
+    pub guard: Option<Expr>,
+    pub body: Expr,
+}
+impl Arm {
+    /// Whether the arm matches every value, so no arm after it is ever reached
+    pub fn is_total(&self) -> bool {
+        self.guard.is_none() && !self.pattern.is_refutable()
+    }
+}
+impl Display for Arm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.guard {
+            Some(guard) => write!(f, "{} if {} => {}", self.pattern, guard, self.body),
+            None => write!(f, "{} => {}", self.pattern, self.body),
+        }
+    }
+}
+
+/// What a value is bound to: a name, a tuple taken apart into its parts, `(q, r)`, a number the value has to
+/// equal or `_`, which matches anything
+#[derive(PartialEq, Debug, Clone)]
+pub enum Pattern {
+    Bind(Ident),
+    Tuple(Vec<Pattern>),
+    Num(f64),
+    Wildcard,
+}
+impl Pattern {
+    /// The names it binds, from left to right
+    pub fn idents(&self) -> Vec<&Ident> {
+        match self {
+            Self::Bind(ident) => vec![ident],
+            Self::Tuple(parts) => parts.iter().flat_map(Pattern::idents).collect(),
+            Self::Num(_) | Self::Wildcard => Vec::new(),
+        }
+    }
+
+    /// Whether some values of the right type don't match, like `0`
+    pub fn is_refutable(&self) -> bool {
+        match self {
+            Self::Num(_) => true,
+            Self::Tuple(parts) => parts.iter().any(Pattern::is_refutable),
+            Self::Bind(_) | Self::Wildcard => false,
+        }
+    }
+
+    /// Like `destructure`, but a value that doesn't match binds nothing and gives `false`
+    pub fn matches(&self, val: &Value, out: &mut Vec<(Ident, Value)>) -> bool {
+        let mut vars = Vec::new();
+        let matched = self.destructure(val, &mut vars).is_ok();
+        if matched {
+            out.extend(vars);
+        }
+        matched
+    }
+
+    /// Binds the names to the parts of `val`, adding them to `out`
+    pub fn destructure(&self, val: &Value, out: &mut Vec<(Ident, Value)>) -> Result<(), String> {
+        match (self, val) {
+            (Self::Bind(ident), val) => out.push((ident.clone(), val.clone())),
+            (Self::Wildcard, _) => (),
+            (Self::Num(v), Value::Num(val)) if v == val => (),
+            (Self::Num(_), val) => return Err(format!("{} doesn't match {}", val, self)),
+            (Self::Tuple(parts), Value::Tuple(items)) if parts.len() == items.len() => {
+                for (part, item) in parts.iter().zip(items) {
+                    part.destructure(item, out)?;
+                }
+            }
+            (Self::Tuple(parts), val) => {
+                return Err(format!(
+                    "Expected a tuple of {} values to take apart into {}, got {}",
+                    parts.len(),
+                    self,
+                    val
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Bind(ident) => write!(f, "{}", ident),
+            Self::Num(v) => write!(f, "{}", v),
+            Self::Wildcard => write!(f, "_"),
+            Self::Tuple(parts) => write!(
+                f,
+                "({})",
+                parts
+                    .iter()
+                    .map(|part| part.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Statements are separated by newlines or `;`
+#[derive(PartialEq, Debug, Clone)]
+pub enum Stmt {
+    Function(Function),
+    Var {
+        ident: Ident,
+        ann: Option<Type>,
+        expr: Expr,
+    },
+    /// `var (q, r) = expr`, declaring a variable for each part of a tuple
+    Destructure {
+        pattern: Pattern,
+        expr: Expr,
+    },
+    /// `const ident = expr`, a variable that can't be assigned to or redeclared
+    Const {
+        ident: Ident,
+        ann: Option<Type>,
+        expr: Expr,
+    },
+    /// `x = expr`, or `x += expr` where `op` is the operator applied
+    Assign {
+        ident: Ident,
+        op: Option<Operator>,
+        expr: Expr,
+        span: Span,
+    },
+    /// `while cond do body end`
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+    },
+    /// `for ident in from..to do body end`, counting up through both ends
+    For {
+        ident: Ident,
+        from: Expr,
+        to: Expr,
+        body: Vec<Stmt>,
+    },
+    /// `if cond then body else otherwise end`, `else if` nests another `If` in `otherwise`
+    If {
+        cond: Expr,
+        then: Vec<Stmt>,
+        otherwise: Vec<Stmt>,
+    },
+    /// `import "path.mini" as alias`, `import name` is `import "name.mini"`
+    Import {
+        path: String,
+        alias: Option<Ident>,
+        span: Span,
+    },
+    /// `use ns`, making the names inside `ns` usable without `ns.`
+    Use {
+        ns: Ident,
+        span: Span,
+    },
+    /// `override function ...` or `override var ...`, which may replace a builtin
+    Override(Box<Stmt>),
+    Break,
+    Continue,
+    Return(Expr),
+    Expr(Expr),
+}
+impl Display for Stmt {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Function(func) => write!(f, "{}", func),
+            Self::Var {
+                ident,
+                ann: Some(ty),
+                expr,
+            } => write!(f, "var {}: {} = {}", ident, ty, expr),
+            Self::Var { ident, expr, .. } => write!(f, "var {} = {}", ident, expr),
+            Self::Destructure { pattern, expr } => write!(f, "var {} = {}", pattern, expr),
+            Self::Const {
+                ident,
+                ann: Some(ty),
+                expr,
+            } => write!(f, "const {}: {} = {}", ident, ty, expr),
+            Self::Const { ident, expr, .. } => write!(f, "const {} = {}", ident, expr),
+            Self::Assign {
+                ident,
+                op: Some(op),
+                expr,
+                ..
+            } => write!(f, "{} {}= {}", ident, op, expr),
+            Self::Assign { ident, expr, .. } => write!(f, "{} = {}", ident, expr),
+            Self::While { cond, body } => write!(f, "while {} do {}end", cond, opened(body)),
+            Self::For {
+                ident,
+                from,
+                to,
+                body,
+            } => write!(
+                f,
+                "for {} in {}..{} do {}end",
+                ident,
+                from,
+                to,
+                opened(body)
+            ),
+            Self::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                write!(f, "if {} then {}", cond, opened(then))?;
+                match otherwise.as_slice() {
+                    [] => write!(f, "end"),
+                    [stmt @ Self::If { .. }] => write!(f, "else {}", stmt),
+                    _ => write!(f, "else {}end", opened(otherwise)),
+                }
+            }
+            Self::Import {
+                path,
+                alias: Some(alias),
+                ..
+            } => write!(f, "import \"{}\" as {}", path, alias),
+            Self::Import { path, .. } => write!(f, "import \"{}\"", path),
+            Self::Use { ns, .. } => write!(f, "use {}", ns),
+            Self::Override(stmt) => write!(f, "override {}", stmt),
+            Self::Break => write!(f, "break"),
+            Self::Continue => write!(f, "continue"),
+            Self::Return(expr) => write!(f, "return {}", expr),
+            Self::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+/// Statements on one line, separated by `;`
+fn block(body: &[Stmt]) -> String {
+    body.iter()
+        .map(|stmt| format!("{}", stmt))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// A block followed by a space, so an empty one doesn't leave two
+fn opened(body: &[Stmt]) -> String {
+    if body.is_empty() {
+        String::new()
+    } else {
+        format!("{} ", block(body))
+    }
+}
+
+/// How a token changes the nesting of blocks. An `else if` shares the `end` of the `if` it follows.
+pub fn nesting(prev: Option<&Token>, tok: &Token) -> isize {
+    match tok {
+        Token::Keyword(Keyword::Do) | Token::Operator(Operator::LBrace) => 1,
+        // `else if` continues a block, and `n if n > 0 =>` in a match is a guard
+        Token::Keyword(Keyword::If)
+            if !matches!(
+                prev,
+                Some(Token::Keyword(Keyword::Else))
+                    | Some(Token::Identifier(_))
+                    | Some(Token::Value(_))
+                    | Some(Token::Operator(Operator::RParen))
+            ) =>
+        {
+            1
+        }
+        Token::Keyword(Keyword::End) | Token::Operator(Operator::RBrace) => -1,
+        _ => 0,
+    }
+}
+
+pub fn has_dups(idents: &[Ident]) -> Option<Vec<Ident>> {
+    let mut map = HashMap::new();
+    let mut dups = Vec::new();
+
+    for (idx, ident) in idents.iter().enumerate() {
+        if map.insert(ident, idx).is_some() {
+            dups.push(ident.clone());
+        }
+    }
+
+    if dups.is_empty() {
+        None
+    } else {
+        Some(dups)
+    }
+}
+
+fn dups_error(dups: &[Ident]) -> String {
+    format!(
+        "Encountered multiple declarations of ({}) in argslist, dont do that :)",
+        dups.iter()
+            .map(|ident| ident.internal_cloned())
+            .collect::<Vec<String>>()
+            .join(", ")
+    )
+}
+
+/// The fields of a record, `rate: 0.05, term: 30`
+fn assignments(fields: &[(Ident, Expr)]) -> String {
+    fields
+        .iter()
+        .map(|(ident, expr)| format!("{}: {}", ident, expr))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Declaring `ns.name` directly would put it inside a namespace, which only imports do
+fn inside(ident: &Ident) -> String {
+    format!(
+        "{} can't be declared, only imports add names to a namespace",
+        ident
+    )
+}
+
+/// Whether the token can begin an operand, and so an argument when it follows a callee
+fn starts_operand(tok: &Token) -> bool {
+    matches!(
+        tok,
+        Token::Value(_)
+            | Token::Str(_)
+            | Token::Identifier(_)
+            | Token::Operator(Operator::LParen)
+            | Token::Operator(Operator::LBrace)
+            | Token::Operator(Operator::Backslash)
+            | Token::Keyword(Keyword::Fn)
+            | Token::Keyword(Keyword::Match)
+            | Token::Keyword(Keyword::Try)
+    )
+}
+
+fn is_prefix(tok: &Token) -> bool {
+    matches!(
+        tok,
+        Token::Operator(Operator::USub) | Token::Operator(Operator::BitNot)
+    )
+}
+
+/// How deeply expressions, patterns and blocks can nest, so code built from them can't run out of stack
+pub const MAX_NESTING: usize = 100;
+
+/// An argument in a declaration, with its annotation and default
+type Parameter = (Ident, Option<Type>, Option<Rc<Expr>>);
+
+/// Precedence climbing parser. Juxtaposition is application and binds tighter than every operator except postfix ones,
+/// so `f x + 1` is `(f x) + 1` and `f x!` is `f (x!)`.
+pub struct Parser {
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+    /// How many blocks and loops enclose the statement being parsed
+    blocks: usize,
+    loops: usize,
+    /// Whether the statement is in the body of a function, where it can `return`
+    function: bool,
+    /// Whether a `{` ends the expression being parsed rather than being a record argument, as it starts the arms
+    /// after the value of a `match` and the body after a guard
+    block_follows: bool,
+    /// How deeply the code being parsed is nested, counting each operator it's an operand of
+    depth: usize,
+}
+impl Parser {
+    pub fn new(tokens: Vec<(Token, Span)>) -> Self {
+        Parser {
+            tokens,
+            pos: 0,
+            blocks: 0,
+            loops: 0,
+            function: false,
+            block_follows: false,
+            depth: 0,
+        }
+    }
+
+    /// For tokens without positions, e.g. from `tokenize`
+    pub fn from_tokens(tokens: &[Token]) -> Self {
+        Self::new(
+            tokens
+                .iter()
+                .cloned()
+                .map(|tok| (tok, Span::default()))
+                .collect(),
+        )
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(tok, _)| tok)
+    }
+
+    fn span(&self) -> Span {
+        self.tokens
+            .get(self.pos)
+            .map_or_else(Span::default, |(_, span)| *span)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.peek().cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn eat(&mut self, op: Operator) -> bool {
+        if self.peek() == Some(&Token::Operator(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn operand_follows(&self) -> bool {
+        self.peek()
+            .is_some_and(|tok| starts_operand(tok) || is_prefix(tok))
+    }
+
+    /// One level deeper, an error past `MAX_NESTING`. Callers restore `depth` once they're done.
+    fn deeper(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(format!(
+                "Nested more than {} levels deep at {}",
+                MAX_NESTING,
+                self.span()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs `parse` one level deeper
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let depth = self.depth;
+        let res = self.deeper().and_then(|_| parse(self));
+        self.depth = depth;
+        res
+    }
+
+    fn skip_separators(&mut self) {
+        while self.peek() == Some(&Token::Separator) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses every remaining statement
+    pub fn program(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut stmts = Vec::new();
+        while let Some((stmt, _)) = self.next_statement() {
+            stmts.push(stmt?);
+        }
+        Ok(stmts)
+    }
+
+    /// The next statement along with the range of tokens it was parsed from, `None` once there are none left.
+    /// After an error the rest of the statement is skipped, so parsing can carry on from the one after it.
+    pub fn next_statement(&mut self) -> Option<(Result<Stmt, String>, Range<usize>)> {
+        self.skip_separators();
+        self.peek()?;
+        let start = self.pos;
+        let stmt = self.statement();
+        if stmt.is_err() {
+            self.recover(start);
+        }
+        Some((stmt, start..self.pos.min(self.tokens.len())))
+    }
+
+    /// Moves past the statement starting at `start`, including any blocks it opens
+    fn recover(&mut self, start: usize) {
+        self.depth = 0;
+        self.blocks = 0;
+        self.loops = 0;
+        self.function = false;
+        self.pos = start;
+        let mut depth = 0;
+        while let Some(tok) = self.peek() {
+            if *tok == Token::Separator && depth <= 0 {
+                break;
+            }
+            let prev = self.pos.checked_sub(1).map(|idx| &self.tokens[idx].0);
+            depth += nesting(prev, tok);
+            self.pos += 1;
+        }
+    }
+
+    /// Parses a single statement, which has to be followed by a separator, the end of its block or nothing
+    pub fn statement(&mut self) -> Result<Stmt, String> {
+        let assign = matches!(
+            self.tokens.get(self.pos + 1),
+            Some((Token::Operator(op), _)) if *op == Operator::Assign || op.compound().is_some()
+        );
+        let stmt = match self.peek() {
+            Some(Token::Keyword(Keyword::Function)) if self.blocks > 0 => {
+                return Err("Functions can only be declared at the top level".to_owned())
+            }
+            Some(Token::Keyword(Keyword::Function)) => self.function().map(Stmt::Function),
+            Some(Token::Keyword(Keyword::Import)) if self.blocks > 0 => {
+                return Err("Imports can only be at the top level".to_owned())
+            }
+            Some(Token::Keyword(Keyword::Import)) => self.import(),
+            Some(Token::Keyword(Keyword::Use)) | Some(Token::Keyword(Keyword::Override))
+                if self.blocks > 0 =>
+            {
+                return Err(format!(
+                    "{} can only be used at the top level",
+                    self.peek().unwrap()
+                ))
+            }
+            Some(Token::Keyword(Keyword::Use)) => self.use_namespace(),
+            Some(Token::Keyword(Keyword::Override)) => self.override_builtin(),
+            Some(Token::Keyword(Keyword::Const)) if self.blocks > 0 => {
+                return Err("Constants can only be declared at the top level".to_owned())
+            }
+            Some(Token::Keyword(Keyword::Variable)) | Some(Token::Keyword(Keyword::Const)) => {
+                self.var()
+            }
+            Some(Token::Keyword(Keyword::While)) => self.while_loop(),
+            Some(Token::Keyword(Keyword::For)) => self.for_loop(),
+            Some(Token::Keyword(Keyword::If)) => self.conditional(),
+            Some(Token::Keyword(Keyword::Return)) if !self.function => {
+                return Err("return can only be used inside a function body".to_owned())
+            }
+            Some(Token::Keyword(Keyword::Return)) => {
+                self.pos += 1;
+                self.value().map(Stmt::Return)
+            }
+            Some(Token::Keyword(Keyword::Break)) | Some(Token::Keyword(Keyword::Continue)) => {
+                self.jump()
+            }
+            Some(Token::Identifier(_)) if assign => self.assign(),
+            None => Err("Empty expression".to_owned()),
+            _ => self.expr(0).map(Stmt::Expr),
+        }?;
+        match self.peek() {
+            None | Some(Token::Separator) => Ok(stmt),
+            Some(Token::Keyword(Keyword::End))
+            | Some(Token::Keyword(Keyword::Else))
+            | Some(Token::Operator(Operator::RBrace))
+                if self.blocks > 0 =>
+            {
+                Ok(stmt)
+            }
+            Some(Token::Operator(Operator::RParen)) => Err("Mismatched parentheses".to_owned()),
+            Some(Token::Operator(op)) => Err(format!("Unexpected '{}' in expression", op)),
+            Some(tok) => Err(format!("Unexpected {} in expression", tok)),
+        }
+    }
+
+    /// Parses all remaining tokens as one expression
+    pub fn expression(&mut self) -> Result<Expr, String> {
+        if self.peek().is_none() {
+            return Err("Empty expression".to_owned());
+        }
+        let expr = self.expr(0)?;
+        self.skip_separators();
+        match self.peek() {
+            None => Ok(expr),
+            Some(Token::Operator(Operator::RParen)) => Err("Mismatched parentheses".to_owned()),
+            Some(Token::Operator(op)) => Err(format!("Unexpected '{}' in expression", op)),
+            Some(tok) => Err(format!("Unexpected {} in expression", tok)),
+        }
+    }
+
+    /// The expression on the right of `=`
+    fn value(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            None | Some(Token::Separator) => Err("Empty expression".to_owned()),
+            _ => self.expr(0),
+        }
+    }
+
+    fn expr(&mut self, min: u8) -> Result<Expr, String> {
+        self.nested(|parser| parser.operation(min))
+    }
+
+    fn operation(&mut self, min: u8) -> Result<Expr, String> {
+        let mut lhs = match self.peek() {
+            Some(Token::Operator(op)) if is_prefix(&Token::Operator(*op)) => {
+                let op = *op;
+                self.pos += 1;
+                if !self.operand_follows() {
+                    return Err(format!("Operator {} is missing an operand", op));
+                }
+                Expr::Unary {
+                    op,
+                    operand: Box::new(self.expr(op.precedence())?),
+                }
+            }
+            // The body of a lambda extends as far as possible
+            Some(Token::Operator(Operator::Backslash)) | Some(Token::Keyword(Keyword::Fn)) => {
+                return self.lambda()
+            }
+            Some(Token::Keyword(Keyword::Try)) => return self.try_catch(),
+            _ => self.application()?,
+        };
+
+        while let Some(Token::Operator(op)) = self.peek() {
+            let op = *op;
+            if op.arity() != 2 || op.precedence() == 0 || op.precedence() < min {
+                break;
+            }
+            self.pos += 1;
+            if !self.operand_follows() {
+                return Err(format!("Operator {} is missing an operand", op));
+            }
+            // `1 + 2 + 3` nests to the left without recursing, the right operand is a level deeper already
+            if matches!(lhs, Expr::Binary { .. }) {
+                self.deeper()?;
+            }
+            let next = match op.associativity() {
+                OperatorAssociativity::Left => op.precedence() + 1,
+                OperatorAssociativity::Right => op.precedence(),
+            };
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(self.expr(next)?),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn application(&mut self) -> Result<Expr, String> {
+        let span = self.span();
+        let callee = self.postfix()?;
+        let mut args = Vec::new();
+        while let Some(tok) = self.peek() {
+            if !starts_operand(tok)
+                || (self.block_follows && *tok == Token::Operator(Operator::LBrace))
+            {
+                break;
+            }
+            args.push(match tok {
+                Token::Operator(Operator::Backslash) | Token::Keyword(Keyword::Fn) => {
+                    self.lambda()?
+                }
+                Token::Keyword(Keyword::Try) => self.try_catch()?,
+                _ => self.postfix()?,
+            });
+        }
+
+        if args.is_empty() {
+            Ok(callee)
+        } else {
+            Ok(Expr::App {
+                callee: Box::new(callee),
+                args,
+                span,
+            })
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        while let Some(Token::Operator(op)) = self.peek() {
+            let op = *op;
+            if !op.is_postfix() {
+                break;
+            }
+            self.deeper()?;
+            expr = Expr::Unary {
+                op,
+                operand: Box::new(expr),
+            };
+            self.pos += 1;
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let span = self.span();
+        match self.next() {
+            Some(Token::Value(v)) => Ok(Expr::Num(v)),
+            Some(Token::Str(text)) => Ok(Expr::Str(text)),
+            // `f(...)` with nothing between the name and the parenthesis. Tokens from `tokenize` have no
+            // positions, so never count as touching.
+            Some(Token::Identifier(ident))
+                if self.peek() == Some(&Token::Operator(Operator::LParen))
+                    && span.end > span.start
+                    && self.span().start == span.end =>
+            {
+                self.call(Expr::Var(ident, span), span)
+            }
+            Some(Token::Identifier(ident)) => Ok(Expr::Var(ident, span)),
+            Some(Token::Operator(Operator::LParen)) => {
+                self.before_block(false, Self::parenthesised)
+            }
+            Some(Token::Operator(Operator::LBrace)) => self.before_block(false, Self::record),
+            Some(Token::Keyword(Keyword::Match)) => self.match_arms(span),
+            Some(Token::Operator(op)) => Err(format!("Unexpected '{}' in expression", op)),
+            Some(tok) => Err(format!("Unexpected {} in expression", tok)),
+            None => Err("Unexpected end of expression".to_owned()),
+        }
+    }
+
+    /// `(expr)` or a tuple, `(a, b)`, after the `(`
+    fn parenthesised(&mut self) -> Result<Expr, String> {
+        let expr = self.expr(0)?;
+        if !self.eat(Operator::Comma) {
+            return if self.eat(Operator::RParen) {
+                Ok(expr)
+            } else {
+                Err("Mismatched parentheses".to_owned())
+            };
+        }
+        let mut items = vec![expr, self.expr(0)?];
+        while self.eat(Operator::Comma) {
+            items.push(self.expr(0)?);
+        }
+        if self.eat(Operator::RParen) {
+            Ok(Expr::Tuple(items))
+        } else {
+            Err("Expected ',' or ')' after an item of a tuple".to_owned())
+        }
+    }
+
+    /// `{ name: value, ... }` or `{ record with name: value, ... }`, after the `{`
+    fn record(&mut self) -> Result<Expr, String> {
+        self.skip_separators();
+        let literal = matches!(
+            (self.peek(), self.tokens.get(self.pos + 1)),
+            (Some(Token::Operator(Operator::RBrace)), _)
+                | (
+                    Some(Token::Identifier(_)),
+                    Some((Token::Operator(Operator::Colon), _))
+                )
+        );
+        let record = if literal {
+            None
+        } else {
+            let record = self.expr(0)?;
+            self.skip_separators();
+            if self.next() != Some(Token::Keyword(Keyword::With)) {
+                return Err(format!("Expected with after {} in record update", record));
+            }
+            Some(record)
+        };
+
+        let mut fields: Vec<(Ident, Expr)> = Vec::new();
+        loop {
+            self.skip_separators();
+            if self.eat(Operator::RBrace) {
+                break;
+            }
+            let ident = match self.next() {
+                Some(Token::Identifier(ident)) if !ident.is_qualified() => ident,
+                tok => return Err(format!("Expected a field name, got: {:?}", tok)),
+            };
+            if !self.eat(Operator::Colon) {
+                return Err(format!("Expected ':' after field {}", ident));
+            }
+            if fields.iter().any(|(field, _)| *field == ident) {
+                return Err(format!("Field {} is given twice", ident));
+            }
+            let expr = self.expr(0)?;
+            fields.push((ident, expr));
+            self.skip_separators();
+            if self.peek() != Some(&Token::Operator(Operator::RBrace)) && !self.eat(Operator::Comma)
+            {
+                return Err("Expected ',' or '}' after a field".to_owned());
+            }
+        }
+        Ok(match record {
+            Some(record) => Expr::With {
+                record: Box::new(record),
+                fields,
+            },
+            None => Expr::Record(fields),
+        })
+    }
+
+    /// `match value { pattern [if guard] => expr, ... }`, after the `match`
+    fn match_arms(&mut self, span: Span) -> Result<Expr, String> {
+        let value = self.before_block(true, |parser| parser.expr(0))?;
+        if !self.eat(Operator::LBrace) {
+            return Err(format!("Expected '{{' after match {}", value));
+        }
+        let arms = self.before_block(false, Self::arms)?;
+        if arms.is_empty() {
+            return Err(format!("match {} has no arms", value));
+        }
+        Ok(Expr::Match {
+            value: Box::new(value),
+            arms,
+            span,
+        })
+    }
+
+    fn arms(&mut self) -> Result<Vec<Arm>, String> {
+        let mut arms = Vec::new();
+        loop {
+            self.skip_separators();
+            if self.eat(Operator::RBrace) {
+                break;
+            }
+            let pattern = self.pattern()?;
+            let guard = if self.peek() == Some(&Token::Keyword(Keyword::If)) {
+                self.pos += 1;
+                Some(self.expr(0)?)
+            } else {
+                None
+            };
+            if !self.eat(Operator::FatArrow) {
+                return Err(format!("Expected '=>' after {} in match", pattern));
+            }
+            arms.push(Arm {
+                pattern,
+                guard,
+                body: self.expr(0)?,
+            });
+            self.skip_separators();
+            if self.peek() != Some(&Token::Operator(Operator::RBrace)) && !self.eat(Operator::Comma)
+            {
+                return Err("Expected ',' or '}' after an arm of a match".to_owned());
+            }
+        }
+        Ok(arms)
+    }
+
+    /// Parses with `block_follows` set to `block`, restoring it afterwards
+    fn before_block<T>(
+        &mut self,
+        block: bool,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let prev = std::mem::replace(&mut self.block_follows, block);
+        let res = parse(self);
+        self.block_follows = prev;
+        res
+    }
+
+    /// The parenthesised arguments of `f(a, b: 1)`, positional ones first
+    fn call(&mut self, callee: Expr, span: Span) -> Result<Expr, String> {
+        self.pos += 1;
+        let mut args = Vec::new();
+        let mut named = Vec::new();
+        while !self.eat(Operator::RParen) {
+            match (self.peek(), self.tokens.get(self.pos + 1)) {
+                (None, _) => return Err("Mismatched parentheses".to_owned()),
+                (Some(Token::Identifier(ident)), Some((Token::Operator(Operator::Colon), _))) => {
+                    let ident = ident.clone();
+                    self.pos += 2;
+                    if named.iter().any(|(name, _)| *name == ident) {
+                        return Err(format!("Argument {} of {} was given twice", ident, callee));
+                    }
+                    named.push((ident, self.expr(0)?));
+                }
+                _ if !named.is_empty() => {
+                    return Err(format!(
+                        "Positional arguments have to come before named ones in call to {}",
+                        callee
+                    ))
+                }
+                _ => args.push(self.expr(0)?),
+            }
+            if self.peek() != Some(&Token::Operator(Operator::RParen)) && !self.eat(Operator::Comma)
+            {
+                return Err(format!(
+                    "Expected ',' or ')' after an argument of {}",
+                    callee
+                ));
+            }
+        }
+        Ok(Expr::Call {
+            callee: Box::new(callee),
+            args,
+            named,
+            span,
+        })
+    }
+
+    /// `\x y -> body` or `fn(x, y) => body`
+    fn lambda(&mut self) -> Result<Expr, String> {
+        let mut params = Vec::new();
+        if self.next() == Some(Token::Keyword(Keyword::Fn)) {
+            if !self.eat(Operator::LParen) {
+                return Err("Expected '(' after fn".to_owned());
+            }
+            while !self.eat(Operator::RParen) {
+                match self.next() {
+                    Some(Token::Identifier(ident)) => params.push(ident),
+                    tok => {
+                        return Err(format!(
+                            "Expected a parameter name in fn(...), got: {:?}",
+                            tok
+                        ))
+                    }
+                }
+                if self.peek() != Some(&Token::Operator(Operator::RParen))
+                    && !self.eat(Operator::Comma)
+                {
+                    return Err("Expected ',' or ')' after a parameter of fn(...)".to_owned());
+                }
+            }
+            if !self.eat(Operator::FatArrow) {
+                return Err("Expected '=>' after the parameters of a lambda".to_owned());
+            }
+        } else {
+            while let Some(Token::Identifier(ident)) = self.peek() {
+                params.push(ident.clone());
+                self.pos += 1;
+            }
+            if !self.eat(Operator::Arrow) {
+                return Err("Expected '->' after the parameters of a lambda".to_owned());
+            }
+        }
+
+        if params.is_empty() {
+            return Err("A lambda needs at least one parameter".to_owned());
+        }
+        if let Some(dups) = has_dups(&params) {
+            return Err(dups_error(&dups));
+        }
+        if self.peek().is_none() {
+            return Err("Lambda with no body".to_owned());
+        }
+        Ok(Expr::Lambda {
+            params,
+            body: Rc::new(self.expr(0)?),
+        })
+    }
+
+    /// `try body catch e => fallback`. Like the body of a lambda, the fallback extends as far as possible.
+    fn try_catch(&mut self) -> Result<Expr, String> {
+        self.pos += 1;
+        let body = self.expr(0)?;
+        if self.next() != Some(Token::Keyword(Keyword::Catch)) {
+            return Err(format!("Expected catch after try {}", body));
+        }
+        let ident = match self.next() {
+            Some(Token::Identifier(ident)) => ident,
+            tok => {
+                return Err(format!(
+                    "Expected a name for the error after catch, got: {:?}",
+                    tok
+                ))
+            }
+        };
+        if !self.eat(Operator::FatArrow) {
+            return Err(format!("Expected '=>' after catch {}", ident));
+        }
+        if self.peek().is_none() {
+            return Err("Expected an expression after catch".to_owned());
+        }
+        Ok(Expr::Try {
+            body: Box::new(body),
+            ident,
+            fallback: Box::new(self.expr(0)?),
+        })
+    }
+
+    /// Reads the type name following a `:` in an annotation
+    fn annotation(&mut self, fnident: &Ident) -> Result<Type, String> {
+        match self.next() {
+            Some(Token::Identifier(name)) => Type::from_ident(&name)
+                .ok_or_else(|| format!("Unknown type {} in declaration of {}", name, fnident)),
+            tok => Err(format!(
+                "Expected a type after ':' in declaration of {}, got: {:?}",
+                fnident, tok
+            )),
+        }
+    }
+
+    /// `function name a (b: type) (c = default) ...rest [: type] = body`
+    fn function(&mut self) -> Result<Function, String> {
+        self.pos += 1;
+        let ident = match self.next() {
+            Some(Token::Identifier(ident)) if ident.is_qualified() => return Err(inside(&ident)),
+            Some(Token::Identifier(ident)) => ident,
+            _ => return Err("`function` keyword not followed by an identifier".to_owned()),
+        };
+
+        let mut args = Vec::new();
+        let mut annotations = Vec::new();
+        let mut defaults = Vec::new();
+        let mut patterns = Vec::new();
+        let mut guard = None;
+        let mut variadic = false;
+        let mut ret = None;
+        let mut braces = false;
+        while let Some(tok) = self.next() {
+            if variadic
+                && !matches!(
+                    tok,
+                    Token::Operator(
+                        Operator::Assign | Operator::LBrace | Operator::Colon | Operator::BitOr
+                    )
+                )
+            {
+                return Err(format!(
+                    "...{} has to be the last argument in declaration of {}",
+                    args.last().unwrap(),
+                    ident
+                ));
+            }
+            match tok {
+                Token::Operator(Operator::Assign) => break,
+                Token::Operator(Operator::LBrace) => {
+                    braces = true;
+                    break;
+                }
+                // `| cond`, the clause only applies when the guard holds
+                Token::Operator(Operator::BitOr) if guard.is_none() => {
+                    guard = Some(Rc::new(self.before_block(true, |parser| parser.expr(0))?));
+                }
+                // 0, _ or (a, b), a clause for the arguments matching it
+                tok if self.starts_pattern(&tok) => {
+                    self.pos -= 1;
+                    let pattern = self.pattern()?;
+                    if defaults.iter().any(Option::is_some) {
+                        return Err(format!(
+                            "Argument {} needs a default since the ones before it have one, in declaration of {}",
+                            pattern, ident
+                        ));
+                    }
+                    args.push(Ident::unnamed(pattern.to_string()));
+                    annotations.push(None);
+                    defaults.push(None);
+                    patterns.push(Some(pattern));
+                }
+                Token::Identifier(arg) => {
+                    if defaults.iter().any(Option::is_some) {
+                        return Err(format!(
+                            "Argument {} needs a default since the ones before it have one, in declaration of {}",
+                            arg, ident
+                        ));
+                    }
+                    args.push(arg);
+                    annotations.push(None);
+                    defaults.push(None);
+                    patterns.push(None);
+                }
+                // (arg: type), (arg = default) or (arg: type = default)
+                Token::Operator(Operator::LParen) => {
+                    let (arg, ann, default) = self.parameter(&ident)?;
+                    if default.is_none() && defaults.iter().any(Option::is_some) {
+                        return Err(format!(
+                            "Argument {} needs a default since the ones before it have one, in declaration of {}",
+                            arg, ident
+                        ));
+                    }
+                    args.push(arg);
+                    annotations.push(ann);
+                    defaults.push(default);
+                    patterns.push(None);
+                }
+                // ...rest or ...(rest: type), where the type is that of each argument
+                Token::Operator(Operator::Ellipsis) => {
+                    let (arg, ann) = match self.next() {
+                        Some(Token::Identifier(arg)) => (arg, None),
+                        Some(Token::Operator(Operator::LParen)) => match self.parameter(&ident)? {
+                            (arg, ann, None) => (arg, ann),
+                            (arg, _, Some(_)) => {
+                                return Err(format!(
+                                    "...{} can't have a default in declaration of {}",
+                                    arg, ident
+                                ))
+                            }
+                        },
+                        tok => {
+                            return Err(format!(
+                            "Expected an argument name after '...' in declaration of {}, got: {:?}",
+                            ident, tok
+                        ))
+                        }
+                    };
+                    args.push(arg);
+                    annotations.push(ann);
+                    defaults.push(None);
+                    patterns.push(None);
+                    variadic = true;
+                }
+                // : type =
+                Token::Operator(Operator::Colon) => {
+                    ret = Some(self.annotation(&ident)?);
+                    if !matches!(
+                        self.peek(),
+                        Some(Token::Operator(Operator::Assign))
+                            | Some(Token::Operator(Operator::LBrace))
+                    ) {
+                        return Err(format!(
+                            "Expected '=' after the return type in declaration of {}",
+                            ident
+                        ));
+                    }
+                }
+                tok => {
+                    return Err(format!(
+                        "Expected identifier in declaration of {}, got: {:?}",
+                        ident, tok
+                    ))
+                }
+            }
+        }
+
+        if !braces && matches!(self.peek(), None | Some(Token::Separator)) {
+            return Err("Function declaration with no body".to_owned());
+        }
+        let mut func = Function {
+            ident,
+            args,
+            body: Rc::new(Expr::Block(Vec::new())),
+            annotations,
+            defaults,
+            patterns,
+            guard,
+            clauses: Vec::new(),
+            variadic,
+            ret,
+            sig: None,
+            file: None,
+            lambda: false,
+        };
+        if let Some(dups) = has_dups(&func.names()) {
+            return Err(dups_error(&dups));
+        }
+        let body = if braces {
+            self.function = true;
+            let body = self.statements(&[Token::Operator(Operator::RBrace)])?;
+            self.function = false;
+            self.pos += 1;
+            Expr::Block(body)
+        } else {
+            self.expr(0)?
+        };
+        func.body = Rc::new(body);
+        Ok(func)
+    }
+
+    /// Whether `tok`, just read among the arguments of a declaration, starts a pattern rather than naming an argument
+    fn starts_pattern(&self, tok: &Token) -> bool {
+        match tok {
+            Token::Value(_) | Token::Operator(Operator::USub) | Token::Operator(Operator::Sub) => {
+                true
+            }
+            Token::Identifier(ident) => ident.internal_cloned() == "_",
+            // `(a, b)` or `(0, b)` rather than `(a: type)`
+            Token::Operator(Operator::LParen) => matches!(
+                (self.peek(), self.tokens.get(self.pos + 1)),
+                (Some(Token::Operator(Operator::LParen)), _)
+                    | (Some(Token::Value(_)), _)
+                    | (Some(Token::Operator(Operator::USub)), _)
+                    | (
+                        Some(Token::Identifier(_)),
+                        Some((Token::Operator(Operator::Comma), _))
+                    )
+            ),
+            _ => false,
+        }
+    }
+
+    /// The inside of `(arg: type = default)` in a declaration, after the `(`
+    fn parameter(&mut self, fnident: &Ident) -> Result<Parameter, String> {
+        let expected = || {
+            format!(
+                "Expected (argument: type) or (argument = default) in declaration of {}",
+                fnident
+            )
+        };
+        let arg = match self.next() {
+            Some(Token::Identifier(arg)) => arg,
+            _ => return Err(expected()),
+        };
+        let ann = if self.eat(Operator::Colon) {
+            Some(self.annotation(fnident)?)
+        } else {
+            None
+        };
+        let default = if self.eat(Operator::Assign) {
+            Some(Rc::new(self.expr(0)?))
+        } else {
+            None
+        };
+        if (ann.is_none() && default.is_none()) || !self.eat(Operator::RParen) {
+            return Err(expected());
+        }
+        Ok((arg, ann, default))
+    }
+
+    /// `import "path.mini"` or `import name`, either followed by `as alias`
+    fn import(&mut self) -> Result<Stmt, String> {
+        self.pos += 1;
+        let span = self.span();
+        let path = match self.next() {
+            Some(Token::Str(path)) => path,
+            // `import lib.stats` is lib/stats.mini
+            Some(Token::Identifier(name)) => format!("{}.mini", name.to_string().replace('.', "/")),
+            tok => {
+                return Err(format!(
+                    "Expected a file or module name after import, got: {:?}",
+                    tok
+                ))
+            }
+        };
+        let alias = if self.peek() == Some(&Token::Keyword(Keyword::As)) {
+            self.pos += 1;
+            match self.next() {
+                Some(Token::Identifier(alias)) => Some(alias),
+                tok => return Err(format!("Expected a name after as, got: {:?}", tok)),
+            }
+        } else {
+            None
+        };
+        Ok(Stmt::Import { path, alias, span })
+    }
+
+    /// `use ns`
+    fn use_namespace(&mut self) -> Result<Stmt, String> {
+        self.pos += 1;
+        let span = self.span();
+        match self.next() {
+            Some(Token::Identifier(ns)) => Ok(Stmt::Use { ns, span }),
+            tok => Err(format!("Expected a namespace after use, got: {:?}", tok)),
+        }
+    }
+
+    /// `override function ...` or `override var ...`
+    fn override_builtin(&mut self) -> Result<Stmt, String> {
+        self.pos += 1;
+        let stmt = match self.peek() {
+            Some(Token::Keyword(Keyword::Function)) => self.function().map(Stmt::Function),
+            Some(Token::Keyword(Keyword::Variable)) | Some(Token::Keyword(Keyword::Const)) => {
+                self.var()
+            }
+            tok => Err(format!(
+                "Expected function, var or const after override, got: {:?}",
+                tok
+            )),
+        }?;
+        Ok(Stmt::Override(Box::new(stmt)))
+    }
+
+    /// `var ident[: type] = expr`, or the same starting with `const`. `var (a, b) = expr` takes a tuple apart.
+    fn var(&mut self) -> Result<Stmt, String> {
+        let keyword = self.next().unwrap();
+        if keyword == Token::Keyword(Keyword::Variable)
+            && self.peek() == Some(&Token::Operator(Operator::LParen))
+        {
+            let pattern = self.pattern()?;
+            if !self.eat(Operator::Assign) {
+                return Err(format!("Expected '=' after var {}", pattern));
+            }
+            return Ok(Stmt::Destructure {
+                pattern,
+                expr: self.value()?,
+            });
+        }
+        let ident = match self.next() {
+            Some(Token::Identifier(ident)) if ident.is_qualified() => return Err(inside(&ident)),
+            Some(Token::Identifier(ident)) => ident,
+            _ => {
+                return Err(format!(
+                    "`{}` keyword not followed by an identifier",
+                    keyword
+                ))
+            }
+        };
+        let ann = if self.eat(Operator::Colon) {
+            match self.next() {
+                Some(Token::Identifier(name)) => {
+                    Some(Type::from_ident(&name).ok_or_else(|| {
+                        format!("Unknown type {} in declaration of {}", name, ident)
+                    })?)
+                }
+                tok => {
+                    return Err(format!(
+                        "Expected a type after ':' in declaration of {}, got: {:?}",
+                        ident, tok
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+        if !self.eat(Operator::Assign) {
+            return Err(format!(
+                "Unexpected token before assignment operator in varible assignment: {:?}",
+                self.peek()
+            ));
+        }
+        let expr = self.value()?;
+        if keyword == Token::Keyword(Keyword::Const) {
+            Ok(Stmt::Const { ident, ann, expr })
+        } else {
+            Ok(Stmt::Var { ident, ann, expr })
+        }
+    }
+
+    /// A name, or `(a, (b, c))` taking a tuple apart. The same name can't be bound twice.
+    fn pattern(&mut self) -> Result<Pattern, String> {
+        let pattern = self.parts()?;
+        let idents = pattern
+            .idents()
+            .into_iter()
+            .cloned()
+            .collect::<Vec<Ident>>();
+        match has_dups(&idents) {
+            Some(dups) => Err(dups_error(&dups)),
+            None => Ok(pattern),
+        }
+    }
+
+    fn parts(&mut self) -> Result<Pattern, String> {
+        self.nested(Self::part)
+    }
+
+    fn part(&mut self) -> Result<Pattern, String> {
+        match self.next() {
+            Some(Token::Value(v)) => Ok(Pattern::Num(v)),
+            Some(Token::Operator(Operator::USub)) | Some(Token::Operator(Operator::Sub)) => {
+                match self.next() {
+                    Some(Token::Value(v)) => Ok(Pattern::Num(-v)),
+                    tok => Err(format!("Expected a number after '-', got: {:?}", tok)),
+                }
+            }
+            Some(Token::Identifier(ident)) if ident.internal_cloned() == "_" => {
+                Ok(Pattern::Wildcard)
+            }
+            Some(Token::Identifier(ident)) if ident.is_qualified() => Err(inside(&ident)),
+            Some(Token::Identifier(ident)) => Ok(Pattern::Bind(ident)),
+            Some(Token::Operator(Operator::LParen)) => {
+                let mut parts = vec![self.parts()?];
+                while self.eat(Operator::Comma) {
+                    parts.push(self.parts()?);
+                }
+                if parts.len() < 2 {
+                    return Err(format!(
+                        "Expected ',' after {} in a tuple to take apart",
+                        parts[0]
+                    ));
+                }
+                if !self.eat(Operator::RParen) {
+                    return Err("Expected ',' or ')' in a tuple to take apart".to_owned());
+                }
+                Ok(Pattern::Tuple(parts))
+            }
+            tok => Err(format!(
+                "Expected a name, a number, _ or a tuple to take apart, got: {:?}",
+                tok
+            )),
+        }
+    }
+
+    /// `x = expr` or `x += expr`
+    fn assign(&mut self) -> Result<Stmt, String> {
+        let span = self.span();
+        let ident = match self.next() {
+            Some(Token::Identifier(ident)) => ident,
+            _ => unreachable!(),
+        };
+        let op = match self.next() {
+            Some(Token::Operator(op)) => op.compound(),
+            _ => unreachable!(),
+        };
+        Ok(Stmt::Assign {
+            ident,
+            op,
+            expr: self.value()?,
+            span,
+        })
+    }
+
+    /// Statements up to the first of `closers`, which is left for the caller
+    fn statements(&mut self, closers: &[Token]) -> Result<Vec<Stmt>, String> {
+        self.nested(|parser| parser.block_statements(closers))
+    }
+
+    fn block_statements(&mut self, closers: &[Token]) -> Result<Vec<Stmt>, String> {
+        self.blocks += 1;
+        let mut body = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                Some(tok) if closers.contains(tok) => break,
+                None => return Err(format!("Expected '{}' to close the block", closers[0])),
+                _ => body.push(self.statement()?),
+            }
+        }
+        self.blocks -= 1;
+        Ok(body)
+    }
+
+    /// Statements up to the `end` closing a block
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        let body = self.statements(&[Token::Keyword(Keyword::End)])?;
+        self.pos += 1;
+        Ok(body)
+    }
+
+    /// `if cond then body [else if ...] [else otherwise] end`
+    fn conditional(&mut self) -> Result<Stmt, String> {
+        self.pos += 1;
+        let cond = self.value()?;
+        if self.next() != Some(Token::Keyword(Keyword::Then)) {
+            return Err("Expected 'then' after the condition of an if".to_owned());
+        }
+        let then =
+            self.statements(&[Token::Keyword(Keyword::End), Token::Keyword(Keyword::Else)])?;
+        let otherwise = match self.next() {
+            Some(Token::Keyword(Keyword::Else))
+                if self.peek() == Some(&Token::Keyword(Keyword::If)) =>
+            {
+                vec![self.conditional()?]
+            }
+            Some(Token::Keyword(Keyword::Else)) => self.block()?,
+            _ => Vec::new(),
+        };
+        Ok(Stmt::If {
+            cond,
+            then,
+            otherwise,
+        })
+    }
+
+    fn loop_body(&mut self) -> Result<Vec<Stmt>, String> {
+        self.loops += 1;
+        let body = self.block()?;
+        self.loops -= 1;
+        Ok(body)
+    }
+
+    /// `while cond do body end`
+    fn while_loop(&mut self) -> Result<Stmt, String> {
+        self.pos += 1;
+        let cond = self.value()?;
+        if self.next() != Some(Token::Keyword(Keyword::Do)) {
+            return Err("Expected 'do' after the condition of a while loop".to_owned());
+        }
+        Ok(Stmt::While {
+            cond,
+            body: self.loop_body()?,
+        })
+    }
+
+    /// `for ident in from..to do body end`
+    fn for_loop(&mut self) -> Result<Stmt, String> {
+        self.pos += 1;
+        let ident = match self.next() {
+            Some(Token::Identifier(ident)) => ident,
+            _ => return Err("`for` keyword not followed by an identifier".to_owned()),
+        };
+        if self.next() != Some(Token::Keyword(Keyword::In)) {
+            return Err(format!("Expected 'in' after for {}", ident));
+        }
+        let from = self.value()?;
+        if !self.eat(Operator::Range) {
+            return Err("Expected '..' between the bounds of a for loop".to_owned());
+        }
+        let to = self.value()?;
+        if self.next() != Some(Token::Keyword(Keyword::Do)) {
+            return Err("Expected 'do' after the range of a for loop".to_owned());
+        }
+        Ok(Stmt::For {
+            ident,
+            from,
+            to,
+            body: self.loop_body()?,
+        })
+    }
+
+    /// `break` or `continue`
+    fn jump(&mut self) -> Result<Stmt, String> {
+        let tok = self.next().unwrap();
+        if self.loops == 0 {
+            return Err(format!("{} can only be used inside a loop", tok));
+        }
+        Ok(match tok {
+            Token::Keyword(Keyword::Break) => Stmt::Break,
+            _ => Stmt::Continue,
+        })
+    }
+}
+
+/// Parses a statement from tokens without positions
+pub fn parse(tokens: &[Token]) -> Result<Stmt, String> {
+    Parser::from_tokens(tokens).statement()
+}
+
+pub fn parse_expression(tokens: &[Token]) -> Result<Expr, String> {
+    Parser::from_tokens(tokens).expression()
+}
+
+#[test]
+fn test_parse() {
+    use crate::token::tokenize;
+    let parse = |s: &str| parse_expression(&tokenize(s.to_owned()).unwrap());
+    let show = |s: &str| format!("{}", parse(s).unwrap());
+
+    assert_eq!(show("1 + 2 * 3"), "1 + 2 * 3");
+    assert_eq!(show("(1 + 2) * 3"), "(1 + 2) * 3");
+    assert_eq!(show("10 - (3 - 2)"), "10 - (3 - 2)");
+    assert_eq!(show("(2 ^ 3) ^ 2"), "(2 ^ 3) ^ 2");
+    assert_eq!(show("2 ^ 3 ^ 2"), "2 ^ 3 ^ 2");
+    assert_eq!(show("-(1 + 2)"), "-(1 + 2)");
+    assert_eq!(show("f x 3 + 1"), "f x 3 + 1");
+    assert_eq!(show("f (g x) (y + 1)!"), "f (g x) (y + 1)!");
+    assert_eq!(show("-f x!"), "-f x!");
+    assert_eq!(show("\\x y -> x * y"), "\\x y -> x * y");
+    assert_eq!(show("fn(x) => x ^ 2"), "\\x -> x ^ 2");
+    assert_eq!(show("map (\\x -> x + 1) 2"), "map (\\x -> x + 1) 2");
+    assert_eq!(show("map \\x -> x + 1"), "map (\\x -> x + 1)");
+    assert_eq!(show("f (1, (a + 2, b))"), "f (1, (a + 2, b))");
+    assert_eq!(
+        show("match p { (0, _) => 1, (x, y) if x > y => x, _ => -1 }"),
+        "match p { (0, _) => 1, (x, y) if x > y => x, _ => -1 }"
+    );
+    assert_eq!(show("1 + match x { _ => 2 }"), "1 + match x { _ => 2 }");
+    assert_eq!(
+        show("2 * try f x catch e => 0"),
+        "2 * (try f x catch e => 0)"
+    );
+    assert_eq!(
+        show(r#""n = " ++ str (n + 1) ++ "\n""#),
+        r#""n = " ++ str (n + 1) ++ "\n""#
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            self::parse(&tokenize("var ((a, b), c) = t".to_owned()).unwrap()).unwrap()
+        ),
+        "var ((a, b), c) = t"
+    );
+
+    assert!(matches!(
+        parse("f 1 2").unwrap(),
+        Expr::App { args, .. } if args.len() == 2
+    ));
+
+    assert_eq!(
+        parse("1 +"),
+        Err("Operator + is missing an operand".to_owned())
+    );
+    assert_eq!(parse("(1 + 2"), Err("Mismatched parentheses".to_owned()));
+    assert_eq!(parse("1 + 2)"), Err("Mismatched parentheses".to_owned()));
+    assert_eq!(
+        parse("1 = 2"),
+        Err("Unexpected '=' in expression".to_owned())
+    );
+    assert_eq!(
+        parse("\\ -> 1"),
+        Err("A lambda needs at least one parameter".to_owned())
+    );
+    assert_eq!(
+        parse("fn(x y) => 1"),
+        Err("Expected ',' or ')' after a parameter of fn(...)".to_owned())
+    );
+    assert!(parse("\\x x -> x").is_err());
+    assert_eq!(
+        parse("(1, 2"),
+        Err("Expected ',' or ')' after an item of a tuple".to_owned())
+    );
+    assert_eq!(parse("match x {}"), Err("match x has no arms".to_owned()));
+    assert_eq!(
+        parse("try f x => 0"),
+        Err("Expected catch after try f x".to_owned())
+    );
+    assert_eq!(
+        parse("match x { 1 2 }"),
+        Err("Expected '=>' after 1 in match".to_owned())
+    );
+}
+
+#[test]
+fn test_nesting() {
+    use crate::{check::Checker, engine::Engine};
+    let mut engine = Engine::new();
+
+    // As deep as it goes, which still runs on a default thread stack
+    for src in [
+        format!("{}2", "-".repeat(MAX_NESTING - 1)),
+        format!("2{}", " + 1".repeat(MAX_NESTING - 1)),
+        format!(
+            "{}2{}",
+            "(2 + ".repeat(MAX_NESTING / 2 - 1),
+            ")".repeat(MAX_NESTING / 2 - 1)
+        ),
+        format!("function f x = {}x\nf 2", "-".repeat(MAX_NESTING - 1)),
+    ] {
+        assert!(Checker::new().check(&src).is_empty(), "{}", src);
+        assert!(engine.run(&src).is_ok(), "{}", src);
+    }
+
+    let parens = format!("{}1{}", "(".repeat(20000), ")".repeat(20000));
+    let negations = format!("{}1", "-".repeat(50000));
+    let sum = format!("1{}", "+1".repeat(50000));
+    let patterns = format!("var {}a, b) = 1", "(".repeat(20000));
+    let blocks = "while 1 do ".repeat(20000);
+    for (src, col) in [
+        (&parens, 101),
+        (&negations, 101),
+        (&sum, 201),
+        (&patterns, 105),
+        (&blocks, 1107),
+    ] {
+        let e = format!("Nested more than {} levels deep at 1:{}", MAX_NESTING, col);
+        assert_eq!(engine.run(src), Err(e.clone()));
+        let diags = Checker::new().check(src);
+        assert_eq!(diags[0].message, e);
+    }
+}
@@ -0,0 +1,980 @@
+use {
+    crate::{
+        function::Function,
+        interpreter::Context,
+        token::{Ident, Operator},
+        tree::{Expr, Pattern, Stmt},
+        value::Value,
+    },
+    std::{
+        collections::HashMap,
+        fmt::{self, Display, Formatter},
+    },
+};
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Type {
+    Num,
+    Int,
+    Str,
+    /// Any value at all, only taken by builtins like `format`
+    Any,
+    Var(usize),
+    Fn(Vec<Type>, Box<Type>),
+    /// The arguments collected by a variadic parameter
+    List(Box<Type>),
+    /// A parameter with a default value, `num?`
+    Optional(Box<Type>),
+    /// A variadic parameter, `...num` takes any number of numbers
+    Rest(Box<Type>),
+    Record(Vec<(Ident, Type)>),
+    Tuple(Vec<Type>),
+}
+impl Type {
+    /// Parses the name used in an annotation, e.g. the `num` in `(w: num)`
+    pub fn from_ident(ident: &Ident) -> Option<Self> {
+        match ident.internal_cloned().as_str() {
+            "num" => Some(Self::Num),
+            "int" => Some(Self::Int),
+            "str" => Some(Self::Str),
+            _ => None,
+        }
+    }
+
+    /// Whether a runtime value satisfies this type
+    pub fn accepts(&self, val: &Value) -> bool {
+        match (self, val) {
+            (Self::Int, Value::Num(v)) => v.is_finite() && v.fract() == 0.0,
+            (Self::Num, Value::Num(_)) | (Self::Str, Value::Str(_)) => true,
+            (Self::Var(_), _) | (Self::Any, _) => true,
+            (Self::Fn(params, _), Value::Func(closure)) => {
+                params.iter().filter(|p| !p.is_optional()).count() == closure.arity()
+            }
+            (Self::List(ty), Value::List(items)) => items.iter().all(|item| ty.accepts(item)),
+            (Self::Tuple(tys), Value::Tuple(items)) => {
+                tys.len() == items.len() && tys.iter().zip(items).all(|(ty, item)| ty.accepts(item))
+            }
+            (Self::Record(tys), Value::Record(fields)) => {
+                tys.len() == fields.len()
+                    && tys
+                        .iter()
+                        .all(|(ident, ty)| val.field(ident).is_ok_and(|val| ty.accepts(val)))
+            }
+            _ => false,
+        }
+    }
+
+    /// Parameters that can be left out of a call
+    fn is_optional(&self) -> bool {
+        matches!(self, Self::Optional(_) | Self::Rest(_))
+    }
+
+    /// The type an argument has inside the function body
+    fn bound(&self) -> Type {
+        match self {
+            Self::Optional(ty) => (**ty).clone(),
+            Self::Rest(ty) => Self::List(ty.clone()),
+            ty => ty.clone(),
+        }
+    }
+
+    fn occurs(&self, var: usize) -> bool {
+        match self {
+            Self::Num | Self::Int | Self::Str | Self::Any => false,
+            Self::Var(v) => *v == var,
+            Self::Fn(params, ret) => params.iter().any(|p| p.occurs(var)) || ret.occurs(var),
+            Self::List(ty) | Self::Optional(ty) | Self::Rest(ty) => ty.occurs(var),
+            Self::Record(fields) => fields.iter().any(|(_, ty)| ty.occurs(var)),
+            Self::Tuple(tys) => tys.iter().any(|ty| ty.occurs(var)),
+        }
+    }
+
+    fn vars(&self, out: &mut Vec<usize>) {
+        match self {
+            Self::Num | Self::Int | Self::Str | Self::Any => (),
+            Self::Var(v) => {
+                if !out.contains(v) {
+                    out.push(*v)
+                }
+            }
+            Self::Fn(params, ret) => {
+                params.iter().for_each(|p| p.vars(out));
+                ret.vars(out);
+            }
+            Self::List(ty) | Self::Optional(ty) | Self::Rest(ty) => ty.vars(out),
+            Self::Record(fields) => fields.iter().for_each(|(_, ty)| ty.vars(out)),
+            Self::Tuple(tys) => tys.iter().for_each(|ty| ty.vars(out)),
+        }
+    }
+
+    fn rename(&self, map: &HashMap<usize, Type>) -> Type {
+        match self {
+            Self::Num | Self::Int | Self::Str | Self::Any => self.clone(),
+            Self::Var(v) => map.get(v).cloned().unwrap_or(Self::Var(*v)),
+            Self::Fn(params, ret) => Self::Fn(
+                params.iter().map(|p| p.rename(map)).collect(),
+                Box::new(ret.rename(map)),
+            ),
+            Self::List(ty) => Self::List(Box::new(ty.rename(map))),
+            Self::Optional(ty) => Self::Optional(Box::new(ty.rename(map))),
+            Self::Rest(ty) => Self::Rest(Box::new(ty.rename(map))),
+            Self::Record(fields) => Self::Record(
+                fields
+                    .iter()
+                    .map(|(ident, ty)| (ident.clone(), ty.rename(map)))
+                    .collect(),
+            ),
+            Self::Tuple(tys) => Self::Tuple(tys.iter().map(|ty| ty.rename(map)).collect()),
+        }
+    }
+
+    /// Renumbers type variables from 0 in order of appearance, so signatures print as 'a, 'b, ...
+    pub fn normalized(&self) -> Type {
+        let mut vars = Vec::new();
+        self.vars(&mut vars);
+        let map = vars
+            .into_iter()
+            .enumerate()
+            .map(|(idx, var)| (var, Self::Var(idx)))
+            .collect();
+        self.rename(&map)
+    }
+}
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Num => write!(f, "num"),
+            Self::Int => write!(f, "int"),
+            Self::Str => write!(f, "str"),
+            Self::Any => write!(f, "any"),
+            Self::Var(v) => {
+                let letter = (b'a' + (*v % 26) as u8) as char;
+                if *v < 26 {
+                    write!(f, "'{}", letter)
+                } else {
+                    write!(f, "'{}{}", letter, v / 26)
+                }
+            }
+            Self::Fn(params, ret) => write!(
+                f,
+                "fn({}) -> {}",
+                params
+                    .iter()
+                    .map(|p| format!("{}", p))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                ret
+            ),
+            Self::List(ty) => write!(f, "[{}]", ty),
+            Self::Optional(ty) => write!(f, "{}?", ty),
+            Self::Rest(ty) => write!(f, "...{}", ty),
+            Self::Tuple(tys) => write!(
+                f,
+                "({})",
+                tys.iter()
+                    .map(|ty| ty.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Record(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Self::Record(fields) => write!(
+                f,
+                "{{ {} }}",
+                fields
+                    .iter()
+                    .map(|(ident, ty)| format!("{}: {}", ident, ty))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// What names refer to while inferring an expression
+struct Env<'a> {
+    ctx: &'a Context,
+    locals: HashMap<Ident, Type>,
+    /// The function being inferred and its signature so far
+    current: Option<(&'a Ident, &'a Type)>,
+}
+
+/// Bitwise operators give integers, everything else any number
+fn result(op: Operator) -> Type {
+    match op {
+        Operator::BitAnd
+        | Operator::BitOr
+        | Operator::BitXor
+        | Operator::BitNot
+        | Operator::Shl
+        | Operator::Shr
+        | Operator::Lt
+        | Operator::Gt
+        | Operator::Le
+        | Operator::Ge
+        | Operator::Eq
+        | Operator::Ne => Type::Int,
+        Operator::Concat => Type::Str,
+        _ => Type::Num,
+    }
+}
+
+/// Unification based inference over expression trees
+pub struct Inference {
+    next: usize,
+    subst: HashMap<usize, Type>,
+}
+impl Inference {
+    pub fn new() -> Self {
+        Inference {
+            next: 0,
+            subst: HashMap::new(),
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.next += 1;
+        Type::Var(self.next - 1)
+    }
+
+    fn resolve(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(v) => match self.subst.get(v) {
+                Some(t) => self.resolve(t),
+                None => ty.clone(),
+            },
+            Type::Fn(params, ret) => Type::Fn(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            Type::List(ty) => Type::List(Box::new(self.resolve(ty))),
+            Type::Optional(ty) => Type::Optional(Box::new(self.resolve(ty))),
+            Type::Rest(ty) => Type::Rest(Box::new(self.resolve(ty))),
+            Type::Record(fields) => Type::Record(
+                fields
+                    .iter()
+                    .map(|(ident, ty)| (ident.clone(), self.resolve(ty)))
+                    .collect(),
+            ),
+            Type::Tuple(tys) => Type::Tuple(tys.iter().map(|ty| self.resolve(ty)).collect()),
+            Type::Num | Type::Int | Type::Str | Type::Any => ty.clone(),
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), String> {
+        match (self.resolve(a), self.resolve(b)) {
+            // Whether a number is integral is only known at runtime, so `int` is checked when values are bound
+            (Type::Num, Type::Num) | (Type::Int, Type::Int) | (Type::Str, Type::Str) => Ok(()),
+            // Never bound to a variable, so what else is known about it isn't lost
+            (Type::Any, _) | (_, Type::Any) => Ok(()),
+            (Type::Num, Type::Int) | (Type::Int, Type::Num) => Ok(()),
+            (Type::Var(x), Type::Var(y)) if x == y => Ok(()),
+            (Type::Var(v), t) | (t, Type::Var(v)) => {
+                if t.occurs(v) {
+                    Err(format!(
+                        "Cannot construct the infinite type {} = {}",
+                        Type::Var(v),
+                        t
+                    ))
+                } else {
+                    self.subst.insert(v, t);
+                    Ok(())
+                }
+            }
+            (Type::Fn(pa, ra), Type::Fn(pb, rb)) if pa.len() == pb.len() => {
+                for (x, y) in pa.iter().zip(pb.iter()) {
+                    self.unify(x, y)?;
+                }
+                self.unify(&ra, &rb)
+            }
+            (Type::Tuple(x), Type::Tuple(y)) if x.len() == y.len() => {
+                for (tx, ty) in x.iter().zip(&y) {
+                    self.unify(tx, ty)?;
+                }
+                Ok(())
+            }
+            (Type::List(x), Type::List(y))
+            | (Type::Optional(x), Type::Optional(y))
+            | (Type::Rest(x), Type::Rest(y)) => self.unify(&x, &y),
+            (Type::Record(x), Type::Record(y))
+                if x.len() == y.len()
+                    && x.iter()
+                        .all(|(ident, _)| y.iter().any(|(other, _)| other == ident)) =>
+            {
+                for (ident, tx) in &x {
+                    let ty = &y.iter().find(|(other, _)| other == ident).unwrap().1;
+                    self.unify(tx, ty)?;
+                }
+                Ok(())
+            }
+            (x, y) => Err(format!("Type mismatch: expected {}, got {}", x, y)),
+        }
+    }
+
+    /// Gives every type variable in `ty` a fresh name, so each use of a polymorphic function is independent
+    fn instantiate(&mut self, ty: &Type) -> Type {
+        let mut vars = Vec::new();
+        ty.vars(&mut vars);
+        let map = vars
+            .into_iter()
+            .map(|var| (var, self.fresh()))
+            .collect::<HashMap<usize, Type>>();
+        ty.rename(&map)
+    }
+
+    fn signature(&mut self, func: &Function, current: Option<&Type>) -> Result<Type, String> {
+        match (&func.sig, current) {
+            (Some(sig), _) => Ok(self.instantiate(sig)),
+            // A recursive reference to the function being inferred
+            (None, Some(current)) => Ok(current.clone()),
+            (None, None) => Err(format!("The type of {} is not known", func.ident)),
+        }
+    }
+
+    /// The type of a value bound to a global variable
+    fn value(&mut self, val: &Value) -> Result<Type, String> {
+        let closure = match val {
+            Value::Num(_) => return Ok(Type::Num),
+            Value::Str(_) => return Ok(Type::Str),
+            Value::Func(closure) => closure,
+            Value::List(items) => {
+                let ty = self.fresh();
+                for item in items {
+                    let item = self.value(item)?;
+                    self.unify(&ty, &item)?;
+                }
+                return Ok(Type::List(Box::new(ty)));
+            }
+            Value::Record(fields) => {
+                return Ok(Type::Record(
+                    fields
+                        .iter()
+                        .map(|(ident, val)| Ok((ident.clone(), self.value(val)?)))
+                        .collect::<Result<Vec<(Ident, Type)>, String>>()?,
+                ))
+            }
+            Value::Tuple(items) => {
+                return Ok(Type::Tuple(
+                    items
+                        .iter()
+                        .map(|item| self.value(item))
+                        .collect::<Result<Vec<Type>, String>>()?,
+                ))
+            }
+        };
+        match &closure.func.sig {
+            Some(sig) => {
+                let sig = self.instantiate(sig);
+                let applied = closure
+                    .applied
+                    .iter()
+                    .map(|arg| self.value(arg))
+                    .collect::<Result<Vec<Type>, String>>()?;
+                self.apply(sig, applied, &format!("{}", closure))
+            }
+            None => Ok(self.fresh()),
+        }
+    }
+
+    /// The type of calling `func` with `args`. Fewer arguments than it needs give a function of the rest,
+    /// a variadic parameter takes every remaining argument.
+    fn apply(&mut self, func: Type, args: Vec<Type>, name: &str) -> Result<Type, String> {
+        let args = args.into_iter().map(Some).collect::<Vec<_>>();
+        self.call(func, args, false, name)
+    }
+
+    /// Like `apply`, `None` is an argument left out of `f(a, c: 1)`. With `complete`, missing arguments
+    /// are an error rather than giving a function of the rest.
+    fn call(
+        &mut self,
+        func: Type,
+        args: Vec<Option<Type>>,
+        complete: bool,
+        name: &str,
+    ) -> Result<Type, String> {
+        let (params, ret) = match self.resolve(&func) {
+            Type::Fn(params, ret) => (params, *ret),
+            Type::Var(_) => {
+                let ret = self.fresh();
+                let args = args
+                    .into_iter()
+                    .map(|arg| arg.unwrap_or_else(|| self.fresh()))
+                    .collect();
+                self.unify(&func, &Type::Fn(args, Box::new(ret.clone())))
+                    .map_err(|e| format!("{} in call to {}", e, name))?;
+                return Ok(ret);
+            }
+            ty => return Err(format!("{} has type {}, so it can't be called", name, ty)),
+        };
+
+        for (idx, param) in params.iter().enumerate() {
+            let arg = match (param, args.get(idx)) {
+                (Type::Rest(ty), _) => {
+                    for arg in args[idx.min(args.len())..].iter().flatten() {
+                        self.unify(ty, arg)
+                            .map_err(|e| format!("{} in call to {}", e, name))?;
+                    }
+                    return Ok(ret);
+                }
+                (_, Some(Some(arg))) => arg,
+                (param, _) if param.is_optional() => continue,
+                (_, Some(None)) => return Err(format!("Missing an argument in call to {}", name)),
+                (_, None) if complete => {
+                    return Err(format!("Missing an argument in call to {}", name))
+                }
+                (_, None) => return Ok(Type::Fn(params[idx..].to_vec(), Box::new(ret))),
+            };
+            self.unify(&param.bound(), arg)
+                .map_err(|e| format!("{} in call to {}", e, name))?;
+        }
+        if args.len() <= params.len() {
+            Ok(ret)
+        } else {
+            self.apply(
+                ret,
+                args[params.len()..].iter().flatten().cloned().collect(),
+                name,
+            )
+        }
+    }
+
+    /// The names of the parameters `f(a: 1)` can give, for a function known by name
+    fn parameters(&self, callee: &Expr, env: &Env) -> Option<Vec<Ident>> {
+        let ident = match callee {
+            Expr::Var(ident, _) if !env.locals.contains_key(ident) => ident,
+            _ => return None,
+        };
+        let ident = &env.ctx.resolve(ident);
+        let (func, applied) = match env.ctx.functions.get(ident) {
+            Some(func) => (func.clone(), 0),
+            None => match env.ctx.variables.borrow().get(ident) {
+                Some(Value::Func(closure)) => {
+                    (closure.func.as_ref().clone(), closure.applied.len())
+                }
+                _ => return None,
+            },
+        };
+        let fixed = func.argc() - func.variadic as usize;
+        Some(func.args[applied.min(fixed)..fixed].to_vec())
+    }
+
+    fn operand(&mut self, expr: &Expr, op: Operator, env: &Env) -> Result<(), String> {
+        let ty = self.expression(expr, env)?;
+        self.unify(&Type::Num, &ty)
+            .map_err(|e| format!("{} in operand of {}", e, op))
+    }
+
+    fn expression(&mut self, expr: &Expr, env: &Env) -> Result<Type, String> {
+        match expr {
+            Expr::Num(_) => Ok(Type::Num),
+            Expr::Str(_) => Ok(Type::Str),
+            Expr::Var(ident, _) => {
+                let resolved = env.ctx.resolve(ident);
+                if let Some(ty) = env.locals.get(ident) {
+                    Ok(ty.clone())
+                } else if let Some(func) = env.ctx.functions.get(&resolved) {
+                    let cur = env
+                        .current
+                        .filter(|(current, _)| *current == ident)
+                        .map(|(_, ty)| ty);
+                    let sig = self.signature(func, cur)?;
+                    // Functions without arguments are called by naming them
+                    if func.argc() == 0 {
+                        self.apply(sig, Vec::new(), &format!("{}", ident))
+                    } else {
+                        Ok(sig)
+                    }
+                } else if let Some(val) = env.ctx.variables.borrow().get(&resolved) {
+                    self.value(val)
+                } else if let Some(ty) = self.field_access(ident, env)? {
+                    Ok(ty)
+                } else {
+                    Err(format!("Unknown identifier {}", ident))
+                }
+            }
+            Expr::Unary { op, operand } => {
+                self.operand(operand, *op, env)?;
+                Ok(result(*op))
+            }
+            // Any two values of the same type can be compared for equality
+            Expr::Binary { op, lhs, rhs } if matches!(op, Operator::Eq | Operator::Ne) => {
+                let lhs = self.expression(lhs, env)?;
+                let rhs = self.expression(rhs, env)?;
+                self.unify(&lhs, &rhs)
+                    .map_err(|e| format!("{} in operands of {}", e, op))?;
+                Ok(result(*op))
+            }
+            Expr::Binary { op, lhs, rhs } if *op == Operator::Concat => {
+                for operand in [lhs, rhs] {
+                    let ty = self.expression(operand, env)?;
+                    self.unify(&Type::Str, &ty)
+                        .map_err(|e| format!("{} in operand of {}", e, op))?;
+                }
+                Ok(result(*op))
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                self.operand(lhs, *op, env)?;
+                self.operand(rhs, *op, env)?;
+                Ok(result(*op))
+            }
+            Expr::App { callee, args, .. } => {
+                let func = self.expression(callee, env)?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.expression(arg, env))
+                    .collect::<Result<Vec<Type>, String>>()?;
+                self.apply(func, args, &format!("{}", callee))
+            }
+            Expr::Call {
+                callee,
+                args,
+                named,
+                ..
+            } => {
+                let func = self.expression(callee, env)?;
+                let mut tys = Vec::new();
+                for arg in args {
+                    tys.push(Some(self.expression(arg, env)?));
+                }
+                if !named.is_empty() {
+                    let params = self.parameters(callee, env).ok_or_else(|| {
+                        format!(
+                            "Named arguments need a function known by name, in call to {}",
+                            callee
+                        )
+                    })?;
+                    for (ident, arg) in named {
+                        let idx = params
+                            .iter()
+                            .position(|param| param == ident)
+                            .ok_or_else(|| format!("{} has no argument named {}", callee, ident))?;
+                        if tys.len() <= idx {
+                            tys.resize(idx + 1, None);
+                        }
+                        if tys[idx].is_some() {
+                            return Err(format!(
+                                "Argument {} of {} was given twice",
+                                ident, callee
+                            ));
+                        }
+                        tys[idx] = Some(self.expression(arg, env)?);
+                    }
+                }
+                self.call(func, tys, true, &format!("{}", callee))
+            }
+            Expr::Lambda { params, body } => {
+                let mut locals = env.locals.clone();
+                let tys = params
+                    .iter()
+                    .map(|param| {
+                        let ty = self.fresh();
+                        locals.insert(param.clone(), ty.clone());
+                        ty
+                    })
+                    .collect::<Vec<Type>>();
+                let ret = self.expression(body, &Env { locals, ..*env })?;
+                Ok(Type::Fn(tys, Box::new(ret)))
+            }
+            Expr::Block(body) => {
+                let ret = self.fresh();
+                self.statements(body, env, &ret)?;
+                Ok(ret)
+            }
+            Expr::Record(fields) => Ok(Type::Record(
+                fields
+                    .iter()
+                    .map(|(ident, expr)| Ok((ident.clone(), self.expression(expr, env)?)))
+                    .collect::<Result<Vec<(Ident, Type)>, String>>()?,
+            )),
+            Expr::With { record, fields } => {
+                let ty = self.expression(record, env)?;
+                for (ident, expr) in fields {
+                    let old = self.field(&ty, ident)?;
+                    let new = self.expression(expr, env)?;
+                    self.unify(&old, &new)
+                        .map_err(|e| format!("{} in field {} of {}", e, ident, record))?;
+                }
+                Ok(ty)
+            }
+            Expr::Tuple(items) => Ok(Type::Tuple(
+                items
+                    .iter()
+                    .map(|item| self.expression(item, env))
+                    .collect::<Result<Vec<Type>, String>>()?,
+            )),
+            Expr::Match { value, arms, .. } => {
+                let ty = self.expression(value, env)?;
+                let ret = self.fresh();
+                for arm in arms {
+                    let mut locals = env.locals.clone();
+                    self.pattern(&arm.pattern, &ty, &mut locals)?;
+                    let env = Env { locals, ..*env };
+                    if let Some(guard) = &arm.guard {
+                        self.condition(guard, &env)?;
+                    }
+                    let body = self.expression(&arm.body, &env)?;
+                    self.unify(&ret, &body)
+                        .map_err(|e| format!("{} in arm {} of the match", e, arm.pattern))?;
+                }
+                Ok(ret)
+            }
+            Expr::Try {
+                body,
+                ident,
+                fallback,
+            } => {
+                let ty = self.expression(body, env)?;
+                let mut locals = env.locals.clone();
+                locals.insert(
+                    ident.clone(),
+                    Type::Record(vec![
+                        (Ident::new("kind".to_owned()).unwrap(), Type::Str),
+                        (Ident::new("message".to_owned()).unwrap(), Type::Str),
+                    ]),
+                );
+                let alt = self.expression(fallback, &Env { locals, ..*env })?;
+                self.unify(&ty, &alt)
+                    .map_err(|e| format!("{} in the fallback of catch {}", e, ident))?;
+                Ok(ty)
+            }
+        }
+    }
+
+    /// Adds the names `pattern` binds to `locals`, taking apart a value of type `ty`
+    fn pattern(
+        &mut self,
+        pattern: &Pattern,
+        ty: &Type,
+        locals: &mut HashMap<Ident, Type>,
+    ) -> Result<(), String> {
+        match pattern {
+            Pattern::Bind(ident) => {
+                locals.insert(ident.clone(), ty.clone());
+            }
+            Pattern::Num(_) => self
+                .unify(&Type::Num, ty)
+                .map_err(|e| format!("{} matching {}", e, pattern))?,
+            Pattern::Wildcard => (),
+            Pattern::Tuple(parts) => {
+                let tys = parts.iter().map(|_| self.fresh()).collect::<Vec<Type>>();
+                self.unify(&Type::Tuple(tys.clone()), ty)
+                    .map_err(|e| format!("{} taking apart {}", e, pattern))?;
+                for (part, ty) in parts.iter().zip(&tys) {
+                    self.pattern(part, ty, locals)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The type of the field `ident` of a value of type `ty`. Nothing is known about the fields of a record
+    /// whose type isn't known yet, such as an argument.
+    fn field(&mut self, ty: &Type, ident: &Ident) -> Result<Type, String> {
+        match self.resolve(ty) {
+            Type::Record(fields) => fields
+                .iter()
+                .find(|(field, _)| field == ident)
+                .map(|(_, ty)| ty.clone())
+                .ok_or_else(|| format!("{} has no field {}", Type::Record(fields.clone()), ident)),
+            Type::Var(_) => Ok(self.fresh()),
+            ty => Err(format!(
+                "Expected a record, got {} with field {}",
+                ty, ident
+            )),
+        }
+    }
+
+    /// `loan.rate` read as the field `rate` of a variable `loan`, `None` if no part of the name is a variable
+    fn field_access(&mut self, ident: &Ident, env: &Env) -> Result<Option<Type>, String> {
+        for (base, fields) in ident.fields() {
+            let ty = if let Some(ty) = env.locals.get(&base) {
+                ty.clone()
+            } else {
+                let val = env
+                    .ctx
+                    .variables
+                    .borrow()
+                    .get(&env.ctx.resolve(&base))
+                    .cloned();
+                match val {
+                    Some(val) => self.value(&val)?,
+                    None => continue,
+                }
+            };
+            return fields
+                .iter()
+                .try_fold(ty, |ty, field| self.field(&ty, field))
+                .map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Infers a block of statements, every value it returns has to have type `ret`
+    fn statements(&mut self, body: &[Stmt], env: &Env, ret: &Type) -> Result<(), String> {
+        let mut env = Env {
+            locals: env.locals.clone(),
+            ..*env
+        };
+        for stmt in body {
+            match stmt {
+                Stmt::Function(_)
+                | Stmt::Import { .. }
+                | Stmt::Use { .. }
+                | Stmt::Override(_)
+                | Stmt::Const { .. }
+                | Stmt::Break
+                | Stmt::Continue => (),
+                Stmt::Var { ident, ann, expr } => {
+                    let ty = self.expression(expr, &env)?;
+                    if let Some(ann) = ann {
+                        self.unify(ann, &ty)
+                            .map_err(|e| format!("{} in declaration of {}", e, ident))?;
+                    }
+                    env.locals.insert(ident.clone(), ty);
+                }
+                Stmt::Destructure { pattern, expr } => {
+                    let ty = self.expression(expr, &env)?;
+                    self.pattern(pattern, &ty, &mut env.locals)?;
+                }
+                Stmt::Assign {
+                    ident,
+                    op,
+                    expr,
+                    span,
+                } => {
+                    let ty = self.expression(expr, &env)?;
+                    let var = self.expression(&Expr::Var(ident.clone(), *span), &env)?;
+                    let res = match op {
+                        Some(op) => self
+                            .unify(&Type::Num, &var)
+                            .and_then(|_| self.unify(&Type::Num, &ty))
+                            .map_err(|e| format!("{} in operand of {}", e, op)),
+                        None => self.unify(&var, &ty),
+                    };
+                    res.map_err(|e| format!("{} in assignment to {}", e, ident))?;
+                }
+                Stmt::While { cond, body } => {
+                    self.condition(cond, &env)?;
+                    self.statements(body, &env, ret)?;
+                }
+                Stmt::For {
+                    ident,
+                    from,
+                    to,
+                    body,
+                } => {
+                    self.condition(from, &env)?;
+                    self.condition(to, &env)?;
+                    let mut locals = env.locals.clone();
+                    locals.insert(ident.clone(), Type::Num);
+                    self.statements(body, &Env { locals, ..env }, ret)?;
+                }
+                Stmt::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    self.condition(cond, &env)?;
+                    self.statements(then, &env, ret)?;
+                    self.statements(otherwise, &env, ret)?;
+                }
+                Stmt::Return(expr) => {
+                    let ty = self.expression(expr, &env)?;
+                    self.unify(ret, &ty)
+                        .map_err(|e| format!("{} in return value", e))?;
+                }
+                Stmt::Expr(expr) => {
+                    self.expression(expr, &env)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Infers a clause of a function whose arguments have the types `params`
+    fn clause(
+        &mut self,
+        clause: &Function,
+        params: &[Type],
+        ret: &Type,
+        sig: &Type,
+        ctx: &Context,
+    ) -> Result<(), String> {
+        for ((arg, ann), param) in clause.args.iter().zip(&clause.annotations).zip(params) {
+            if let Some(ann) = ann {
+                // An annotation is the type of each argument `...xs` collects, or of the default
+                let param = match param {
+                    Type::Rest(ty) | Type::Optional(ty) => ty,
+                    ty => ty,
+                };
+                self.unify(param, ann)
+                    .map_err(|e| format!("{} in annotation of {}", e, arg))?;
+            }
+        }
+        if let Some(ty) = &clause.ret {
+            self.unify(ret, ty)
+                .map_err(|e| format!("{} in return annotation", e))?;
+        }
+
+        let mut locals = clause
+            .args
+            .iter()
+            .cloned()
+            .zip(params.iter().map(Type::bound))
+            .collect::<HashMap<Ident, Type>>();
+        for (pattern, param) in clause.patterns.iter().zip(params) {
+            if let Some(pattern) = pattern {
+                self.pattern(pattern, &param.bound(), &mut locals)?;
+            }
+        }
+        let env = Env {
+            ctx,
+            locals,
+            current: Some((&clause.ident, sig)),
+        };
+        for ((arg, param), default) in clause.args.iter().zip(params).zip(&clause.defaults) {
+            if let Some(default) = default {
+                let ty = self.expression(default, &env)?;
+                self.unify(&param.bound(), &ty)
+                    .map_err(|e| format!("{} in default of {}", e, arg))?;
+            }
+        }
+        if let Some(guard) = &clause.guard {
+            self.condition(guard, &env)?;
+        }
+        let body = self.expression(&clause.body, &env)?;
+        self.unify(ret, &body)
+    }
+
+    fn condition(&mut self, expr: &Expr, env: &Env) -> Result<(), String> {
+        let ty = self.expression(expr, env)?;
+        self.unify(&Type::Num, &ty)
+    }
+}
+impl Default for Inference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Infers the signature of `func`. References to `func` inside its own body are treated monomorphically
+pub fn infer_function(func: &Function, ctx: &Context) -> Result<Type, String> {
+    let mut inf = Inference::new();
+    let params = func
+        .annotations
+        .iter()
+        .zip(&func.defaults)
+        .enumerate()
+        .map(|(idx, (ann, default))| {
+            let ty = Box::new(ann.clone().unwrap_or_else(|| inf.fresh()));
+            match default {
+                _ if func.variadic && idx + 1 == func.argc() => Type::Rest(ty),
+                Some(_) => Type::Optional(ty),
+                None => *ty,
+            }
+        })
+        .collect::<Vec<Type>>();
+    let ret = func.ret.clone().unwrap_or_else(|| inf.fresh());
+    let sig = Type::Fn(params.clone(), Box::new(ret.clone()));
+
+    // Every clause shares the signature of the first
+    for clause in std::iter::once(func).chain(&func.clauses) {
+        inf.clause(clause, &params, &ret, &sig, ctx)
+            .map_err(|e| format!("In function {}: {}", func.ident, e))?;
+    }
+    Ok(inf.resolve(&sig).normalized())
+}
+
+pub fn infer_expression(expr: &Expr, ctx: &Context) -> Result<Type, String> {
+    let env = Env {
+        ctx,
+        locals: HashMap::new(),
+        current: None,
+    };
+    let mut inf = Inference::new();
+    let ty = inf.expression(expr, &env)?;
+    Ok(inf.resolve(&ty).normalized())
+}
+
+#[test]
+fn test_infer() {
+    use crate::{token::tokenize, tree::parse_expression};
+    let mut ctx = Context::new();
+    let infer = |s: &str, ctx: &Context| {
+        infer_expression(
+            &parse_expression(&tokenize(s.to_owned()).unwrap()).unwrap(),
+            ctx,
+        )
+        .map(|ty| format!("{}", ty))
+    };
+
+    let func =
+        Function::new(&tokenize("function foo a b = 2 * a + sin b".to_owned()).unwrap()).unwrap();
+    assert_eq!(
+        infer_function(&func, &ctx).unwrap(),
+        Type::Fn(vec![Type::Num, Type::Num], Box::new(Type::Num))
+    );
+
+    let func = Function::new(&tokenize("function first a b = a".to_owned()).unwrap()).unwrap();
+    assert_eq!(
+        format!("{}", infer_function(&func, &ctx).unwrap()),
+        "fn('a, 'b) -> 'a"
+    );
+
+    let func = ctx
+        .new_func(&tokenize("function first a b = a".to_owned()).unwrap())
+        .unwrap();
+    assert_eq!(
+        format!("{}", func),
+        "function first a b = a  # fn('a, 'b) -> 'a"
+    );
+    assert_eq!(infer("first 1 2 + 3", &ctx), Ok("num".to_owned()));
+
+    // Partial application and lambdas
+    assert_eq!(infer("first 1", &ctx), Ok("fn('a) -> num".to_owned()));
+    assert_eq!(infer("\\x -> x * 2", &ctx), Ok("fn(num) -> num".to_owned()));
+    assert_eq!(
+        infer("\\f x -> f (f x)", &ctx),
+        Ok("fn(fn('a) -> 'a, 'a) -> 'a".to_owned())
+    );
+    assert_eq!(
+        infer("(\\x y -> x) 1", &ctx),
+        Ok("fn('a) -> num".to_owned())
+    );
+    ctx.new_var(&tokenize("var inc = \\x -> x + 1".to_owned()).unwrap())
+        .unwrap();
+    assert_eq!(infer("inc", &ctx), Ok("fn(num) -> num".to_owned()));
+    assert!(infer("inc inc", &ctx).is_err());
+
+    // Defaults and variadic arguments
+    let func = ctx
+        .new_func(&tokenize("function scale x (by = 2) = x * by".to_owned()).unwrap())
+        .unwrap();
+    assert_eq!(
+        format!("{}", func),
+        "function scale x (by = 2) = x * by  # fn(num, num?) -> num"
+    );
+    assert_eq!(infer("scale 1", &ctx), Ok("num".to_owned()));
+    assert_eq!(infer("scale", &ctx), Ok("fn(num, num?) -> num".to_owned()));
+    assert_eq!(infer("max 1 2 3", &ctx), Ok("num".to_owned()));
+    assert_eq!(infer("max", &ctx), Ok("fn(...num) -> num".to_owned()));
+    let func = ctx
+        .new_func(&tokenize("function avg ...xs = sum xs / len xs".to_owned()).unwrap())
+        .unwrap();
+    assert_eq!(
+        format!("{}", func),
+        "function avg ...xs = sum xs / len xs  # fn(...num) -> num"
+    );
+    assert!(infer("avg 1 (\\x -> x)", &ctx).is_err());
+
+    let func =
+        Function::new(&tokenize("function bad a = max a (\\x -> x)".to_owned()).unwrap()).unwrap();
+    assert!(infer_function(&func, &ctx).is_err());
+    let func =
+        Function::new(&tokenize("function bad a (b = \\x -> x) = a + b".to_owned()).unwrap())
+            .unwrap();
+    assert!(infer_function(&func, &ctx).is_err());
+    let func = Function::new(&tokenize("function bad a = a a".to_owned()).unwrap()).unwrap();
+    assert!(infer_function(&func, &ctx).is_err());
+    let func = Function::new(&tokenize("function bad a = a + b".to_owned()).unwrap()).unwrap();
+    assert!(infer_function(&func, &ctx).is_err());
+}
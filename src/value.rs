@@ -0,0 +1,199 @@
+use {
+    crate::{
+        function::Function,
+        token::{quote, Ident},
+    },
+    std::{
+        fmt::{self, Display, Formatter},
+        rc::Rc,
+    },
+};
+
+/// A function as a value: a named function or a lambda, with any arguments already applied
+#[derive(PartialEq, Debug, Clone)]
+pub struct Closure {
+    pub func: Rc<Function>,
+    /// The locals a lambda could see where it was created
+    pub captured: Vec<(Ident, Value)>,
+    pub applied: Vec<Value>,
+}
+impl Closure {
+    pub fn new(func: Function) -> Self {
+        Closure {
+            func: Rc::new(func),
+            captured: Vec::new(),
+            applied: Vec::new(),
+        }
+    }
+
+    /// How many more arguments are needed before the function is called
+    pub fn arity(&self) -> usize {
+        self.func.required().saturating_sub(self.applied.len())
+    }
+}
+impl Display for Closure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let lambda = || {
+            format!(
+                "\\{} -> {}",
+                self.func
+                    .args
+                    .iter()
+                    .map(|arg| arg.internal_cloned())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+                self.func.body
+            )
+        };
+        match (self.func.lambda, self.applied.is_empty()) {
+            (false, _) => write!(f, "{}", self.func.ident)?,
+            (true, true) => write!(f, "{}", lambda())?,
+            (true, false) => write!(f, "({})", lambda())?,
+        }
+        for arg in &self.applied {
+            match arg {
+                Value::Num(v) if *v >= 0.0 => write!(f, " {}", v)?,
+                Value::Tuple(_) => write!(f, " {}", arg)?,
+                _ => write!(f, " ({})", arg)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Value {
+    Num(f64),
+    Str(String),
+    Func(Closure),
+    /// The arguments collected by a variadic parameter
+    List(Vec<Value>),
+    /// Named fields in the order they were written, `{ rate: 0.05, term: 30 }`
+    Record(Vec<(Ident, Value)>),
+    /// `(q, r)`, several values returned or passed as one
+    Tuple(Vec<Value>),
+}
+impl Value {
+    pub fn num(&self) -> Result<f64, String> {
+        match self {
+            Self::Num(v) => Ok(*v),
+            _ => Err(format!("Expected a number, got {}", self)),
+        }
+    }
+
+    pub fn str(&self) -> Result<&str, String> {
+        match self {
+            Self::Str(text) => Ok(text),
+            _ => Err(format!("Expected a string, got {}", self)),
+        }
+    }
+
+    /// The value as `str` converts it, a string is its text without quotes
+    pub fn text(&self) -> String {
+        match self {
+            Self::Str(text) => text.clone(),
+            val => val.to_string(),
+        }
+    }
+
+    pub fn list(&self) -> Result<&[Value], String> {
+        match self {
+            Self::List(items) => Ok(items),
+            _ => Err(format!("Expected a list, got {}", self)),
+        }
+    }
+
+    pub fn field(&self, ident: &Ident) -> Result<&Value, String> {
+        match self {
+            Self::Record(fields) => fields
+                .iter()
+                .find(|(field, _)| field == ident)
+                .map(|(_, val)| val)
+                .ok_or_else(|| format!("{} has no field {}", self, ident)),
+            _ => Err(format!("Expected a record, got {}", self)),
+        }
+    }
+
+    /// A copy of the record with the field `ident` replaced, which it has to have already
+    pub fn with(&self, ident: &Ident, val: Value) -> Result<Value, String> {
+        self.field(ident)?;
+        let mut record = self.clone();
+        if let Self::Record(fields) = &mut record {
+            for (field, old) in fields {
+                if field == ident {
+                    *old = val.clone();
+                }
+            }
+        }
+        Ok(record)
+    }
+
+    /// `==` on any values. Records are equal when they have the same fields with equal values, in any order.
+    pub fn equals(&self, other: &Value) -> Result<bool, String> {
+        match (self, other) {
+            (Self::Num(a), Self::Num(b)) => Ok(a == b),
+            (Self::Str(a), Self::Str(b)) => Ok(a == b),
+            (Self::Func(_), _) | (_, Self::Func(_)) => Err(format!(
+                "Functions can't be compared, got {} and {}",
+                self, other
+            )),
+            (Self::List(a), Self::List(b)) | (Self::Tuple(a), Self::Tuple(b))
+                if a.len() == b.len() =>
+            {
+                for (a, b) in a.iter().zip(b) {
+                    if !a.equals(b)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            (Self::Record(a), Self::Record(b)) if a.len() == b.len() => {
+                for (ident, val) in a {
+                    match other.field(ident) {
+                        Ok(other) if val.equals(other)? => (),
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Num(v) => write!(f, "{}", v),
+            Self::Str(text) => write!(f, "{}", quote(text)),
+            Self::Func(closure) => write!(f, "{}", closure),
+            Self::List(items) => write!(
+                f,
+                "[{}]",
+                items
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Tuple(items) => write!(
+                f,
+                "({})",
+                items
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Record(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Self::Record(fields) => write!(
+                f,
+                "{{ {} }}",
+                fields
+                    .iter()
+                    .map(|(ident, val)| format!("{}: {}", ident, val))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+}